@@ -0,0 +1,125 @@
+//! Test-only stand-in for the codex CLI.
+//!
+//! Tests point `CODEX_BIN` at this binary and describe its behavior in `FAKE_CODEX_SCENARIO`,
+//! one directive per line:
+//!
+//! - `emit <line>`    write `<line>` to stdout
+//! - `stderr <line>`  write `<line>` to stderr
+//! - `sleep <ms>`     sleep for `<ms>` milliseconds
+//! - `spin <ms>`      busy-loop on the CPU for `<ms>` milliseconds
+//! - `exit <code>`    exit immediately with `<code>`
+//! - `emit-hex <hex>` write the hex-decoded bytes plus a newline, for output that is not UTF-8
+//! - `emit-args`      write the received command-line arguments as a `fake_codex.args` event
+//! - `flood <n> <bytes>` alternate `<n>` times between an agent_message of `<bytes>` characters
+//!   on stdout and a `<bytes>`-character line on stderr, without pausing, to fill both pipes
+//! - `kill`           terminate by SIGKILL, as the OOM killer would (Unix only)
+//! - `fail-first <n> <path>` count invocations in the file at `<path>`; the first `<n>` emit a
+//!   rate-limit error with `retry_after: 0` and exit 1, later ones continue with the scenario
+//!
+//! Command-line arguments are otherwise ignored, so the same scenario works for any codex
+//! invocation.
+
+use std::io::Write;
+use std::process::ExitCode;
+use std::time::Duration;
+
+fn main() -> ExitCode {
+    let scenario = std::env::var("FAKE_CODEX_SCENARIO").unwrap_or_default();
+    let mut stdout = std::io::stdout();
+    let mut stderr = std::io::stderr();
+
+    for directive in scenario.lines() {
+        let (command, arg) = directive.split_once(' ').unwrap_or((directive, ""));
+        match command {
+            "emit" => {
+                let _ = writeln!(stdout, "{}", arg);
+                let _ = stdout.flush();
+            }
+            "emit-hex" => {
+                let bytes: Vec<u8> = (0..arg.len())
+                    .step_by(2)
+                    .filter_map(|i| arg.get(i..i + 2))
+                    .filter_map(|pair| u8::from_str_radix(pair, 16).ok())
+                    .collect();
+                let _ = stdout.write_all(&bytes);
+                let _ = stdout.write_all(b"\n");
+                let _ = stdout.flush();
+            }
+            "emit-args" => {
+                let args: Vec<String> = std::env::args().skip(1).collect();
+                let event = serde_json::json!({ "type": "fake_codex.args", "args": args });
+                let _ = writeln!(stdout, "{}", event);
+                let _ = stdout.flush();
+            }
+            "stderr" => {
+                let _ = writeln!(stderr, "{}", arg);
+                let _ = stderr.flush();
+            }
+            "sleep" => {
+                let millis = arg.trim().parse().unwrap_or(0);
+                std::thread::sleep(Duration::from_millis(millis));
+            }
+            "spin" => {
+                let millis = arg.trim().parse().unwrap_or(0);
+                let deadline = std::time::Instant::now() + Duration::from_millis(millis);
+                let mut counter: u64 = 0;
+                while std::time::Instant::now() < deadline {
+                    counter = std::hint::black_box(counter.wrapping_add(1));
+                }
+            }
+            "exit" => return ExitCode::from(arg.trim().parse::<u8>().unwrap_or(1)),
+            "fail-first" => {
+                let (limit, path) = arg.split_once(' ').unwrap_or((arg, ""));
+                let limit: u32 = limit.trim().parse().unwrap_or(0);
+                let previous: u32 = std::fs::read_to_string(path.trim())
+                    .ok()
+                    .and_then(|count| count.trim().parse().ok())
+                    .unwrap_or(0);
+                let _ = std::fs::write(path.trim(), (previous + 1).to_string());
+                if previous < limit {
+                    let _ = writeln!(
+                        stdout,
+                        r#"{{"type":"turn.failed","error":{{"message":"Rate limit exceeded","retry_after":0}}}}"#
+                    );
+                    let _ = stdout.flush();
+                    return ExitCode::from(1);
+                }
+            }
+            "flood" => {
+                let (rounds, bytes) = arg.split_once(' ').unwrap_or((arg, "0"));
+                let rounds: usize = rounds.trim().parse().unwrap_or(0);
+                let bytes: usize = bytes.trim().parse().unwrap_or(0);
+                let event = serde_json::json!({
+                    "type": "item.completed",
+                    "item": { "type": "agent_message", "text": "o".repeat(bytes) },
+                });
+                let noise = "e".repeat(bytes);
+                for _ in 0..rounds {
+                    let _ = writeln!(stdout, "{}", event);
+                    let _ = writeln!(stderr, "{}", noise);
+                }
+                let _ = stdout.flush();
+                let _ = stderr.flush();
+            }
+            "kill" => {
+                #[cfg(unix)]
+                {
+                    let pid = std::process::id().to_string();
+                    let _ = std::process::Command::new("kill")
+                        .args(["-KILL", &pid])
+                        .status();
+                    // Give the signal time to arrive before falling back to a plain exit
+                    std::thread::sleep(Duration::from_secs(5));
+                }
+                return ExitCode::from(137);
+            }
+            "" => {}
+            other => {
+                let _ = writeln!(stderr, "fake_codex: unknown directive '{}'", other);
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,106 @@
+use crate::codex::{self, CodexResult, OptionsBuilder, SandboxPolicy};
+use anyhow::Result;
+use std::path::Path;
+
+/// Runs codex tasks from another crate without going through the MCP server.
+/// Settings made with the `with_*` methods apply to every task the client runs; the defaults are
+/// those of [`codex::Options::builder`] (read-only sandbox, git repo check, default timeout).
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use codex_mcp_rs::{CodexClient, SandboxPolicy};
+///
+/// let client = CodexClient::new()
+///     .with_sandbox(SandboxPolicy::WorkspaceWrite)
+///     .with_timeout_secs(300);
+/// let result = client.run_task("Add a unit test for parse_args", "/path/to/repo").await?;
+/// if result.success {
+///     println!("{}", result.agent_messages);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CodexClient {
+    sandbox: SandboxPolicy,
+    model: Option<String>,
+    profile: Option<String>,
+    timeout_secs: Option<u64>,
+    skip_git_repo_check: bool,
+    max_retries: u32,
+}
+
+impl CodexClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sandbox(mut self, sandbox: SandboxPolicy) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn with_profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = Some(profile.into());
+        self
+    }
+
+    pub fn with_timeout_secs(mut self, secs: u64) -> Self {
+        self.timeout_secs = Some(secs);
+        self
+    }
+
+    pub fn with_skip_git_repo_check(mut self, skip: bool) -> Self {
+        self.skip_git_repo_check = skip;
+        self
+    }
+
+    pub fn with_max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Options for a task in `cd` with this client's settings applied. Use it to set anything the
+    /// client does not expose, then pass the built options to [`codex::run`]
+    pub fn options(&self, prompt: impl Into<String>, cd: impl AsRef<Path>) -> OptionsBuilder {
+        let mut builder = codex::Options::builder(prompt, cd.as_ref())
+            .with_sandbox(self.sandbox.clone())
+            .with_skip_git_repo_check(self.skip_git_repo_check)
+            .with_max_retries(self.max_retries);
+        if let Some(model) = &self.model {
+            builder = builder.with_model(model.clone());
+        }
+        if let Some(profile) = &self.profile {
+            builder = builder.with_profile(profile.clone());
+        }
+        if let Some(secs) = self.timeout_secs {
+            builder = builder.with_timeout_secs(secs);
+        }
+        builder
+    }
+
+    /// Run `prompt` in a new session in `cd`. An `Err` means codex could not be run at all;
+    /// failures reported by codex itself come back as a result with `success == false`
+    pub async fn run_task(
+        &self,
+        prompt: impl Into<String>,
+        cd: impl AsRef<Path>,
+    ) -> Result<CodexResult> {
+        codex::run(self.options(prompt, cd).build()).await
+    }
+
+    /// Continue the session `session_id` (from a previous result) with another prompt
+    pub async fn resume_task(
+        &self,
+        session_id: impl Into<String>,
+        prompt: impl Into<String>,
+        cd: impl AsRef<Path>,
+    ) -> Result<CodexResult> {
+        codex::run(self.options(prompt, cd).with_session_id(session_id).build()).await
+    }
+}
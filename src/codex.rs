@@ -1,12 +1,19 @@
+use crate::dir_lock::{DirLock, DEFAULT_DIR_LOCK_WAIT_SECS};
+use crate::events::{EventSink, RunSummary};
 use anyhow::{Context, Result};
+use encoding_rs::Encoding;
 use rmcp::schemars;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::str::Utf8Error;
+use std::sync::Arc;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
 
 /// Default timeout in seconds (10 minutes)
 pub const DEFAULT_TIMEOUT_SECS: u64 = 600;
@@ -14,6 +21,68 @@ pub const DEFAULT_TIMEOUT_SECS: u64 = 600;
 /// Maximum allowed timeout in seconds (1 hour)
 pub const MAX_TIMEOUT_SECS: u64 = 3600;
 
+/// Maximum timeout for runs with allow_long_timeout, e.g. privileged batch jobs (24 hours)
+pub const MAX_LONG_TIMEOUT_SECS: u64 = 24 * 3600;
+
+/// Maximum number of retries for transient failures
+pub const MAX_RETRIES: u32 = 5;
+
+/// Backoff before the first retry, doubled for each subsequent retry
+const RETRY_BASE_BACKOFF_MS: u64 = 1000;
+
+/// Upper bound for the backoff between retries
+const MAX_RETRY_BACKOFF_MS: u64 = 30_000;
+
+/// Lowercase error fragments that indicate a temporary upstream failure
+const TRANSIENT_ERROR_MARKERS: &[&str] = &[
+    "rate limit",
+    "rate_limit",
+    "overloaded",
+    "temporarily unavailable",
+    "connection reset",
+    "connection refused",
+    "stream disconnected",
+];
+
+/// HTTP status codes that indicate a temporary upstream failure
+const TRANSIENT_STATUS_CODES: &[u16] = &[429, 502, 503, 504];
+
+/// Lowercase words that introduce a status code in an error message, e.g. "status 503" or
+/// "http 429". A bare number is not enough, since line numbers and ids contain digits too
+const STATUS_CODE_PREFIXES: &[&str] = &[
+    "status ",
+    "status: ",
+    "status=",
+    "status code ",
+    "status code: ",
+    "status_code: ",
+    "status_code=",
+    "http ",
+    "http error ",
+];
+
+/// Maximum AGENTS.md size in bytes that will be injected into a run (1MB)
+pub const MAX_AGENTS_MD_SIZE: u64 = 1024 * 1024;
+
+/// Highest AGENTS.md size limit agents_absolute_max can raise MAX_AGENTS_MD_SIZE to (64MB)
+pub const MAX_AGENTS_ABSOLUTE_MAX: u64 = 64 * 1024 * 1024;
+
+/// AGENTS.md size limit for a run: `requested` capped at MAX_AGENTS_ABSOLUTE_MAX, else
+/// MAX_AGENTS_MD_SIZE
+pub fn agents_md_size_limit(requested: Option<u64>) -> u64 {
+    requested.map_or(MAX_AGENTS_MD_SIZE, |limit| {
+        limit.min(MAX_AGENTS_ABSOLUTE_MAX)
+    })
+}
+
+/// Name of the per-project instructions file looked up in the working directory
+const AGENTS_MD_FILE: &str = "AGENTS.md";
+
+/// Instruction prepended to the prompt by the plan tool
+pub const PLAN_INSTRUCTION: &str = "Do not modify any files or run commands that change state. \
+Instead, reply with a concise numbered list (1., 2., 3., ...) of the steps you would take to \
+accomplish the following task, one step per line.\n\nTask:\n";
+
 /// Sandbox policy for model-generated commands
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema, Default)]
 #[serde(rename_all = "kebab-case")]
@@ -28,6 +97,13 @@ pub enum SandboxPolicy {
 }
 
 impl SandboxPolicy {
+    /// Every sandbox policy, in order of increasing privilege
+    pub const ALL: [SandboxPolicy; 3] = [
+        SandboxPolicy::ReadOnly,
+        SandboxPolicy::WorkspaceWrite,
+        SandboxPolicy::DangerFullAccess,
+    ];
+
     pub fn as_str(&self) -> &str {
         match self {
             SandboxPolicy::ReadOnly => "read-only",
@@ -35,6 +111,109 @@ impl SandboxPolicy {
             SandboxPolicy::DangerFullAccess => "danger-full-access",
         }
     }
+
+    /// Human-readable description of what commands run under this policy may do
+    pub fn describe(&self) -> &'static str {
+        match self {
+            SandboxPolicy::ReadOnly => {
+                "Commands can read files but cannot modify anything on disk or access the network."
+            }
+            SandboxPolicy::WorkspaceWrite => {
+                "Commands can read files and modify files inside the working directory only."
+            }
+            SandboxPolicy::DangerFullAccess => {
+                "Commands run without a sandbox and can modify any file or access the network."
+            }
+        }
+    }
+}
+
+/// Whether a run can modify files: any sandbox other than read-only, yolo, which bypasses the
+/// sandbox entirely, or full_auto, which implies workspace-write
+pub fn can_write(sandbox: &SandboxPolicy, yolo: bool, full_auto: bool) -> bool {
+    yolo || full_auto || *sandbox != SandboxPolicy::ReadOnly
+}
+
+/// ANSI color mode forwarded to codex via --color
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// Let codex decide (the flag is omitted)
+    #[default]
+    Auto,
+    /// Always emit ANSI color codes
+    Always,
+    /// Never emit ANSI color codes (recommended, since output is captured rather than displayed)
+    Never,
+}
+
+impl ColorMode {
+    /// Value for codex's --color flag, or None when the flag should be omitted
+    pub fn as_flag_value(self) -> Option<&'static str> {
+        match self {
+            ColorMode::Auto => None,
+            ColorMode::Always => Some("always"),
+            ColorMode::Never => Some("never"),
+        }
+    }
+}
+
+/// How consecutive agent_message items are joined into agent_messages
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentMessageJoin {
+    /// Separate messages with a newline
+    #[default]
+    Newline,
+    /// Concatenate seamlessly, for answers streamed as many small chunks
+    None,
+    /// Separate messages with a single space
+    Space,
+}
+
+impl AgentMessageJoin {
+    pub fn separator(self) -> &'static str {
+        match self {
+            AgentMessageJoin::Newline => "\n",
+            AgentMessageJoin::None => "",
+            AgentMessageJoin::Space => " ",
+        }
+    }
+}
+
+/// Which side of agent_messages is dropped once it exceeds the size limit
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentTruncateFrom {
+    /// Keep the earliest messages and stop appending once the limit is hit
+    #[default]
+    End,
+    /// Keep the latest messages (usually the conclusion), dropping the oldest first
+    Start,
+}
+
+/// How bytes that are not valid UTF-8 in codex's stdout are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Utf8Mode {
+    /// Replace invalid sequences with U+FFFD and carry on
+    #[default]
+    Lossy,
+    /// Treat a line containing invalid UTF-8 as a parse error and record a warning
+    Strict,
+}
+
+/// Number of agent messages and JSON events seen in a session. Used to skip history that codex
+/// replays on resume when only the current turn's output is wanted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TurnOffset {
+    pub agent_messages: usize,
+    pub events: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -53,9 +232,467 @@ pub struct Options {
     /// Timeout in seconds for the codex execution. If None, defaults to 600 seconds (10 minutes).
     /// Set to a specific value to override. The library enforces a timeout to prevent unbounded execution.
     pub timeout_secs: Option<u64>,
+    /// Idle timeout in seconds. If set, the child is killed when no stdout line arrives within this
+    /// window, even if the total timeout has not elapsed yet. None or 0 disables the idle check.
+    pub idle_timeout_secs: Option<u64>,
+    /// Capture the first and last parsed JSON events, independent of return_all_messages
+    pub return_boundary_events: bool,
+    /// Color mode passed to codex via --color. None and Some(Auto) omit the flag
+    pub color: Option<ColorMode>,
+    /// Send AGENTS.md through codex's developer_instructions config instead of prepending it
+    /// to the prompt. Falls back to prepending where the flag cannot be passed safely (Windows)
+    pub agents_as_system_flag: bool,
+    /// Inject a `_received_ms` key (milliseconds since the run started) into each message
+    /// collected for all_messages. Off by default so payloads are passed through unmodified
+    pub annotate_timestamps: bool,
+    /// Number of times to retry a transient failure (capped at MAX_RETRIES). Retries never
+    /// extend the overall timeout_secs budget
+    pub max_retries: u32,
+    /// Separator inserted between consecutive agent messages
+    pub agent_message_join: AgentMessageJoin,
+    /// Leading agent messages and events to leave out of the result, as recorded after the
+    /// previous turn. Zero (the default) returns everything
+    pub delta_offset: TurnOffset,
+    /// Handling of invalid UTF-8 in codex's stdout
+    pub utf8_mode: Utf8Mode,
+    /// Command and arguments to pipe agent_messages through after a successful run
+    pub post_process_cmd: Option<Vec<String>>,
+    /// Event types that mark a completed run (e.g. "turn.completed", "task_complete"). When set,
+    /// a clean exit without any of them is still a success but records a warning
+    pub success_event_types: Vec<String>,
+    /// Collect the child's resource usage after it exits (Unix only; ignored elsewhere)
+    pub capture_resource_usage: bool,
+    /// Pass --full-auto (workspace-write sandbox with automatic approvals). Ignored under yolo,
+    /// which already bypasses approvals and the sandbox
+    pub full_auto: bool,
+    /// Also return stdout exactly as codex wrote it, capped at MAX_RAW_STDOUT_BYTES
+    pub return_raw_stdout: bool,
+    /// Stop codex as soon as the first agent message arrives and return just that message
+    pub return_on_first_message: bool,
+    /// Read codex's JSON events from this NDJSON file instead of spawning codex, for replaying a
+    /// recorded run through the same parser
+    pub replay_file: Option<PathBuf>,
+    /// Convert numeric ids in all_messages to strings (see NORMALIZED_ID_KEYS)
+    pub normalize_ids: bool,
+    /// Raise the timeout cap from MAX_TIMEOUT_SECS to MAX_LONG_TIMEOUT_SECS
+    pub allow_long_timeout: bool,
+    /// Collect todo, plan_update, and todo_list items into CodexResult::todos
+    pub return_todos: bool,
+    /// Start codex through `sh -lc` so PATH and environment from login profiles apply (Unix only)
+    pub use_login_shell: bool,
+    /// Wait a random 0..=spawn_jitter_ms milliseconds before spawning codex, so parallel calls
+    /// don't all start at once
+    pub spawn_jitter_ms: u64,
+    /// Also return agent_messages with Markdown formatting removed, in agent_messages_plain
+    pub plain_text_output: bool,
+    /// How long a writable run waits for another writable run in the same directory before failing
+    pub dir_lock_wait_secs: u64,
+    /// Leave the first N messages out of all_messages, for clients that already have them
+    pub after_message_index: Option<usize>,
+    /// Event types left out of all_messages (e.g. noisy heartbeats). Applied after
+    /// all_messages_include_types
+    pub all_messages_exclude_types: Vec<String>,
+    /// When non-empty, only these event types are collected into all_messages
+    pub all_messages_include_types: Vec<String>,
+    /// Record the working directory's HEAD commit before and after the run
+    pub capture_git_head: bool,
+    /// Extra HTTP headers codex sends to the model provider (see HEADERS_PROVIDER_ID)
+    pub provider_headers: BTreeMap<String, String>,
+    /// Which side of agent_messages is dropped when it exceeds the size limit
+    pub agent_truncate_from: AgentTruncateFrom,
+    /// Encoding of codex's stdout, e.g. "gbk" or "latin1" (default: UTF-8)
+    pub stdout_encoding: Option<String>,
+    /// Raise (or lower) the AGENTS.md size limit for this run, capped at MAX_AGENTS_ABSOLUTE_MAX
+    pub agents_absolute_max: Option<u64>,
+    /// Append each agent message to this file as it arrives, one per line, e.g. for `tail -f`
+    pub agent_messages_sink: Option<PathBuf>,
+    /// Only write agent messages to agent_messages_sink, leaving agent_messages empty
+    pub agent_messages_sink_only: bool,
+    /// Cancelling this token stops codex and returns what was collected so far, including the
+    /// session_id, so the session can be resumed later
+    pub cancel: Option<CancellationToken>,
+    /// Receives the run's start, every codex event, and its end
+    pub event_sink: Option<Arc<dyn EventSink>>,
+    /// Use codex's local open-source model provider (`--oss`) instead of the hosted API
+    pub oss: bool,
+    /// Command (program followed by its arguments) run in working_dir after a successful run to
+    /// check the result, e.g. the test suite. Its outcome is reported in CodexResult::verification
+    pub verify_cmd: Option<Vec<String>>,
+    /// Mark the run as failed when verify_cmd fails
+    pub verify_affects_success: bool,
+    /// Tag removed from the start of every agent message (e.g. "assistant> "); messages without
+    /// it are kept as they are
+    pub agent_message_strip_prefix: Option<String>,
+    /// Stop codex as soon as an agent message contains one of these phrases (e.g. a secret-leak
+    /// marker). The run is returned with `interrupted` set and a warning naming the phrase.
+    /// Empty phrases are ignored
+    pub stop_sequences: Vec<String>,
+    /// Collect image files codex reports producing (`image` and image `file_output` items) into
+    /// CodexResult::output_images
+    pub return_output_images: bool,
+    /// Fail the run instead of continuing without AGENTS.md when an existing AGENTS.md cannot be
+    /// read (e.g. permission denied). An AGENTS.md over the size limit is still only a warning
+    pub agents_read_error_fatal: bool,
 }
 
-#[derive(Debug)]
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            prompt: String::new(),
+            working_dir: PathBuf::new(),
+            sandbox: SandboxPolicy::default(),
+            session_id: None,
+            skip_git_repo_check: false,
+            return_all_messages: false,
+            return_all_messages_limit: None,
+            image_paths: Vec::new(),
+            model: None,
+            yolo: false,
+            profile: None,
+            timeout_secs: None,
+            idle_timeout_secs: None,
+            return_boundary_events: false,
+            color: None,
+            agents_as_system_flag: false,
+            annotate_timestamps: false,
+            max_retries: 0,
+            agent_message_join: AgentMessageJoin::default(),
+            delta_offset: Default::default(),
+            utf8_mode: Default::default(),
+            post_process_cmd: None,
+            success_event_types: Vec::new(),
+            capture_resource_usage: false,
+            full_auto: false,
+            return_raw_stdout: false,
+            return_on_first_message: false,
+            replay_file: None,
+            normalize_ids: false,
+            allow_long_timeout: false,
+            return_todos: false,
+            use_login_shell: false,
+            spawn_jitter_ms: 0,
+            plain_text_output: false,
+            dir_lock_wait_secs: DEFAULT_DIR_LOCK_WAIT_SECS,
+            after_message_index: None,
+            all_messages_exclude_types: Vec::new(),
+            all_messages_include_types: Vec::new(),
+            capture_git_head: false,
+            provider_headers: BTreeMap::new(),
+            agent_truncate_from: AgentTruncateFrom::default(),
+            stdout_encoding: None,
+            agents_absolute_max: None,
+            agent_messages_sink: None,
+            agent_messages_sink_only: false,
+            cancel: None,
+            event_sink: None,
+            oss: false,
+            verify_cmd: None,
+            verify_affects_success: false,
+            agent_message_strip_prefix: None,
+            stop_sequences: Vec::new(),
+            return_output_images: false,
+            agents_read_error_fatal: false,
+        }
+    }
+}
+
+impl Options {
+    /// Start building options for a run with the required prompt and working directory
+    pub fn builder(prompt: impl Into<String>, working_dir: impl Into<PathBuf>) -> OptionsBuilder {
+        OptionsBuilder::new(prompt, working_dir)
+    }
+}
+
+/// Builder for [`Options`] with safe defaults: read-only sandbox, new session, no yolo,
+/// git repo check enabled, and the default timeout
+#[derive(Debug, Clone)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn new(prompt: impl Into<String>, working_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            options: Options {
+                prompt: prompt.into(),
+                working_dir: working_dir.into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn with_sandbox(mut self, sandbox: SandboxPolicy) -> Self {
+        self.options.sandbox = sandbox;
+        self
+    }
+
+    pub fn with_session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.options.session_id = Some(session_id.into());
+        self
+    }
+
+    pub fn with_skip_git_repo_check(mut self, skip: bool) -> Self {
+        self.options.skip_git_repo_check = skip;
+        self
+    }
+
+    pub fn with_return_all_messages(mut self, enabled: bool) -> Self {
+        self.options.return_all_messages = enabled;
+        self
+    }
+
+    pub fn with_return_all_messages_limit(mut self, limit: usize) -> Self {
+        self.options.return_all_messages_limit = Some(limit);
+        self
+    }
+
+    pub fn with_image_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.image_paths.push(path.into());
+        self
+    }
+
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.options.model = Some(model.into());
+        self
+    }
+
+    pub fn with_yolo(mut self, yolo: bool) -> Self {
+        self.options.yolo = yolo;
+        self
+    }
+
+    pub fn with_profile(mut self, profile: impl Into<String>) -> Self {
+        self.options.profile = Some(profile.into());
+        self
+    }
+
+    pub fn with_timeout_secs(mut self, secs: u64) -> Self {
+        self.options.timeout_secs = Some(secs);
+        self
+    }
+
+    pub fn with_idle_timeout_secs(mut self, secs: u64) -> Self {
+        self.options.idle_timeout_secs = Some(secs);
+        self
+    }
+
+    pub fn with_return_boundary_events(mut self, enabled: bool) -> Self {
+        self.options.return_boundary_events = enabled;
+        self
+    }
+
+    pub fn with_color(mut self, color: ColorMode) -> Self {
+        self.options.color = Some(color);
+        self
+    }
+
+    pub fn with_agents_as_system_flag(mut self, enabled: bool) -> Self {
+        self.options.agents_as_system_flag = enabled;
+        self
+    }
+
+    pub fn with_annotate_timestamps(mut self, enabled: bool) -> Self {
+        self.options.annotate_timestamps = enabled;
+        self
+    }
+
+    pub fn with_max_retries(mut self, retries: u32) -> Self {
+        self.options.max_retries = retries;
+        self
+    }
+
+    pub fn with_agent_message_join(mut self, join: AgentMessageJoin) -> Self {
+        self.options.agent_message_join = join;
+        self
+    }
+
+    pub fn with_delta_offset(mut self, offset: TurnOffset) -> Self {
+        self.options.delta_offset = offset;
+        self
+    }
+
+    pub fn with_utf8_mode(mut self, mode: Utf8Mode) -> Self {
+        self.options.utf8_mode = mode;
+        self
+    }
+
+    pub fn with_post_process_cmd(mut self, cmd: Vec<String>) -> Self {
+        self.options.post_process_cmd = Some(cmd);
+        self
+    }
+
+    pub fn with_success_event_types(mut self, types: Vec<String>) -> Self {
+        self.options.success_event_types = types;
+        self
+    }
+
+    pub fn with_capture_resource_usage(mut self, enabled: bool) -> Self {
+        self.options.capture_resource_usage = enabled;
+        self
+    }
+
+    pub fn with_full_auto(mut self, enabled: bool) -> Self {
+        self.options.full_auto = enabled;
+        self
+    }
+
+    pub fn with_return_raw_stdout(mut self, enabled: bool) -> Self {
+        self.options.return_raw_stdout = enabled;
+        self
+    }
+
+    pub fn with_return_on_first_message(mut self, enabled: bool) -> Self {
+        self.options.return_on_first_message = enabled;
+        self
+    }
+
+    pub fn with_replay_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.replay_file = Some(path.into());
+        self
+    }
+
+    pub fn with_normalize_ids(mut self, enabled: bool) -> Self {
+        self.options.normalize_ids = enabled;
+        self
+    }
+
+    pub fn with_allow_long_timeout(mut self, enabled: bool) -> Self {
+        self.options.allow_long_timeout = enabled;
+        self
+    }
+
+    pub fn with_return_todos(mut self, enabled: bool) -> Self {
+        self.options.return_todos = enabled;
+        self
+    }
+
+    pub fn with_use_login_shell(mut self, enabled: bool) -> Self {
+        self.options.use_login_shell = enabled;
+        self
+    }
+
+    pub fn with_spawn_jitter_ms(mut self, max_ms: u64) -> Self {
+        self.options.spawn_jitter_ms = max_ms;
+        self
+    }
+
+    pub fn with_plain_text_output(mut self, enabled: bool) -> Self {
+        self.options.plain_text_output = enabled;
+        self
+    }
+
+    pub fn with_dir_lock_wait_secs(mut self, secs: u64) -> Self {
+        self.options.dir_lock_wait_secs = secs;
+        self
+    }
+
+    pub fn with_after_message_index(mut self, index: usize) -> Self {
+        self.options.after_message_index = Some(index);
+        self
+    }
+
+    pub fn with_all_messages_exclude_types(mut self, types: Vec<String>) -> Self {
+        self.options.all_messages_exclude_types = types;
+        self
+    }
+
+    pub fn with_all_messages_include_types(mut self, types: Vec<String>) -> Self {
+        self.options.all_messages_include_types = types;
+        self
+    }
+
+    pub fn with_capture_git_head(mut self, enabled: bool) -> Self {
+        self.options.capture_git_head = enabled;
+        self
+    }
+
+    pub fn with_agent_truncate_from(mut self, side: AgentTruncateFrom) -> Self {
+        self.options.agent_truncate_from = side;
+        self
+    }
+
+    pub fn with_stdout_encoding(mut self, label: impl Into<String>) -> Self {
+        self.options.stdout_encoding = Some(label.into());
+        self
+    }
+
+    pub fn with_oss(mut self, enabled: bool) -> Self {
+        self.options.oss = enabled;
+        self
+    }
+
+    pub fn with_verify_cmd(mut self, cmd: Vec<String>) -> Self {
+        self.options.verify_cmd = Some(cmd);
+        self
+    }
+
+    pub fn with_verify_affects_success(mut self, enabled: bool) -> Self {
+        self.options.verify_affects_success = enabled;
+        self
+    }
+
+    pub fn with_agent_message_strip_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.options.agent_message_strip_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn with_stop_sequences(mut self, sequences: Vec<String>) -> Self {
+        self.options.stop_sequences = sequences;
+        self
+    }
+
+    pub fn with_return_output_images(mut self, enabled: bool) -> Self {
+        self.options.return_output_images = enabled;
+        self
+    }
+
+    pub fn with_agents_read_error_fatal(mut self, enabled: bool) -> Self {
+        self.options.agents_read_error_fatal = enabled;
+        self
+    }
+
+    pub fn with_event_sink(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.options.event_sink = Some(sink);
+        self
+    }
+
+    pub fn with_cancel_token(mut self, token: CancellationToken) -> Self {
+        self.options.cancel = Some(token);
+        self
+    }
+
+    pub fn with_agent_messages_sink(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.agent_messages_sink = Some(path.into());
+        self
+    }
+
+    pub fn with_agent_messages_sink_only(mut self, enabled: bool) -> Self {
+        self.options.agent_messages_sink_only = enabled;
+        self
+    }
+
+    pub fn with_agents_absolute_max(mut self, max_bytes: u64) -> Self {
+        self.options.agents_absolute_max = Some(max_bytes);
+        self
+    }
+
+    pub fn with_provider_header(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.options
+            .provider_headers
+            .insert(name.into(), value.into());
+        self
+    }
+
+    pub fn build(self) -> Options {
+        self.options
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct CodexResult {
     pub success: bool,
     pub session_id: String,
@@ -65,6 +702,512 @@ pub struct CodexResult {
     pub all_messages_truncated: bool,
     pub error: Option<String>,
     pub warnings: Option<String>,
+    /// First parsed JSON event (only captured when return_boundary_events is set)
+    pub first_event: Option<Value>,
+    /// Last parsed JSON event (only captured when return_boundary_events is set)
+    pub last_event: Option<Value>,
+    /// Seconds to wait before retrying, parsed from a rate-limit error's retry_after/reset_at
+    pub retry_after_secs: Option<u64>,
+    /// Agent messages and events seen in this run, including any skipped by delta_offset
+    pub turn_offset: TurnOffset,
+    /// Resource usage of the codex process (only when capture_resource_usage is set, on Unix)
+    pub resource_usage: Option<ResourceUsage>,
+    /// Each distinct error recorded during the run, in order. `error` joins the same
+    /// information into one string
+    pub errors: Vec<String>,
+    /// Codex's stdout as received, up to MAX_RAW_STDOUT_BYTES (only when return_raw_stdout is set)
+    pub raw_stdout: Option<String>,
+    /// Whether raw_stdout hit the size cap or contains a line cut at the line length limit
+    pub raw_stdout_truncated: bool,
+    /// Whether codex was stopped on purpose before finishing (return_on_first_message or
+    /// cancellation through Options::cancel)
+    pub interrupted: bool,
+    /// Message cap actually applied to all_messages after clamping (only when
+    /// return_all_messages is set)
+    pub effective_message_limit: Option<usize>,
+    /// Latest state of codex's todo list (only when return_todos is set)
+    pub todos: Vec<TodoItem>,
+    /// AGENTS.md file whose content was passed to codex. None when it was absent, empty, or
+    /// skipped (e.g. over MAX_AGENTS_MD_SIZE or not valid UTF-8)
+    pub agents_md_path: Option<PathBuf>,
+    /// Milliseconds waited before spawning codex because of spawn_jitter_ms
+    pub spawn_delay_ms: u64,
+    /// agent_messages with Markdown removed, when plain_text_output is requested
+    pub agent_messages_plain: Option<String>,
+    /// Number of messages codex produced in this run, including any skipped by
+    /// after_message_index; pass it as the next after_message_index to fetch only newer messages
+    /// (only when return_all_messages is set)
+    pub message_count: Option<usize>,
+    /// Number of times codex was started, including retries of transient failures (1 when there
+    /// were no retries)
+    pub attempts: u32,
+    /// HEAD commit of the working directory before the run (only when capture_git_head is set
+    /// and the directory is in a git repository with commits)
+    pub git_before: Option<String>,
+    /// HEAD commit of the working directory after the run, under the same conditions
+    pub git_after: Option<String>,
+    /// The final answer on its own: the agent_message marked `"final": true`, else the last
+    /// agent_message of the run
+    pub final_answer: Option<String>,
+    /// Exit code of the codex process; None when it was killed by a signal or never exited
+    /// (e.g. on timeout). Messages collected before a nonzero exit are still returned
+    pub exit_code: Option<i32>,
+    /// Milliseconds from spawning codex to its first agent_message; None when none arrived
+    pub ttft_ms: Option<u64>,
+    /// Outcome of verify_cmd; None when none was given or the run did not succeed
+    pub verification: Option<Verification>,
+    /// Image files codex produced, in order of appearance (only when return_output_images is
+    /// set). Paths that are missing, not images, or over MAX_OUTPUT_IMAGE_BYTES are left out
+    /// with a warning
+    pub output_images: Vec<OutputImage>,
+}
+
+impl CodexResult {
+    /// Whether AGENTS.md content was passed to codex for this run
+    pub fn agents_md_applied(&self) -> bool {
+        self.agents_md_path.is_some()
+    }
+}
+
+/// Outcome of running verify_cmd after codex finished
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, schemars::JsonSchema)]
+pub struct Verification {
+    /// Whether the command exited with status 0
+    pub passed: bool,
+    /// Exit code of the command; None when it could not be started, timed out, or was killed by
+    /// a signal
+    pub exit_code: Option<i32>,
+    /// Combined stdout and stderr, keeping the last MAX_VERIFY_OUTPUT_BYTES
+    pub output: String,
+}
+
+/// Largest file reported in CodexResult::output_images
+pub const MAX_OUTPUT_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Image file codex reported producing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, schemars::JsonSchema)]
+pub struct OutputImage {
+    /// Path of the file, resolved against the working directory when codex reported it relative
+    pub path: PathBuf,
+    /// MIME type inferred from the file extension, e.g. image/png
+    pub mime: String,
+}
+
+/// MIME type of an image file by extension; None when the extension is not a known image type
+fn image_mime_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    Some(match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "bmp" => "image/bmp",
+        _ => return None,
+    })
+}
+
+/// Record the file behind an `image` or `file_output` item. Only image files are collected from
+/// file_output items; other files are ignored without a warning
+fn record_output_image(
+    result: &mut CodexResult,
+    working_dir: &Path,
+    item_type: &str,
+    item: &serde_json::Map<String, Value>,
+) {
+    let Some(path) = item.get("path").and_then(Value::as_str) else {
+        return;
+    };
+    let path = working_dir.join(path);
+    let Some(mime) = image_mime_type(&path) else {
+        if item_type == "image" {
+            let warning = format!(
+                "Output image {} does not have a recognized image extension; skipped",
+                path.display()
+            );
+            result.warnings = push_warning(result.warnings.take(), &warning);
+        }
+        return;
+    };
+    if result.output_images.iter().any(|image| image.path == path) {
+        return;
+    }
+    let problem = match std::fs::metadata(&path) {
+        Err(e) => Some(format!("cannot be read: {}", e)),
+        Ok(metadata) if !metadata.is_file() => Some("is not a file".to_string()),
+        Ok(metadata) if metadata.len() > MAX_OUTPUT_IMAGE_BYTES => Some(format!(
+            "is {} bytes, over the {} byte limit",
+            metadata.len(),
+            MAX_OUTPUT_IMAGE_BYTES
+        )),
+        Ok(_) => None,
+    };
+    match problem {
+        Some(problem) => {
+            let warning = format!("Output image {} {}; skipped", path.display(), problem);
+            result.warnings = push_warning(result.warnings.take(), &warning);
+        }
+        None => result.output_images.push(OutputImage {
+            path,
+            mime: mime.to_string(),
+        }),
+    }
+}
+
+/// One entry of codex's todo list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, schemars::JsonSchema)]
+pub struct TodoItem {
+    /// Identifier used to replace earlier versions of the same entry
+    #[serde(skip)]
+    pub id: Option<String>,
+    pub text: String,
+    /// Status as reported by codex, e.g. pending, in_progress, or completed
+    pub status: String,
+}
+
+impl TodoItem {
+    /// Read an entry from a todo object. A boolean `completed` stands in for a missing `status`.
+    fn from_json(entry: &serde_json::Map<String, Value>) -> Option<Self> {
+        let text = entry.get("text").and_then(Value::as_str)?.to_string();
+        let status = match (
+            entry.get("status").and_then(Value::as_str),
+            entry.get("completed"),
+        ) {
+            (Some(status), _) => status.to_string(),
+            (None, Some(Value::Bool(true))) => "completed".to_string(),
+            _ => "pending".to_string(),
+        };
+        let id = entry.get("id").and_then(|id| match id {
+            Value::String(id) => Some(id.clone()),
+            Value::Number(id) => Some(id.to_string()),
+            _ => None,
+        });
+        Some(Self { id, text, status })
+    }
+}
+
+/// Apply a todo, plan_update, or todo_list item. An item carrying an `items` array is a full snapshot that
+/// replaces the list; otherwise it is a single entry that replaces the one with the same id.
+fn update_todos(todos: &mut Vec<TodoItem>, item: &serde_json::Map<String, Value>) {
+    if let Some(entries) = item.get("items").and_then(Value::as_array) {
+        *todos = entries
+            .iter()
+            .filter_map(Value::as_object)
+            .filter_map(TodoItem::from_json)
+            .collect();
+        return;
+    }
+    let Some(todo) = TodoItem::from_json(item) else {
+        return;
+    };
+    match todos
+        .iter_mut()
+        .find(|existing| todo.id.is_some() && existing.id == todo.id)
+    {
+        Some(existing) => *existing = todo,
+        None => todos.push(todo),
+    }
+}
+
+/// Resource usage of a finished codex process, as reported by wait4
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, schemars::JsonSchema)]
+pub struct ResourceUsage {
+    /// Peak resident set size in kilobytes
+    pub max_rss_kb: u64,
+    /// CPU time spent in user mode, in milliseconds
+    pub user_cpu_ms: u64,
+    /// CPU time spent in the kernel, in milliseconds
+    pub system_cpu_ms: u64,
+}
+
+#[cfg(unix)]
+impl ResourceUsage {
+    fn from_rusage(usage: &libc::rusage) -> Self {
+        let millis = |tv: libc::timeval| tv.tv_sec as u64 * 1000 + tv.tv_usec as u64 / 1000;
+        // ru_maxrss is reported in kilobytes on Linux but in bytes on macOS
+        let max_rss = usage.ru_maxrss.max(0) as u64;
+        let max_rss_kb = if cfg!(target_os = "macos") {
+            max_rss / 1024
+        } else {
+            max_rss
+        };
+        Self {
+            max_rss_kb,
+            user_cpu_ms: millis(usage.ru_utime),
+            system_cpu_ms: millis(usage.ru_stime),
+        }
+    }
+}
+
+/// Id fields that normalize_ids converts from numbers to strings, both at the top level of an
+/// event and inside its `item` object
+pub const NORMALIZED_ID_KEYS: &[&str] = &["id", "thread_id", "turn_id", "item_id", "call_id"];
+
+/// Rewrite numeric NORMALIZED_ID_KEYS values as strings, since codex versions disagree on the type
+fn normalize_message_ids(message: &mut HashMap<String, Value>) {
+    fn stringify(value: &mut Value) {
+        if let Value::Number(number) = value {
+            *value = Value::String(number.to_string());
+        }
+    }
+
+    for key in NORMALIZED_ID_KEYS {
+        if let Some(value) = message.get_mut(*key) {
+            stringify(value);
+        }
+    }
+    if let Some(Value::Object(item)) = message.get_mut("item") {
+        for key in NORMALIZED_ID_KEYS {
+            if let Some(value) = item.get_mut(*key) {
+                stringify(value);
+            }
+        }
+    }
+}
+
+/// Remove inline Markdown: emphasis markers, code spans, and link/image syntax (keeping the text)
+fn strip_inline_markdown(line: &str) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut out = String::with_capacity(line.len());
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '`' => {}
+            '*' | '_' if chars.get(i + 1) == Some(&chars[i]) => i += 1,
+            // Single `*` emphasis only; `_` is too common inside identifiers
+            '*' if chars.get(i + 1).is_some_and(|c| !c.is_whitespace())
+                || (i > 0 && !chars[i - 1].is_whitespace()) => {}
+            '!' if chars.get(i + 1) == Some(&'[') => {}
+            '[' => {
+                let close = chars[i..].iter().position(|&c| c == ']').map(|p| i + p);
+                match close {
+                    Some(close) if chars.get(close + 1) == Some(&'(') => {
+                        let end = chars[close..].iter().position(|&c| c == ')');
+                        match end {
+                            Some(end) => {
+                                out.push_str(&strip_inline_markdown(
+                                    &chars[i + 1..close].iter().collect::<String>(),
+                                ));
+                                i = close + end + 1;
+                                continue;
+                            }
+                            None => out.push('['),
+                        }
+                    }
+                    _ => out.push('['),
+                }
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// Convert Markdown to plain text: code fences are dropped (their contents kept verbatim),
+/// heading, blockquote, and bullet markers are removed, horizontal rules are dropped, and inline
+/// emphasis, code spans, and links are flattened to their text
+pub fn markdown_to_plain_text(markdown: &str) -> String {
+    let mut lines = Vec::new();
+    let mut in_fence = false;
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            lines.push(line.to_string());
+            continue;
+        }
+        let indent = &line[..line.len() - trimmed.len()];
+        let mut content = trimmed;
+        let heading_level = content.chars().take_while(|&c| c == '#').count();
+        if (1..=6).contains(&heading_level)
+            && content[heading_level..].starts_with(|c: char| c == ' ' || c == '\t')
+        {
+            content = content[heading_level..].trim();
+        }
+        while let Some(rest) = content.strip_prefix('>') {
+            content = rest.trim_start();
+        }
+        let compact: String = content.chars().filter(|c| !c.is_whitespace()).collect();
+        if compact.len() >= 3
+            && ['-', '*', '_']
+                .iter()
+                .any(|&marker| compact.chars().all(|c| c == marker))
+        {
+            continue;
+        }
+        for marker in ["- ", "* ", "+ "] {
+            if let Some(rest) = content.strip_prefix(marker) {
+                content = rest;
+                break;
+            }
+        }
+        lines.push(format!("{}{}", indent, strip_inline_markdown(content)));
+    }
+    lines.join("\n")
+}
+
+/// Whether an event belongs in all_messages under the type filters. A filter entry matches the
+/// event's own `type` or its item's `type` (e.g. `agent_message`). The include list is applied
+/// first, then the exclude list
+fn collects_message(opts: &Options, event: &Value) -> bool {
+    let types = [
+        event.get("type"),
+        event.get("item").and_then(|item| item.get("type")),
+    ];
+    let matches = |filter: &[String]| {
+        types
+            .iter()
+            .flatten()
+            .filter_map(|t| t.as_str())
+            .any(|t| filter.iter().any(|f| f == t))
+    };
+    (opts.all_messages_include_types.is_empty() || matches(&opts.all_messages_include_types))
+        && !matches(&opts.all_messages_exclude_types)
+}
+
+/// Upper bound for return_all_messages_limit, to prevent excessive memory usage
+pub const MAX_MESSAGE_LIMIT: usize = 50000;
+
+/// Maximum bytes of stdout kept for return_raw_stdout
+pub const MAX_RAW_STDOUT_BYTES: usize = 10 * 1024 * 1024;
+
+/// Sleep for the idle timeout, or forever when there is none
+async fn idle_sleep(idle_timeout_secs: Option<u64>) {
+    match idle_timeout_secs {
+        Some(secs) => tokio::time::sleep(std::time::Duration::from_secs(secs)).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolve when `token` is cancelled, or never when there is no token
+async fn cancelled(token: Option<&CancellationToken>) {
+    match token {
+        Some(token) => token.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Ring buffer of the most recent agent messages within `capacity` bytes, used for
+/// AgentTruncateFrom::Start
+struct AgentMessageTail {
+    messages: VecDeque<String>,
+    size: usize,
+    capacity: usize,
+    truncated: bool,
+}
+
+impl AgentMessageTail {
+    fn new(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            size: 0,
+            capacity,
+            truncated: false,
+        }
+    }
+
+    fn push(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let mut text = text;
+        if text.len() > self.capacity {
+            // A single oversized message keeps its end, cut at a character boundary
+            let mut start = text.len() - self.capacity;
+            while !text.is_char_boundary(start) {
+                start += 1;
+            }
+            text = &text[start..];
+            self.truncated = true;
+        }
+        self.size += text.len();
+        self.messages.push_back(text.to_string());
+        while self.size > self.capacity {
+            let Some(dropped) = self.messages.pop_front() else {
+                break;
+            };
+            self.size -= dropped.len();
+            self.truncated = true;
+        }
+    }
+
+    /// Join the retained messages, prefixed with a marker when earlier content was dropped
+    fn finish(self, separator: &str) -> String {
+        let joined = Vec::from(self.messages).join(separator);
+        if self.truncated {
+            format!(
+                "[... Earlier agent messages truncated due to size limit ...]\n{}",
+                joined
+            )
+        } else {
+            joined
+        }
+    }
+}
+
+/// Open agent_messages_sink for appending, creating it if needed
+async fn open_agent_messages_sink(path: &Path) -> std::result::Result<tokio::fs::File, String> {
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(|e| {
+            format!(
+                "Failed to open agent_messages_sink {}: {}; agent messages were not streamed",
+                path.display(),
+                e
+            )
+        })
+}
+
+/// Write one agent message to the sink and flush it so readers see it immediately
+async fn append_agent_message(
+    file: &mut tokio::fs::File,
+    text: &str,
+) -> std::result::Result<(), String> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut line = String::with_capacity(text.len() + 1);
+    line.push_str(text);
+    line.push('\n');
+    let write = async {
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await
+    };
+    write.await.map_err(|e| {
+        format!(
+            "Failed to write to agent_messages_sink: {}; streaming stopped",
+            e
+        )
+    })
+}
+
+/// Append one stdout line to raw_stdout (when enabled), stopping at MAX_RAW_STDOUT_BYTES.
+/// Invalid UTF-8 is replaced, since the capture is returned as text.
+fn capture_raw_stdout(result: &mut CodexResult, line: &[u8], line_truncated: bool) {
+    let Some(raw) = result.raw_stdout.as_mut() else {
+        return;
+    };
+    if line_truncated {
+        result.raw_stdout_truncated = true;
+    }
+    let text = String::from_utf8_lossy(line);
+    let remaining = MAX_RAW_STDOUT_BYTES.saturating_sub(raw.len());
+    if text.len() <= remaining {
+        raw.push_str(&text);
+        return;
+    }
+    let mut end = remaining;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    raw.push_str(&text[..end]);
+    result.raw_stdout_truncated = true;
 }
 
 /// Result of reading a line with length limit
@@ -139,73 +1282,397 @@ async fn read_line_with_limit<R: AsyncBufReadExt + Unpin>(
 /// Execute Codex CLI with the given options and return the result
 /// Requires timeout to be set to prevent unbounded execution.
 /// If timeout_secs is None or 0, uses DEFAULT_TIMEOUT_SECS.
-/// If timeout_secs exceeds MAX_TIMEOUT_SECS, caps to MAX_TIMEOUT_SECS.
+/// If timeout_secs exceeds MAX_TIMEOUT_SECS, caps to MAX_TIMEOUT_SECS (MAX_LONG_TIMEOUT_SECS when
+/// allow_long_timeout is set).
+/// Transient failures are retried up to max_retries times, but every attempt and the backoff
+/// between attempts share the single timeout_secs deadline. A retry_after hint from a rate-limit
+/// error replaces the exponential backoff.
 pub async fn run(opts: Options) -> Result<CodexResult> {
+    let Some(sink) = opts.event_sink.clone() else {
+        return run_with_git_head(opts).await;
+    };
+    sink.on_run_start(&opts);
+    let started = std::time::Instant::now();
+    let outcome = run_with_git_head(opts).await;
+    let summary = match &outcome {
+        Ok(result) => RunSummary::from_result(result, started.elapsed()),
+        Err(e) => RunSummary::failed(format!("{:#}", e), started.elapsed()),
+    };
+    sink.on_run_end(&summary);
+    outcome
+}
+
+/// Run codex, recording HEAD before and after when capture_git_head is set
+async fn run_with_git_head(opts: Options) -> Result<CodexResult> {
+    if !opts.capture_git_head {
+        return run_with_retries(opts).await;
+    }
+    let working_dir = opts.working_dir.clone();
+    let git_before = crate::git::head_commit(&working_dir);
+    let mut result = run_with_retries(opts).await?;
+    result.git_before = git_before;
+    result.git_after = crate::git::head_commit(&working_dir);
+    Ok(result)
+}
+
+/// Run codex under the timeout, retrying transient failures, then verify_cmd when one is given
+/// and the run succeeded. Verification holds the directory lock and shares the deadline
+async fn run_with_retries(opts: Options) -> Result<CodexResult> {
     // Ensure timeout is always set and within bounds
+    let max_timeout_secs = max_timeout_secs(opts.allow_long_timeout);
     let timeout_secs = match opts.timeout_secs {
         None | Some(0) => DEFAULT_TIMEOUT_SECS,
-        Some(t) if t > MAX_TIMEOUT_SECS => MAX_TIMEOUT_SECS,
+        Some(t) if t > max_timeout_secs => max_timeout_secs,
         Some(t) => t,
     };
 
     let opts = Options {
-        prompt: opts.prompt,
-        working_dir: opts.working_dir,
-        sandbox: opts.sandbox,
-        session_id: opts.session_id,
-        skip_git_repo_check: opts.skip_git_repo_check,
-        return_all_messages: opts.return_all_messages,
-        return_all_messages_limit: opts.return_all_messages_limit,
-        image_paths: opts.image_paths,
-        model: opts.model,
-        yolo: opts.yolo,
-        profile: opts.profile,
         timeout_secs: Some(timeout_secs),
+        ..opts
     };
 
-    // Apply timeout
-    let duration = std::time::Duration::from_secs(timeout_secs);
-    match tokio::time::timeout(duration, run_internal(opts)).await {
-        Ok(result) => result,
-        Err(_) => {
-            // Timeout occurred - the child process will be killed automatically via kill_on_drop
-            let result = CodexResult {
-                success: false,
-                session_id: String::new(),
-                agent_messages: String::new(),
-                agent_messages_truncated: false,
-                all_messages: Vec::new(),
-                all_messages_truncated: false,
-                error: Some(format!(
-                    "Codex execution timed out after {} seconds",
-                    timeout_secs
-                )),
-                warnings: None,
-            };
-            // Skip validation since timeout error is already well-defined
-            Ok(enforce_required_fields(result, ValidationMode::Skip))
+    // Apply timeout as a deadline shared by all attempts
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(timeout_secs);
+    let max_retries = opts.max_retries.min(MAX_RETRIES);
+    let mut attempt: u32 = 0;
+
+    // Runs that can write take turns per directory so concurrent edits can't corrupt each other.
+    // Waiting for the lock counts against the timeout
+    let _dir_lock = if needs_dir_lock(&opts) {
+        let wait = std::time::Duration::from_secs(opts.dir_lock_wait_secs);
+        match tokio::time::timeout_at(deadline, DirLock::acquire(&opts.working_dir, wait)).await {
+            Ok(lock) => Some(lock?),
+            Err(_) => return Ok(timeout_result(timeout_secs)),
+        }
+    } else {
+        None
+    };
+
+    loop {
+        let mut result = match tokio::time::timeout_at(deadline, run_internal(opts.clone())).await {
+            Ok(result) => result?,
+            // The child process will be killed automatically via kill_on_drop
+            Err(_) => {
+                let mut result = timeout_result(timeout_secs);
+                result.attempts = attempt + 1;
+                return Ok(result);
+            }
+        };
+        result.attempts = attempt + 1;
+
+        if attempt >= max_retries || !is_transient_failure(&result) {
+            return Ok(verify(result, &opts, deadline).await);
         }
+
+        // Prefer the server's own reset hint over blind exponential backoff
+        let backoff = result
+            .retry_after_secs
+            .map(std::time::Duration::from_secs)
+            .unwrap_or_else(|| retry_backoff(attempt));
+        if tokio::time::Instant::now() + backoff >= deadline {
+            return Ok(retry_budget_exhausted(result, attempt + 1, timeout_secs));
+        }
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
     }
 }
 
-/// Internal implementation of codex execution
-async fn run_internal(opts: Options) -> Result<CodexResult> {
+/// Run verify_cmd after a successful run and record its outcome. A failed check fails the run
+/// when verify_affects_success is set and is otherwise a warning
+async fn verify(
+    mut result: CodexResult,
+    opts: &Options,
+    deadline: tokio::time::Instant,
+) -> CodexResult {
+    let Some(cmd) = opts.verify_cmd.as_deref() else {
+        return result;
+    };
+    if !result.success {
+        return result;
+    }
+
+    let verification = run_verify_cmd(cmd, &opts.working_dir, deadline).await;
+    if !verification.passed {
+        let message = format!(
+            "verify_cmd '{}' failed (exit code {:?})",
+            cmd.join(" "),
+            verification.exit_code
+        );
+        if opts.verify_affects_success {
+            result.success = false;
+            result.errors.push(message.clone());
+            result.error = Some(message);
+        } else {
+            result.warnings = push_warning(result.warnings.take(), &message);
+        }
+    }
+    result.verification = Some(verification);
+    result
+}
+
+/// Whether a run may modify its working directory and so must hold the directory lock
+fn needs_dir_lock(opts: &Options) -> bool {
+    opts.replay_file.is_none() && can_write(&opts.sandbox, opts.yolo, opts.full_auto)
+}
+
+/// Timeout cap for a run, raised for long-running privileged requests
+pub fn max_timeout_secs(allow_long_timeout: bool) -> u64 {
+    if allow_long_timeout {
+        MAX_LONG_TIMEOUT_SECS
+    } else {
+        MAX_TIMEOUT_SECS
+    }
+}
+
+fn timeout_result(timeout_secs: u64) -> CodexResult {
+    failed_result(format!(
+        "Codex execution timed out after {} seconds",
+        timeout_secs
+    ))
+}
+
+/// Result of a run that failed with `message` before codex produced anything
+fn failed_result(message: String) -> CodexResult {
+    let result = CodexResult {
+        error: Some(message.clone()),
+        errors: vec![message],
+        attempts: 1,
+        ..Default::default()
+    };
+    // Skip validation since the error is already well-defined
+    enforce_required_fields(result, ValidationMode::Skip)
+}
+
+/// A failure is worth retrying when the error looks like a temporary upstream problem and the
+/// agent has not produced any answer yet (so a retry cannot duplicate visible work)
+fn is_transient_failure(result: &CodexResult) -> bool {
+    if result.success || !result.agent_messages.is_empty() {
+        return false;
+    }
+    let Some(error) = result.error.as_deref() else {
+        return false;
+    };
+    let error = error.to_ascii_lowercase();
+    TRANSIENT_ERROR_MARKERS
+        .iter()
+        .any(|marker| error.contains(marker))
+        || mentions_transient_status(&error)
+}
+
+/// Whether a lowercase error message names a transient status code after one of
+/// STATUS_CODE_PREFIXES, as a whole number
+fn mentions_transient_status(error: &str) -> bool {
+    STATUS_CODE_PREFIXES.iter().any(|prefix| {
+        TRANSIENT_STATUS_CODES.iter().any(|code| {
+            let needle = format!("{}{}", prefix, code);
+            error.match_indices(&needle).any(|(start, _)| {
+                let at_word_start = !error[..start].ends_with(|c: char| c.is_ascii_alphanumeric());
+                let at_number_end =
+                    !error[start + needle.len()..].starts_with(|c: char| c.is_ascii_digit());
+                at_word_start && at_number_end
+            })
+        })
+    })
+}
+
+/// Exponential backoff: 1s, 2s, 4s, ... capped at MAX_RETRY_BACKOFF_MS
+fn retry_backoff(attempt: u32) -> std::time::Duration {
+    let millis = RETRY_BASE_BACKOFF_MS.saturating_mul(1u64 << attempt.min(16));
+    std::time::Duration::from_millis(millis.min(MAX_RETRY_BACKOFF_MS))
+}
+
+/// Extract a retry delay from an error event. Accepts `retry_after` (seconds from now) or
+/// `reset_at` (Unix timestamp in seconds), on the event itself or its nested `error` object.
+fn parse_retry_after(event: &Value) -> Option<u64> {
+    let sources = [event.get("error"), Some(event)];
+    for source in sources.into_iter().flatten() {
+        if let Some(secs) = source.get("retry_after").and_then(json_seconds) {
+            return Some(secs);
+        }
+        if let Some(reset_at) = source.get("reset_at").and_then(json_seconds) {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            return Some(reset_at.saturating_sub(now));
+        }
+    }
+    None
+}
+
+/// Read a non-negative number of seconds from a JSON number or numeric string, rounding up
+fn json_seconds(value: &Value) -> Option<u64> {
+    let secs = match value {
+        Value::Number(n) => n.as_f64()?,
+        Value::String(s) => s.trim().parse::<f64>().ok()?,
+        _ => return None,
+    };
+    (secs.is_finite() && secs >= 0.0).then(|| secs.ceil() as u64)
+}
+
+fn retry_budget_exhausted(
+    mut result: CodexResult,
+    attempts: u32,
+    timeout_secs: u64,
+) -> CodexResult {
+    let note = format!(
+        "Retry budget exhausted: {} attempt(s) made and no time left for another within the {} second timeout",
+        attempts, timeout_secs
+    );
+    result.errors.push(note.clone());
+    result.error = Some(match result.error.take() {
+        Some(existing) if !existing.is_empty() => format!("{existing}\n{note}"),
+        _ => note,
+    });
+    result
+}
+
+/// Why an existing AGENTS.md was not used
+#[derive(Debug)]
+struct AgentsMdSkipped {
+    reason: String,
+    /// The file could not be read, as opposed to being over the size limit
+    unreadable: bool,
+}
+
+/// Read AGENTS.md from the working directory, if present.
+/// Returns Ok(None) when there is no usable file, and Err with the reason when it had to be skipped.
+async fn read_agents_md(
+    working_dir: &Path,
+    max_size: u64,
+) -> std::result::Result<Option<String>, AgentsMdSkipped> {
+    let path = working_dir.join(AGENTS_MD_FILE);
+    let unreadable = |e: std::io::Error| AgentsMdSkipped {
+        reason: format!("Failed to read {}: {}", path.display(), e),
+        unreadable: true,
+    };
+    let metadata = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(unreadable(e)),
+    };
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    if metadata.len() > max_size {
+        return Err(AgentsMdSkipped {
+            reason: format!(
+                "{} is {} bytes, exceeding the {} byte limit",
+                path.display(),
+                metadata.len(),
+                max_size
+            ),
+            unreadable: false,
+        });
+    }
+
+    let content = tokio::fs::read_to_string(&path).await.map_err(unreadable)?;
+    // Editors on Windows often save with a BOM, which str::trim does not remove
+    let content = content.strip_prefix('\u{FEFF}').unwrap_or(&content).trim();
+    Ok((!content.is_empty()).then(|| content.to_string()))
+}
+
+/// Whether `working_dir` has an AGENTS.md that would be injected, i.e. one within the size limit
+/// with content other than whitespace and a BOM
+pub fn has_agents_md(working_dir: &Path, max_size: u64) -> bool {
+    let path = working_dir.join(AGENTS_MD_FILE);
+    match std::fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() <= max_size => {}
+        _ => return false,
+    }
+    std::fs::read_to_string(&path).is_ok_and(|content| {
+        !content
+            .strip_prefix('\u{FEFF}')
+            .unwrap_or(&content)
+            .trim()
+            .is_empty()
+    })
+}
+
+/// Whether AGENTS.md goes through the developer_instructions flag rather than the prompt.
+/// On Windows arguments are re-parsed by cmd.exe, which cannot carry multi-line values safely.
+fn uses_agents_system_flag(opts: &Options) -> bool {
+    opts.agents_as_system_flag && !cfg!(windows)
+}
+
+fn prepend_agents_md(agents_md: &str, prompt: &str) -> String {
+    format!("<agents_md>\n{}\n</agents_md>\n\n{}", agents_md, prompt)
+}
+
+/// Base command for invoking the codex binary, without any arguments
+fn codex_command() -> Command {
     // Allow overriding the codex binary for tests or custom setups
     let codex_bin = std::env::var("CODEX_BIN").unwrap_or_else(|_| "codex".to_string());
 
-    // Build the base command
     // On Windows, codex is distributed as codex.cmd which requires cmd.exe to execute.
     // Use %ComSpec% to avoid PATH hijacking, /D to disable AutoRun, /S for proper quoting.
     // Note: Arguments pass through cmd.exe shell parsing - this is unavoidable for .cmd files.
     #[cfg(windows)]
-    let mut cmd = {
+    let cmd = {
         let comspec = std::env::var("ComSpec").unwrap_or_else(|_| "cmd.exe".to_string());
         let mut c = Command::new(comspec);
         c.args(["/D", "/S", "/C", &codex_bin]);
         c
     };
     #[cfg(not(windows))]
-    let mut cmd = Command::new(codex_bin);
+    let cmd = Command::new(codex_bin);
+
+    cmd
+}
+
+/// Script for wrap_in_login_shell. The program and arguments arrive as positional parameters and
+/// are only ever expanded inside double quotes, so the shell never re-parses their contents.
+#[cfg(unix)]
+const LOGIN_SHELL_SCRIPT: &str = "exec \"$0\" \"$@\"";
+
+/// Re-run `cmd` as `sh -lc 'exec "$0" "$@"' <program> <args>...`, so the program is resolved with
+/// the PATH a login shell sets up while every argument reaches it unchanged
+#[cfg(unix)]
+fn wrap_in_login_shell(cmd: &Command) -> Command {
+    let inner = cmd.as_std();
+    let mut shell = Command::new("/bin/sh");
+    shell
+        .args(["-lc", LOGIN_SHELL_SCRIPT])
+        .arg(inner.get_program())
+        .args(inner.get_args());
+    shell
+}
+
+/// Whether `model` names a hosted OpenAI model that a local `--oss` provider cannot serve.
+/// gpt-oss models and anything else (e.g. `qwen2.5-coder:7b`) are assumed to be local
+pub fn is_cloud_only_model(model: &str) -> bool {
+    let model = model.to_ascii_lowercase();
+    !model.starts_with("gpt-oss")
+        && ["gpt-", "o1", "o3", "o4", "codex-"]
+            .iter()
+            .any(|prefix| model.starts_with(prefix))
+}
+
+/// Model provider whose `http_headers` config receives provider_headers
+pub const HEADERS_PROVIDER_ID: &str = "openai";
+
+/// Whether `name` is a valid HTTP header name (an RFC 9110 token)
+pub fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// `--config` override adding one HTTP header to HEADERS_PROVIDER_ID. Name and value are written
+/// as JSON string literals, which are also valid TOML quoted keys and basic strings
+fn provider_header_override(name: &str, value: &str) -> String {
+    format!(
+        "model_providers.{}.http_headers.{}={}",
+        HEADERS_PROVIDER_ID,
+        Value::String(name.to_string()),
+        Value::String(value.to_string())
+    )
+}
+
+/// Build the codex command line for the given options without spawning it
+fn build_command(opts: &Options, agents_md: Option<&str>) -> Command {
+    let mut cmd = codex_command();
 
     cmd.args(["exec", "--sandbox", opts.sandbox.as_str(), "--cd"]);
 
@@ -224,12 +1691,34 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
     if let Some(ref profile) = opts.profile {
         cmd.args(["--profile", profile]);
     }
+    if opts.oss {
+        cmd.arg("--oss");
+    }
     if opts.yolo {
         cmd.arg("--yolo");
+    } else if opts.full_auto {
+        cmd.arg("--full-auto");
     }
     if opts.skip_git_repo_check {
         cmd.arg("--skip-git-repo-check");
     }
+    if let Some(flag) = opts.color.and_then(ColorMode::as_flag_value) {
+        cmd.args(["--color", flag]);
+    }
+    for (name, value) in &opts.provider_headers {
+        cmd.args(["--config", &provider_header_override(name, value)]);
+    }
+
+    let prompt = match agents_md {
+        Some(agents_md) if uses_agents_system_flag(opts) => {
+            // A JSON string literal is also a valid TOML basic string
+            let value = Value::String(agents_md.to_string());
+            cmd.args(["--config", &format!("developer_instructions={}", value)]);
+            opts.prompt.clone()
+        }
+        Some(agents_md) => prepend_agents_md(agents_md, &opts.prompt),
+        None => opts.prompt.clone(),
+    };
     if opts.return_all_messages {
         cmd.arg("--return-all-messages");
         if let Some(limit) = opts.return_all_messages_limit {
@@ -244,7 +1733,12 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
 
     // Add the prompt at the end - Command::arg() handles proper escaping across platforms
     // Note: When resuming, the prompt serves as a continuation message in the existing session
-    cmd.args(["--", &opts.prompt]);
+    cmd.args(["--", &prompt]);
+
+    #[cfg(unix)]
+    if opts.use_login_shell {
+        cmd = wrap_in_login_shell(&cmd);
+    }
 
     // Configure process
     cmd.stdin(Stdio::null());
@@ -252,27 +1746,121 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
     cmd.stderr(Stdio::piped());
     cmd.kill_on_drop(true); // Ensure child is killed if this future is dropped (e.g., on timeout)
 
+    cmd
+}
+
+/// A stream of codex output, either from the child's pipes or from a replay file
+type OutputStream = Box<dyn tokio::io::AsyncRead + Unpin + Send>;
+
+/// Spawn codex and take its stdout and stderr
+fn spawn_codex(
+    opts: &Options,
+    agents_md: Option<&str>,
+) -> Result<(Option<tokio::process::Child>, OutputStream, OutputStream)> {
+    let mut cmd = build_command(opts, agents_md);
+
     // Spawn the process
     let mut child = cmd.spawn().context("Failed to spawn codex command")?;
 
     // Read stdout
     let stdout = child.stdout.take().context("Failed to get stdout")?;
     let stderr = child.stderr.take().context("Failed to get stderr")?;
+    Ok((Some(child), Box::new(stdout), Box::new(stderr)))
+}
+
+/// Open a recorded NDJSON event file in place of codex's stdout, with empty stderr
+async fn open_replay(
+    path: &Path,
+) -> Result<(Option<tokio::process::Child>, OutputStream, OutputStream)> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open replay file {}", path.display()))?;
+    Ok((None, Box::new(file), Box::new(tokio::io::empty())))
+}
+
+/// Ask the child to stop, if there is one. Output is still drained afterwards. Returns whether
+/// a kill was sent, so the resulting signal exit is not reported as a separate failure
+fn stop_child(child: &mut Option<tokio::process::Child>) -> bool {
+    match child.as_mut() {
+        Some(child) => child.start_kill().is_ok(),
+        None => false,
+    }
+}
+
+/// Error reported when codex asks for approval, which it can never receive because stdin is null
+pub const APPROVAL_UNAVAILABLE_ERROR: &str =
+    "codex requested approval but stdin is unavailable; use yolo or an approval policy";
+
+/// Whether an event is codex asking for approval (e.g. `exec_approval_request`,
+/// `apply_patch_approval_request`), either as the event type or as an item type
+fn is_approval_request(event: &Value) -> bool {
+    let is_approval = |value: Option<&Value>| {
+        value
+            .and_then(|v| v.get("type"))
+            .and_then(|v| v.as_str())
+            .is_some_and(|t| t.ends_with("approval_request"))
+    };
+    is_approval(Some(event)) || is_approval(event.get("item")) || is_approval(event.get("msg"))
+}
+
+/// Random delay in 0..=max_ms milliseconds to spread out concurrent spawns
+fn spawn_jitter(max_ms: u64) -> std::time::Duration {
+    if max_ms == 0 {
+        return std::time::Duration::ZERO;
+    }
+    let random = uuid::Uuid::new_v4().as_u128();
+    std::time::Duration::from_millis((random % (u128::from(max_ms) + 1)) as u64)
+}
+
+/// Internal implementation of codex execution
+async fn run_internal(opts: Options) -> Result<CodexResult> {
+    let run_started = std::time::Instant::now();
+    let (agents_md, agents_md_warning) = match read_agents_md(
+        &opts.working_dir,
+        agents_md_size_limit(opts.agents_absolute_max),
+    )
+    .await
+    {
+        Ok(content) => (content, None),
+        Err(skipped) if skipped.unreadable && opts.agents_read_error_fatal => {
+            return Ok(failed_result(format!(
+                "{}; aborting because agents_read_error_fatal is set",
+                skipped.reason
+            )));
+        }
+        Err(skipped) => (
+            None,
+            Some(format!("{}; continuing without it", skipped.reason)),
+        ),
+    };
+    let spawn_delay = match opts.replay_file {
+        Some(_) => std::time::Duration::ZERO,
+        None => spawn_jitter(opts.spawn_jitter_ms),
+    };
+    if !spawn_delay.is_zero() {
+        tokio::time::sleep(spawn_delay).await;
+    }
+    let stdout_encoding =
+        stdout_encoding_for_label(opts.stdout_encoding.as_deref()).map_err(anyhow::Error::msg)?;
+    let (mut child, stdout, stderr) = match opts.replay_file.as_deref() {
+        Some(path) => open_replay(path).await?,
+        None => spawn_codex(&opts, agents_md.as_deref())?,
+    };
+    let spawned_at = std::time::Instant::now();
+    let agents_md_path = (agents_md.is_some() && opts.replay_file.is_none())
+        .then(|| opts.working_dir.join(AGENTS_MD_FILE));
 
     let mut result = CodexResult {
         success: true,
-        session_id: String::new(),
-        agent_messages: String::new(),
-        agent_messages_truncated: false,
-        all_messages: Vec::new(),
-        all_messages_truncated: false,
-        error: None,
-        warnings: None,
+        warnings: agents_md_warning,
+        raw_stdout: opts.return_raw_stdout.then(String::new),
+        agents_md_path,
+        spawn_delay_ms: spawn_delay.as_millis() as u64,
+        attempts: 1,
+        ..Default::default()
     };
 
     // Set default limit if return_all_messages is enabled but no limit specified
-    // Cap at 50000 to prevent excessive memory usage
-    const MAX_MESSAGE_LIMIT: usize = 50000;
     const DEFAULT_MESSAGE_LIMIT: usize = 10000;
     const MAX_AGENT_MESSAGES_SIZE: usize = 10 * 1024 * 1024; // 10MB limit for agent messages
     const MAX_ALL_MESSAGES_SIZE: usize = 50 * 1024 * 1024; // 50MB limit for all messages combined
@@ -281,71 +1869,87 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
     } else {
         DEFAULT_MESSAGE_LIMIT
     };
+    if opts.return_all_messages {
+        result.effective_message_limit = Some(message_limit);
+    }
 
     let mut all_messages_size: usize = 0;
+    let mut messages_seen: usize = 0;
+    let mut last_agent_message: Option<String> = None;
+    let mut marked_final_answer: Option<String> = None;
+    let mut agent_tail = (opts.agent_truncate_from == AgentTruncateFrom::Start)
+        .then(|| AgentMessageTail::new(MAX_AGENT_MESSAGES_SIZE));
+    let skip_messages = opts.after_message_index.unwrap_or(0);
+    let mut agent_sink = match opts.agent_messages_sink.as_deref() {
+        Some(path) => match open_agent_messages_sink(path).await {
+            Ok(file) => Some(file),
+            Err(warning) => {
+                result.warnings = push_warning(result.warnings.take(), &warning);
+                None
+            }
+        },
+        None => None,
+    };
 
     // Spawn a task to drain stderr and capture diagnostics with better error handling
-    const MAX_STDERR_SIZE: usize = 1024 * 1024; // 1MB limit for stderr
     const MAX_LINE_LENGTH: usize = 1024 * 1024; // 1MB per line to prevent memory spikes
-    let stderr_handle = tokio::spawn(async move {
-        let mut stderr_output = String::new();
-        let mut stderr_reader = BufReader::new(stderr);
-        let mut truncated = false;
-        let mut line_buf = Vec::new();
-
-        loop {
-            line_buf.clear();
-            match read_line_with_limit(&mut stderr_reader, &mut line_buf, MAX_LINE_LENGTH).await {
-                Ok(read_result) => {
-                    if read_result.bytes_read == 0 {
-                        break; // EOF
-                    }
-                    // Convert to string, handling invalid UTF-8
-                    let line = String::from_utf8_lossy(&line_buf);
-                    let line = line.trim_end_matches('\n').trim_end_matches('\r');
-
-                    // Check if adding this line would exceed the limit
-                    let new_size = stderr_output.len() + line.len() + 1; // +1 for newline
-                    if new_size > MAX_STDERR_SIZE {
-                        if !truncated {
-                            if !stderr_output.is_empty() {
-                                stderr_output.push('\n');
-                            }
-                            stderr_output.push_str("[... stderr truncated due to size limit ...]");
-                            truncated = true;
-                        }
-                        // Continue draining to prevent blocking the child process
-                    } else if !truncated {
-                        if !stderr_output.is_empty() {
-                            stderr_output.push('\n');
-                        }
-                        stderr_output.push_str(line.as_ref());
-                    }
-                }
-                Err(e) => {
-                    // Log the read error but continue - this preserves diagnostic info
-                    eprintln!("Warning: Failed to read from stderr: {}", e);
-                    break;
-                }
-            }
-        }
-
-        stderr_output
-    });
+    let stderr_handle = tokio::spawn(drain_stderr(stderr, MAX_LINE_LENGTH));
 
     // Read stdout line by line with length limit
     let mut reader = BufReader::new(stdout);
     let mut parse_error_seen = false;
+    let mut completion_seen = false;
+    let mut first_message_seen = false;
+    let mut matched_stop_sequence: Option<&str> = None;
+    // Every distinct thread_id, in order of appearance; more than one points at a codex problem
+    let mut thread_ids: Vec<String> = Vec::new();
+    let mut lossy_utf8_reported = false;
     let mut line_buf = Vec::new();
+    let idle_timeout_secs = opts.idle_timeout_secs.filter(|&secs| secs > 0);
+    // Set when we kill codex ourselves; the reason is already recorded, so its signal exit is not
+    let mut stopped_by_server = false;
 
     loop {
         line_buf.clear();
-        match read_line_with_limit(&mut reader, &mut line_buf, MAX_LINE_LENGTH).await {
+        let read_outcome = tokio::select! {
+            read = read_line_with_limit(&mut reader, &mut line_buf, MAX_LINE_LENGTH) => read,
+            // The sleep is recreated on every iteration, so the idle window resets per line
+            _ = idle_sleep(idle_timeout_secs) => {
+                let message = format!(
+                    "Codex produced no output for {} seconds (idle timeout)",
+                    idle_timeout_secs.unwrap_or_default()
+                );
+                result.success = false;
+                result.errors.push(message.clone());
+                result.error = Some(message);
+                stopped_by_server |= stop_child(&mut child);
+                break;
+            }
+            _ = cancelled(opts.cancel.as_ref()) => {
+                let message = if result.session_id.is_empty() {
+                    "Codex run was cancelled".to_string()
+                } else {
+                    format!(
+                        "Codex run was cancelled; resume session {} to continue",
+                        result.session_id
+                    )
+                };
+                result.success = false;
+                result.interrupted = true;
+                result.errors.push(message.clone());
+                result.error = Some(message);
+                stopped_by_server |= stop_child(&mut child);
+                break;
+            }
+        };
+        match read_outcome {
             Ok(read_result) => {
                 if read_result.bytes_read == 0 {
                     break; // EOF
                 }
 
+                capture_raw_stdout(&mut result, &line_buf, read_result.truncated);
+
                 // Check for line truncation - short-circuit to error instead of attempting parse
                 if read_result.truncated {
                     let error_msg = format!(
@@ -353,17 +1957,40 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
                         MAX_LINE_LENGTH
                     );
                     result.success = false;
+                    result.errors.push(error_msg.clone());
                     result.error = Some(error_msg);
                     if !parse_error_seen {
                         parse_error_seen = true;
                         // Stop the child so it cannot block on a full pipe, then keep draining
-                        let _ = child.start_kill();
+                        stopped_by_server |= stop_child(&mut child);
                     }
                     continue;
                 }
 
                 // Convert to string
-                let line = String::from_utf8_lossy(&line_buf);
+                let line = match decode_line(&line_buf, opts.utf8_mode, stdout_encoding) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        if !parse_error_seen {
+                            record_invalid_utf8(&mut result, &e, &line_buf);
+                            parse_error_seen = true;
+                            // Stop the child so it cannot block on a full pipe, then keep draining
+                            stopped_by_server |= stop_child(&mut child);
+                        }
+                        continue;
+                    }
+                };
+                // Lossy decoding only allocates when it had to replace something; the line was
+                // read whole, so the bytes really were invalid rather than cut mid-character
+                if stdout_encoding.is_none()
+                    && matches!(line, Cow::Owned(_))
+                    && !lossy_utf8_reported
+                {
+                    if let Err(e) = std::str::from_utf8(&line_buf) {
+                        record_lossy_utf8(&mut result, &e);
+                        lossy_utf8_reported = true;
+                    }
+                }
                 let line = line.trim_end_matches('\n').trim_end_matches('\r');
 
                 if line.is_empty() {
@@ -375,6 +2002,14 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
                     continue;
                 }
 
+                if exceeds_nesting_depth(line, MAX_JSON_NESTING_DEPTH) {
+                    record_nesting_error(&mut result, line);
+                    parse_error_seen = true;
+                    // Stop the child so it cannot block on a full pipe, then keep draining
+                    stopped_by_server |= stop_child(&mut child);
+                    continue;
+                }
+
                 // Parse JSON line
                 let line_data: Value = match serde_json::from_str(line) {
                     Ok(data) => data,
@@ -383,18 +2018,49 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
                         if !parse_error_seen {
                             parse_error_seen = true;
                             // Stop the child so it cannot block on a full pipe, then keep draining
-                            let _ = child.start_kill();
+                            stopped_by_server |= stop_child(&mut child);
                         }
                         continue;
                     }
                 };
+                if let Some(sink) = &opts.event_sink {
+                    sink.on_event(&line_data);
+                }
+
+                if opts.return_boundary_events {
+                    if result.first_event.is_none() {
+                        result.first_event = Some(line_data.clone());
+                    }
+                    result.last_event = Some(line_data.clone());
+                }
+
+                let replayed_event = result.turn_offset.events < opts.delta_offset.events;
+                result.turn_offset.events += 1;
 
                 // Collect all messages if requested (with bounds checking)
-                if opts.return_all_messages {
+                // Messages the client already has (after_message_index) are counted but not kept
+                let mut skipped_by_cursor = false;
+                if opts.return_all_messages && !replayed_event {
+                    messages_seen += 1;
+                    skipped_by_cursor = messages_seen <= skip_messages;
+                }
+                if opts.return_all_messages
+                    && !replayed_event
+                    && !skipped_by_cursor
+                    && collects_message(&opts, &line_data)
+                {
                     if result.all_messages.len() < message_limit {
-                        if let Ok(map) =
+                        if let Ok(mut map) =
                             serde_json::from_value::<HashMap<String, Value>>(line_data.clone())
                         {
+                            if opts.normalize_ids {
+                                normalize_message_ids(&mut map);
+                            }
+                            if opts.annotate_timestamps {
+                                let received_ms = run_started.elapsed().as_millis() as u64;
+                                map.insert("_received_ms".to_string(), Value::from(received_ms));
+                            }
+
                             // Estimate size of this message (JSON serialized size)
                             let message_size =
                                 serde_json::to_string(&map).map(|s| s.len()).unwrap_or(0);
@@ -415,6 +2081,9 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
                 // Extract thread_id
                 if let Some(thread_id) = line_data.get("thread_id").and_then(|v| v.as_str()) {
                     if !thread_id.is_empty() {
+                        if !thread_ids.iter().any(|seen| seen == thread_id) {
+                            thread_ids.push(thread_id.to_string());
+                        }
                         result.session_id = thread_id.to_string();
                     }
                 }
@@ -423,10 +2092,48 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
                 if let Some(item) = line_data.get("item").and_then(|v| v.as_object()) {
                     if let Some(item_type) = item.get("type").and_then(|v| v.as_str()) {
                         if item_type == "agent_message" {
-                            if let Some(text) = item.get("text").and_then(|v| v.as_str()) {
+                            let replayed_message = result.turn_offset.agent_messages
+                                < opts.delta_offset.agent_messages;
+                            result.turn_offset.agent_messages += 1;
+                            let text = item
+                                .get("text")
+                                .and_then(|v| v.as_str())
+                                .filter(|_| !replayed_message);
+                            if let Some(text) = text {
+                                let text = opts
+                                    .agent_message_strip_prefix
+                                    .as_deref()
+                                    .and_then(|prefix| text.strip_prefix(prefix))
+                                    .unwrap_or(text);
+                                if !first_message_seen {
+                                    result.ttft_ms = Some(spawned_at.elapsed().as_millis() as u64);
+                                }
+                                first_message_seen = true;
+                                if item.get("final").and_then(Value::as_bool) == Some(true) {
+                                    marked_final_answer = Some(text.to_string());
+                                }
+                                last_agent_message = Some(text.to_string());
+                                matched_stop_sequence = opts
+                                    .stop_sequences
+                                    .iter()
+                                    .find(|sequence| {
+                                        !sequence.is_empty() && text.contains(sequence.as_str())
+                                    })
+                                    .map(String::as_str);
+                                if let Some(file) = agent_sink.as_mut() {
+                                    if let Err(warning) = append_agent_message(file, text).await {
+                                        result.warnings =
+                                            push_warning(result.warnings.take(), &warning);
+                                        agent_sink = None;
+                                    }
+                                }
                                 // Check if adding this text would exceed the limit
                                 let new_size = result.agent_messages.len() + text.len();
-                                if new_size > MAX_AGENT_MESSAGES_SIZE {
+                                if agent_sink.is_some() && opts.agent_messages_sink_only {
+                                    // The sink holds the messages; agent_messages stays empty
+                                } else if let Some(tail) = agent_tail.as_mut() {
+                                    tail.push(text);
+                                } else if new_size > MAX_AGENT_MESSAGES_SIZE {
                                     if !result.agent_messages_truncated {
                                         result.agent_messages.push_str(
                                     "\n[... Agent messages truncated due to size limit ...]",
@@ -434,88 +2141,759 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
                                         result.agent_messages_truncated = true;
                                     }
                                 } else if !result.agent_messages_truncated {
-                                    // Add a newline separator between multiple agent messages for better parsing
+                                    // Separate multiple agent messages (newline by default) for better parsing
                                     if !result.agent_messages.is_empty() && !text.is_empty() {
-                                        result.agent_messages.push('\n');
+                                        result
+                                            .agent_messages
+                                            .push_str(opts.agent_message_join.separator());
                                     }
                                     result.agent_messages.push_str(text);
                                 }
                             }
+                        } else if opts.return_todos
+                            && matches!(item_type, "todo" | "plan_update" | "todo_list")
+                        {
+                            update_todos(&mut result.todos, item);
+                        } else if opts.return_output_images
+                            && matches!(item_type, "image" | "file_output")
+                            && line_data.get("type").and_then(Value::as_str)
+                                == Some("item.completed")
+                        {
+                            record_output_image(&mut result, &opts.working_dir, item_type, item);
+                        }
+                    }
+                }
+
+                // Check for errors
+                if let Some(line_type) = line_data.get("type").and_then(|v| v.as_str()) {
+                    if opts.success_event_types.iter().any(|t| t == line_type) {
+                        completion_seen = true;
+                    }
+                    if line_type.contains("fail") || line_type.contains("error") {
+                        // Always mark as failure when we encounter error/fail events
+                        result.success = false;
+                        let msg = match line_data.get("error").and_then(|v| v.as_object()) {
+                            Some(error_obj) => error_obj.get("message").and_then(|v| v.as_str()),
+                            None => line_data.get("message").and_then(|v| v.as_str()),
+                        };
+                        if let Some(msg) = msg {
+                            let message = format!("codex error: {}", msg);
+                            result.errors.push(message.clone());
+                            result.error = Some(message);
+                        }
+                        if let Some(secs) = parse_retry_after(&line_data) {
+                            result.retry_after_secs = Some(secs);
+                        }
+                    }
+                }
+
+                // Codex would wait forever for an answer on stdin, so fail now instead of timing out
+                if is_approval_request(&line_data) {
+                    result.success = false;
+                    result.errors.push(APPROVAL_UNAVAILABLE_ERROR.to_string());
+                    result.error = Some(APPROVAL_UNAVAILABLE_ERROR.to_string());
+                    stopped_by_server |= stop_child(&mut child);
+                    break;
+                }
+
+                if opts.return_on_first_message && first_message_seen {
+                    result.interrupted = true;
+                    stopped_by_server |= stop_child(&mut child);
+                    break;
+                }
+
+                if let Some(sequence) = matched_stop_sequence {
+                    result.interrupted = true;
+                    let warning = format!(
+                        "Codex was stopped because an agent message contained the stop sequence '{}'",
+                        sequence
+                    );
+                    result.warnings = push_warning(result.warnings.take(), &warning);
+                    stopped_by_server |= stop_child(&mut child);
+                    break;
+                }
+            }
+            Err(e) => {
+                // Create a simple IO error for the parse error
+                let io_error = std::io::Error::from(e.kind());
+                record_parse_error(&mut result, &serde_json::Error::io(io_error), "");
+                break;
+            }
+        }
+    }
+
+    result.final_answer = marked_final_answer.or(last_agent_message);
+    if thread_ids.len() > 1 {
+        let warning = format!(
+            "codex reported several thread_ids in one run ({}); using the last one as SESSION_ID",
+            thread_ids.join(", ")
+        );
+        result.warnings = push_warning(result.warnings.take(), &warning);
+    }
+    if let Some(tail) = agent_tail {
+        result.agent_messages_truncated = tail.truncated;
+        result.agent_messages = tail.finish(opts.agent_message_join.separator());
+    }
+    if opts.return_all_messages {
+        result.message_count = Some(messages_seen);
+    }
+
+    // Wait for process to finish; a replay has no process and counts as a clean exit
+    let (status, resource_usage) = match child {
+        Some(child) => wait_for_exit(child, opts.capture_resource_usage).await?,
+        None => (std::process::ExitStatus::default(), None),
+    };
+    result.resource_usage = resource_usage;
+    result.exit_code = status.code();
+
+    // Collect stderr output; read failures become warnings so the response shows stderr may be
+    // incomplete
+    let stderr_output = match stderr_handle.await {
+        Ok(capture) => {
+            if let Some(read_error) = capture.read_error {
+                result.warnings = push_warning(
+                    result.warnings.take(),
+                    &format!(
+                        "Failed to read codex stderr: {}; stderr output may be incomplete",
+                        read_error
+                    ),
+                );
+            }
+            capture.output
+        }
+        Err(e) => {
+            result.warnings = push_warning(
+                result.warnings.take(),
+                &format!("Failed to collect codex stderr: {}", e),
+            );
+            String::new()
+        }
+    };
+
+    // A child killed for return_on_first_message exits by signal, which is not a failure
+    if !status.success() && !result.interrupted {
+        result.success = false;
+        let exit_msg = describe_exit_failure(&status);
+        if !(stopped_by_server && exited_by_signal(&status)) {
+            result.errors.push(exit_msg.clone());
+        }
+        let error_msg = if let Some(ref err) = result.error {
+            err.clone()
+        } else {
+            exit_msg
+        };
+
+        // Append stderr diagnostics if available
+        if !stderr_output.is_empty() {
+            result.errors.push(format!("Stderr: {}", stderr_output));
+            result.error = Some(format!("{}\nStderr: {}", error_msg, stderr_output));
+        } else {
+            result.error = Some(error_msg);
+        }
+    } else if !stderr_output.is_empty() {
+        // On success, put stderr in warnings field instead of error
+        result.warnings = push_warning(result.warnings.take(), &stderr_output);
+    }
+
+    if result.success && !opts.success_event_types.is_empty() && !completion_seen {
+        let warning = format!(
+            "codex exited cleanly but no completion event was observed (expected one of: {})",
+            opts.success_event_types.join(", ")
+        );
+        result.warnings = push_warning(result.warnings.take(), &warning);
+    }
+
+    let mut result = enforce_required_fields(result, ValidationMode::Full);
+    if let Some(cmd) = opts.post_process_cmd.as_deref() {
+        if result.success && !result.agent_messages.is_empty() {
+            match post_process_output(cmd, &opts.working_dir, &result.agent_messages).await {
+                Ok(processed) => result.agent_messages = processed,
+                Err(warning) => result.warnings = push_warning(result.warnings.take(), &warning),
+            }
+        }
+    }
+    if opts.plain_text_output {
+        result.agent_messages_plain = Some(markdown_to_plain_text(&result.agent_messages));
+    }
+
+    Ok(result)
+}
+
+/// Stderr collected from the codex process
+#[derive(Debug, Default)]
+struct StderrCapture {
+    output: String,
+    /// Set when reading stopped on an I/O error rather than at EOF
+    read_error: Option<String>,
+}
+
+/// Read stderr to EOF, keeping at most MAX_STDERR_SIZE bytes but draining the rest so the child
+/// never blocks on a full pipe
+async fn drain_stderr<R: tokio::io::AsyncRead + Unpin>(
+    stderr: R,
+    max_line_length: usize,
+) -> StderrCapture {
+    const MAX_STDERR_SIZE: usize = 1024 * 1024; // 1MB limit for stderr
+    let mut capture = StderrCapture::default();
+    let stderr_output = &mut capture.output;
+    let mut stderr_reader = BufReader::new(stderr);
+    let mut truncated = false;
+    let mut line_buf = Vec::new();
+
+    loop {
+        line_buf.clear();
+        match read_line_with_limit(&mut stderr_reader, &mut line_buf, max_line_length).await {
+            Ok(read_result) => {
+                if read_result.bytes_read == 0 {
+                    break; // EOF
+                }
+                // Convert to string, handling invalid UTF-8
+                let line = String::from_utf8_lossy(&line_buf);
+                let line = line.trim_end_matches('\n').trim_end_matches('\r');
+
+                // Check if adding this line would exceed the limit
+                let new_size = stderr_output.len() + line.len() + 1; // +1 for newline
+                if new_size > MAX_STDERR_SIZE {
+                    if !truncated {
+                        if !stderr_output.is_empty() {
+                            stderr_output.push('\n');
                         }
+                        stderr_output.push_str("[... stderr truncated due to size limit ...]");
+                        truncated = true;
                     }
+                    // Continue draining to prevent blocking the child process
+                } else if !truncated {
+                    if !stderr_output.is_empty() {
+                        stderr_output.push('\n');
+                    }
+                    stderr_output.push_str(line.as_ref());
+                }
+            }
+            Err(e) => {
+                // Keep what was read so far and report the failure alongside it
+                capture.read_error = Some(e.to_string());
+                break;
+            }
+        }
+    }
+
+    capture
+}
+
+/// Wait for the child to exit, also collecting its resource usage when requested and supported
+async fn wait_for_exit(
+    mut child: tokio::process::Child,
+    capture_resource_usage: bool,
+) -> Result<(std::process::ExitStatus, Option<ResourceUsage>)> {
+    #[cfg(unix)]
+    {
+        if let Some(pid) = child.id().filter(|_| capture_resource_usage) {
+            return wait_with_rusage(child, pid).await;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = capture_resource_usage;
+
+    let status = child
+        .wait()
+        .await
+        .context("Failed to wait for codex command")?;
+    Ok((status, None))
+}
+
+/// Wait for the child with waitid(WNOWAIT), which leaves it unreaped, then reap it with wait4 to
+/// read its rusage. The blocking wait runs off the async runtime; if the run is cancelled
+/// meanwhile, dropping `child` still kills the process, which has not been reaped yet.
+#[cfg(unix)]
+async fn wait_with_rusage(
+    child: tokio::process::Child,
+    pid: u32,
+) -> Result<(std::process::ExitStatus, Option<ResourceUsage>)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    tokio::task::spawn_blocking(move || loop {
+        // SAFETY: siginfo_t is a plain C struct for which all-zero bytes is a valid value
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        // SAFETY: the out-pointer refers to a live, writable local
+        let ret = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if ret != -1 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    })
+    .await
+    .context("waitid task failed")?
+    .context("Failed to wait for codex command")?;
+
+    // The child has exited but is not reaped, so the handle still owns the pid. Reap it and
+    // forget the handle with no await in between, so tokio never re-waits or kills (via
+    // kill_on_drop) a pid that the OS may already have handed to another process.
+    let mut raw_status: libc::c_int = 0;
+    // SAFETY: rusage is a plain C struct for which all-zero bytes is a valid value
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: both out-pointers refer to live, writable locals
+    let ret = unsafe {
+        libc::wait4(
+            pid as libc::pid_t,
+            &mut raw_status,
+            libc::WNOHANG,
+            &mut usage,
+        )
+    };
+    if ret != pid as libc::pid_t {
+        let err = std::io::Error::last_os_error();
+        return Err(err).context("Failed to reap codex command");
+    }
+    std::mem::forget(child);
+
+    Ok((
+        std::process::ExitStatus::from_raw(raw_status),
+        Some(ResourceUsage::from_rusage(&usage)),
+    ))
+}
+
+/// Feed `input` to `cmd` on stdin and return its stdout.
+/// Returns a warning instead when the command cannot run, fails, or prints non-UTF-8 output,
+/// in which case the caller keeps the original text.
+async fn post_process_output(
+    cmd: &[String],
+    working_dir: &Path,
+    input: &str,
+) -> std::result::Result<String, String> {
+    use tokio::io::AsyncWriteExt;
+
+    let Some((program, args)) = cmd.split_first() else {
+        return Err("post_process_cmd is empty; agent_messages left unchanged".to_string());
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .current_dir(working_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("post_process_cmd '{}' could not be started: {}", program, e))?;
+
+    // Write from a separate task so a command that streams output cannot deadlock on a full pipe
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.as_bytes().to_vec();
+    let writer = tokio::spawn(async move {
+        let _ = stdin.write_all(&input).await;
+    });
+
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| format!("post_process_cmd '{}' failed: {}", program, e))?;
+    let _ = writer.await;
+
+    if !output.status.success() {
+        return Err(format!(
+            "post_process_cmd '{}' exited with {:?}; agent_messages left unchanged. Stderr: {}",
+            program,
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8(output.stdout).map_err(|_| {
+        format!(
+            "post_process_cmd '{}' produced invalid UTF-8; agent_messages left unchanged",
+            program
+        )
+    })
+}
+
+/// Maximum bytes of verify_cmd output kept in Verification::output
+pub const MAX_VERIFY_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// Run `cmd` in `working_dir` with stdin closed and report whether it exited with status 0.
+/// The command is killed if it is still running at the run's deadline
+async fn run_verify_cmd(
+    cmd: &[String],
+    working_dir: &Path,
+    deadline: tokio::time::Instant,
+) -> Verification {
+    let failed = |output: String| Verification {
+        passed: false,
+        exit_code: None,
+        output,
+    };
+    let Some((program, args)) = cmd.split_first() else {
+        return failed("verify_cmd is empty".to_string());
+    };
+    let child = Command::new(program)
+        .args(args)
+        .current_dir(working_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            return failed(format!(
+                "verify_cmd '{}' could not be started: {}",
+                program, e
+            ))
+        }
+    };
+
+    let output = match tokio::time::timeout_at(deadline, child.wait_with_output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => return failed(format!("verify_cmd '{}' failed: {}", program, e)),
+        Err(_) => {
+            return failed(format!(
+                "verify_cmd '{}' was still running when the run's timeout expired",
+                program
+            ))
+        }
+    };
+
+    let mut combined = output.stdout;
+    combined.extend_from_slice(&output.stderr);
+    let start = combined.len().saturating_sub(MAX_VERIFY_OUTPUT_BYTES);
+    Verification {
+        passed: output.status.success(),
+        exit_code: output.status.code(),
+        output: String::from_utf8_lossy(&combined[start..]).into_owned(),
+    }
+}
+
+/// Timeout for `codex --version` when probing the installation
+const VERSION_TIMEOUT_SECS: u64 = 15;
+
+/// Run `codex --version` to confirm the binary can be started, returning the first line it printed
+pub async fn codex_version() -> Result<String> {
+    let codex_bin = std::env::var("CODEX_BIN").unwrap_or_else(|_| "codex".to_string());
+    let mut cmd = codex_command();
+    cmd.arg("--version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+
+    let output = tokio::time::timeout(
+        std::time::Duration::from_secs(VERSION_TIMEOUT_SECS),
+        cmd.output(),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("'{} --version' timed out", codex_bin))?
+    .with_context(|| format!("Failed to start codex binary '{}'", codex_bin))?;
+    if !output.status.success() {
+        anyhow::bail!("'{} --version' exited with {}", codex_bin, output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string())
+}
+
+/// A codex release number, as printed by `codex --version`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CodexVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+/// Oldest codex release known to support every flag this server passes (`exec --json`,
+/// `exec resume`, `--skip-git-repo-check`, and `-c` config overrides)
+pub const MIN_CODEX_VERSION: CodexVersion = CodexVersion {
+    major: 0,
+    minor: 44,
+    patch: 0,
+};
+
+impl CodexVersion {
+    /// Find the first `major.minor[.patch]` number in `codex --version` output such as
+    /// `codex-cli 0.46.0`. Pre-release suffixes (`0.47.0-alpha.1`) are ignored
+    pub fn parse(text: &str) -> Option<Self> {
+        text.split_whitespace().find_map(|token| {
+            let mut parts = token.trim_start_matches('v').split('.');
+            let major = parts.next()?.parse().ok()?;
+            let minor = parts.next()?.parse().ok()?;
+            let patch = match parts.next() {
+                Some(part) => {
+                    let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+                    digits.parse().ok()?
                 }
+                None => 0,
+            };
+            Some(Self {
+                major,
+                minor,
+                patch,
+            })
+        })
+    }
+}
+
+impl std::fmt::Display for CodexVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Warning for a codex older than MIN_CODEX_VERSION, or None when it is new enough
+pub fn version_compatibility_warning(version: CodexVersion) -> Option<String> {
+    (version < MIN_CODEX_VERSION).then(|| {
+        format!(
+            "codex {} is older than {}, the oldest version known to support the flags this server uses; some options may fail. Upgrade codex.",
+            version, MIN_CODEX_VERSION
+        )
+    })
+}
+
+/// A model configured for codex
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, schemars::JsonSchema)]
+pub struct ModelInfo {
+    pub name: String,
+    pub provider: Option<String>,
+    /// Whether codex uses this model when none is requested
+    pub default: bool,
+}
+
+/// List the models configured in `$CODEX_HOME/config.toml` (default `~/.codex/config.toml`).
+/// The codex CLI has no command that reports its models, so this reads the config instead:
+/// the top-level `model` is the default, and each `[profiles.<name>]` adds its own `model`.
+/// Returns an empty list when the file is missing or cannot be parsed.
+pub fn list_models() -> Vec<ModelInfo> {
+    crate::session::codex_home()
+        .0
+        .and_then(|home| std::fs::read_to_string(home.join("config.toml")).ok())
+        .map(|text| parse_model_list(&text))
+        .unwrap_or_default()
+}
+
+/// Collect the models named in a codex config.toml, default first, without duplicates.
+/// A model's provider comes from the `model_provider` next to it, falling back to the
+/// top-level one.
+pub fn parse_model_list(text: &str) -> Vec<ModelInfo> {
+    let Ok(config) = text.parse::<toml::Table>() else {
+        return Vec::new();
+    };
+    let field = |table: &toml::Table, key: &str| {
+        table
+            .get(key)
+            .and_then(toml::Value::as_str)
+            .map(str::to_string)
+    };
+    let default_provider = field(&config, "model_provider");
+    let profiles = config
+        .get("profiles")
+        .and_then(toml::Value::as_table)
+        .into_iter()
+        .flat_map(|profiles| profiles.values().filter_map(toml::Value::as_table));
+
+    let mut models: Vec<ModelInfo> = Vec::new();
+    for (table, default) in std::iter::once((&config, true)).chain(profiles.map(|t| (t, false))) {
+        let Some(name) = field(table, "model") else {
+            continue;
+        };
+        if models.iter().any(|model| model.name == name) {
+            continue;
+        }
+        models.push(ModelInfo {
+            name,
+            provider: field(table, "model_provider").or_else(|| default_provider.clone()),
+            default,
+        });
+    }
+    models
+}
 
-                // Check for errors
-                if let Some(line_type) = line_data.get("type").and_then(|v| v.as_str()) {
-                    if line_type.contains("fail") || line_type.contains("error") {
-                        // Always mark as failure when we encounter error/fail events
-                        result.success = false;
-                        if let Some(error_obj) = line_data.get("error").and_then(|v| v.as_object())
-                        {
-                            if let Some(msg) = error_obj.get("message").and_then(|v| v.as_str()) {
-                                result.error = Some(format!("codex error: {}", msg));
-                            }
-                        } else if let Some(msg) = line_data.get("message").and_then(|v| v.as_str())
-                        {
-                            result.error = Some(format!("codex error: {}", msg));
-                        }
-                    }
-                }
+/// Best-effort extraction of numbered-list steps ("1. foo", "2) bar") from agent output.
+/// Lines that are not numbered list items are ignored.
+pub fn parse_plan_steps(text: &str) -> Vec<String> {
+    text.lines()
+        .filter_map(|line| {
+            let trimmed = line.trim_start();
+            let digits = trimmed.bytes().take_while(u8::is_ascii_digit).count();
+            if digits == 0 {
+                return None;
             }
-            Err(e) => {
-                // Create a simple IO error for the parse error
-                let io_error = std::io::Error::from(e.kind());
-                record_parse_error(&mut result, &serde_json::Error::io(io_error), "");
-                break;
+            let rest = trimmed[digits..].strip_prefix(['.', ')'])?;
+            if !rest.starts_with(char::is_whitespace) {
+                return None;
             }
-        }
-    }
+            let step = rest.trim();
+            (!step.is_empty()).then(|| step.to_string())
+        })
+        .collect()
+}
 
-    // Wait for process to finish
-    let status = child
-        .wait()
-        .await
-        .context("Failed to wait for codex command")?;
+/// Whether the process was terminated by a signal rather than exiting with a code
+fn exited_by_signal(status: &std::process::ExitStatus) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal().is_some()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        false
+    }
+}
 
-    // Collect stderr output with better error handling
-    let stderr_output = match stderr_handle.await {
-        Ok(output) => output,
-        Err(e) => {
-            // Log the join error but continue processing
-            eprintln!("Warning: Failed to join stderr task: {}", e);
-            String::new()
+/// Describe a failed exit status, distinguishing termination by signal (e.g. the OOM killer's
+/// SIGKILL) from a regular nonzero exit code
+fn describe_exit_failure(status: &std::process::ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            let hint = if signal == 9 {
+                " (SIGKILL; possibly killed by the OOM killer)"
+            } else {
+                ""
+            };
+            return format!("codex terminated by signal {}{}", signal, hint);
         }
-    };
-
-    if !status.success() {
-        result.success = false;
-        let error_msg = if let Some(ref err) = result.error {
-            err.clone()
-        } else {
-            format!("codex command failed with exit code: {:?}", status.code())
-        };
+    }
+    format!("codex command failed with exit code: {:?}", status.code())
+}
 
-        // Append stderr diagnostics if available
-        if !stderr_output.is_empty() {
-            result.error = Some(format!("{}\nStderr: {}", error_msg, stderr_output));
-        } else {
-            result.error = Some(error_msg);
+/// Deepest array/object nesting accepted in a codex output line. serde_json has its own recursion
+/// limit, but checking up front keeps adversarial lines away from the parser entirely
+pub const MAX_JSON_NESTING_DEPTH: usize = 64;
+
+/// Characters of an over-nested line quoted in the error
+const NESTING_ERROR_LINE_PREFIX: usize = 200;
+
+/// Whether `line` nests arrays/objects deeper than `max_depth`. Brackets inside strings are
+/// ignored; the line does not need to be valid JSON
+fn exceeds_nesting_depth(line: &str, max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for byte in line.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
         }
-    } else if !stderr_output.is_empty() {
-        // On success, put stderr in warnings field instead of error
-        result.warnings = Some(stderr_output);
     }
+    false
+}
 
-    Ok(enforce_required_fields(result, ValidationMode::Full))
+fn record_nesting_error(result: &mut CodexResult, line: &str) {
+    let prefix: String = line.chars().take(NESTING_ERROR_LINE_PREFIX).collect();
+    let parse_msg = format!(
+        "JSON parse error: line nests deeper than {} levels. Line: {}...",
+        MAX_JSON_NESTING_DEPTH, prefix
+    );
+    result.success = false;
+    result.errors.push(parse_msg.clone());
+    result.error = match result.error.take() {
+        Some(existing) if !existing.is_empty() => Some(format!("{existing}\n{parse_msg}")),
+        _ => Some(parse_msg),
+    };
 }
 
 fn record_parse_error(result: &mut CodexResult, error: &serde_json::Error, line: &str) {
     let parse_msg = format!("JSON parse error: {}. Line: {}", error, line);
     result.success = false;
+    result.errors.push(parse_msg.clone());
+    result.error = match result.error.take() {
+        Some(existing) if !existing.is_empty() => Some(format!("{existing}\n{parse_msg}")),
+        _ => Some(parse_msg),
+    };
+}
+
+/// Resolve a stdout_encoding label such as "gbk" or "latin1" (WHATWG labels). None means the
+/// default UTF-8 decoding, which honours utf8_mode
+pub fn stdout_encoding_for_label(
+    label: Option<&str>,
+) -> std::result::Result<Option<&'static Encoding>, String> {
+    let Some(label) = label.map(str::trim).filter(|label| !label.is_empty()) else {
+        return Ok(None);
+    };
+    match Encoding::for_label(label.as_bytes()) {
+        Some(encoding) if encoding == encoding_rs::UTF_8 => Ok(None),
+        Some(encoding) => Ok(Some(encoding)),
+        None => Err(format!(
+            "stdout_encoding '{}' is not a known encoding",
+            label
+        )),
+    }
+}
+
+/// Decode one stdout line. Lines in a non-UTF-8 encoding are always decoded lossily
+fn decode_line<'a>(
+    buf: &'a [u8],
+    mode: Utf8Mode,
+    encoding: Option<&'static Encoding>,
+) -> std::result::Result<Cow<'a, str>, Utf8Error> {
+    if let Some(encoding) = encoding {
+        return Ok(encoding.decode_without_bom_handling(buf).0);
+    }
+    match mode {
+        Utf8Mode::Lossy => Ok(String::from_utf8_lossy(buf)),
+        Utf8Mode::Strict => std::str::from_utf8(buf).map(Cow::Borrowed),
+    }
+}
+
+/// Warn that a complete stdout line held invalid UTF-8 that was replaced with U+FFFD
+fn record_lossy_utf8(result: &mut CodexResult, error: &Utf8Error) {
+    result.warnings = push_warning(
+        result.warnings.take(),
+        &format!(
+            "codex output contained invalid UTF-8 after byte {} of a complete line; invalid bytes were replaced with U+FFFD",
+            error.valid_up_to()
+        ),
+    );
+}
+
+fn record_invalid_utf8(result: &mut CodexResult, error: &Utf8Error, line: &[u8]) {
+    let lossy = String::from_utf8_lossy(line);
+    let parse_msg = format!(
+        "Invalid UTF-8 in codex output: {}. Line: {}",
+        error,
+        lossy.trim_end()
+    );
+    result.success = false;
+    result.errors.push(parse_msg.clone());
     result.error = match result.error.take() {
         Some(existing) if !existing.is_empty() => Some(format!("{existing}\n{parse_msg}")),
         _ => Some(parse_msg),
     };
+    result.warnings = push_warning(
+        result.warnings.take(),
+        &format!(
+            "codex output contained invalid UTF-8 after byte {}; the line was rejected (utf8_mode: strict)",
+            error.valid_up_to()
+        ),
+    );
 }
 
 fn push_warning(existing: Option<String>, warning: &str) -> Option<String> {
@@ -540,8 +2918,10 @@ fn enforce_required_fields(mut result: CodexResult, mode: ValidationMode) -> Cod
     // Skip session_id check if there's already an error (e.g., truncation, I/O error)
     // to avoid masking the original error
     if result.session_id.is_empty() && result.error.is_none() {
+        let message = "Failed to get SESSION_ID from the codex session.".to_string();
         result.success = false;
-        result.error = Some("Failed to get SESSION_ID from the codex session.".to_string());
+        result.errors.push(message.clone());
+        result.error = Some(message);
     }
 
     if result.agent_messages.is_empty() {
@@ -562,16 +2942,8 @@ mod tests {
         let opts = Options {
             prompt: "test prompt".to_string(),
             working_dir: PathBuf::from("/tmp"),
-            sandbox: SandboxPolicy::ReadOnly,
-            session_id: None,
             skip_git_repo_check: true,
-            return_all_messages: false,
-            return_all_messages_limit: None,
-            image_paths: vec![],
-            model: None,
-            yolo: false,
-            profile: None,
-            timeout_secs: None,
+            ..Default::default()
         };
 
         assert_eq!(opts.prompt, "test prompt");
@@ -587,14 +2959,13 @@ mod tests {
             working_dir: PathBuf::from("/tmp"),
             sandbox: SandboxPolicy::WorkspaceWrite,
             session_id: Some("test-session-123".to_string()),
-            skip_git_repo_check: false,
             return_all_messages: true,
             return_all_messages_limit: Some(5000),
             image_paths: vec![PathBuf::from("/path/to/image.png")],
             model: Some("claude-3-opus".to_string()),
-            yolo: false,
             profile: Some("default".to_string()),
             timeout_secs: Some(600),
+            ..Default::default()
         };
 
         assert_eq!(opts.session_id, Some("test-session-123".to_string()));
@@ -615,6 +2986,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sandbox_policy_describe_is_distinct() {
+        let descriptions: Vec<&str> = SandboxPolicy::ALL.iter().map(|p| p.describe()).collect();
+        for (i, description) in descriptions.iter().enumerate() {
+            assert!(!description.is_empty());
+            assert!(!descriptions[i + 1..].contains(description));
+        }
+    }
+
     #[test]
     fn test_sandbox_policy_default() {
         assert_eq!(SandboxPolicy::default(), SandboxPolicy::ReadOnly);
@@ -626,11 +3006,9 @@ mod tests {
             success: true,
             session_id: "session".to_string(),
             agent_messages: "ok".to_string(),
-            agent_messages_truncated: false,
-            all_messages: Vec::new(),
-            all_messages_truncated: false,
             error: Some("existing".to_string()),
-            warnings: None,
+            attempts: 1,
+            ..Default::default()
         };
 
         let err = serde_json::from_str::<Value>("not-json").unwrap_err();
@@ -646,12 +3024,9 @@ mod tests {
         let result = CodexResult {
             success: true,
             session_id: "session".to_string(),
-            agent_messages: String::new(),
-            agent_messages_truncated: false,
             all_messages: vec![HashMap::new()],
-            all_messages_truncated: false,
-            error: None,
-            warnings: None,
+            attempts: 1,
+            ..Default::default()
         };
 
         let updated = enforce_required_fields(result, ValidationMode::Full);
@@ -668,13 +3043,9 @@ mod tests {
     fn test_enforce_required_fields_requires_session_id() {
         let result = CodexResult {
             success: true,
-            session_id: String::new(),
             agent_messages: "msg".to_string(),
-            agent_messages_truncated: false,
-            all_messages: Vec::new(),
-            all_messages_truncated: false,
-            error: None,
-            warnings: None,
+            attempts: 1,
+            ..Default::default()
         };
 
         let updated = enforce_required_fields(result, ValidationMode::Full);
@@ -699,14 +3070,9 @@ mod tests {
     fn test_enforce_required_fields_skips_validation_when_requested() {
         // Simulate a timeout result with empty session_id and agent_messages
         let result = CodexResult {
-            success: false,
-            session_id: String::new(),
-            agent_messages: String::new(),
-            agent_messages_truncated: false,
-            all_messages: Vec::new(),
-            all_messages_truncated: false,
             error: Some("Codex execution timed out after 10 seconds".to_string()),
-            warnings: None,
+            attempts: 1,
+            ..Default::default()
         };
 
         let updated = enforce_required_fields(result, ValidationMode::Skip);
@@ -727,17 +3093,12 @@ mod tests {
     fn test_enforce_required_fields_skips_session_id_when_error_exists() {
         // Simulate a truncation error with empty session_id
         let result = CodexResult {
-            success: false,
-            session_id: String::new(),
-            agent_messages: String::new(),
-            agent_messages_truncated: false,
-            all_messages: Vec::new(),
-            all_messages_truncated: false,
             error: Some(
                 "Output line exceeded 1048576 byte limit and was truncated, cannot parse JSON."
                     .to_string(),
             ),
-            warnings: None,
+            attempts: 1,
+            ..Default::default()
         };
 
         let updated = enforce_required_fields(result, ValidationMode::Full);
@@ -754,4 +3115,544 @@ mod tests {
         assert!(updated.warnings.is_some());
         assert!(updated.warnings.unwrap().contains("No agent_messages"));
     }
+
+    #[test]
+    fn test_parse_plan_steps_numbered_list() {
+        let text = "Here is the plan:\n1. Read the code\n2) Write tests\n  3. Refactor\nDone.";
+        assert_eq!(
+            parse_plan_steps(text),
+            vec!["Read the code", "Write tests", "Refactor"]
+        );
+    }
+
+    #[test]
+    fn test_parse_plan_steps_ignores_non_list_lines() {
+        assert!(parse_plan_steps("no steps here\n2024 was a year\n1.5 is a number").is_empty());
+    }
+
+    fn command_args(opts: &Options) -> Vec<String> {
+        command_args_with_agents(opts, None)
+    }
+
+    fn command_args_with_agents(opts: &Options, agents_md: Option<&str>) -> Vec<String> {
+        build_command(opts, agents_md)
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn test_options() -> Options {
+        Options::builder("test", "/tmp").build()
+    }
+
+    #[test]
+    fn test_build_command_forwards_color_never() {
+        let mut opts = test_options();
+        opts.color = Some(ColorMode::Never);
+
+        let args = command_args(&opts);
+        assert!(args.windows(2).any(|pair| pair == ["--color", "never"]));
+
+        opts.color = Some(ColorMode::Auto);
+        assert!(!command_args(&opts).contains(&"--color".to_string()));
+        opts.color = None;
+        assert!(!command_args(&opts).contains(&"--color".to_string()));
+    }
+
+    #[test]
+    fn test_can_write_covers_yolo_and_full_auto() {
+        assert!(!can_write(&SandboxPolicy::ReadOnly, false, false));
+        assert!(can_write(&SandboxPolicy::WorkspaceWrite, false, false));
+        assert!(can_write(&SandboxPolicy::ReadOnly, true, false));
+        assert!(can_write(&SandboxPolicy::ReadOnly, false, true));
+    }
+
+    #[test]
+    fn test_needs_dir_lock_for_full_auto_but_not_replays() {
+        let mut opts = test_options();
+        opts.sandbox = SandboxPolicy::ReadOnly;
+        assert!(!needs_dir_lock(&opts));
+        opts.full_auto = true;
+        assert!(needs_dir_lock(&opts));
+        opts.replay_file = Some(PathBuf::from("run.ndjson"));
+        assert!(!needs_dir_lock(&opts));
+    }
+
+    #[test]
+    fn test_build_command_passes_full_auto_unless_yolo() {
+        let mut opts = test_options();
+        opts.full_auto = true;
+        assert!(command_args(&opts).contains(&"--full-auto".to_string()));
+
+        opts.yolo = true;
+        let args = command_args(&opts);
+        assert!(args.contains(&"--yolo".to_string()));
+        assert!(!args.contains(&"--full-auto".to_string()));
+    }
+
+    #[test]
+    fn test_build_command_passes_oss_flag() {
+        let opts = Options::builder("test", "/tmp")
+            .with_oss(true)
+            .with_model("gpt-oss:20b")
+            .build();
+        let args = command_args(&opts);
+        assert!(args.contains(&"--oss".to_string()));
+        assert!(!command_args(&test_options()).contains(&"--oss".to_string()));
+    }
+
+    #[test]
+    fn test_is_cloud_only_model() {
+        assert!(is_cloud_only_model("gpt-5-codex"));
+        assert!(is_cloud_only_model("O3"));
+        assert!(!is_cloud_only_model("gpt-oss:20b"));
+        assert!(!is_cloud_only_model("qwen2.5-coder:7b"));
+    }
+
+    #[test]
+    fn test_build_command_translates_provider_headers_to_config() {
+        let opts = Options::builder("test", "/tmp")
+            .with_provider_header("X-Org-Id", "acme")
+            .with_provider_header("X-Trace", "a \"quoted\" value")
+            .build();
+
+        let args = command_args(&opts);
+        let overrides: Vec<_> = args
+            .windows(2)
+            .filter(|pair| pair[0] == "--config")
+            .map(|pair| pair[1].as_str())
+            .collect();
+        assert_eq!(
+            overrides,
+            [
+                r#"model_providers.openai.http_headers."X-Org-Id"="acme""#,
+                r#"model_providers.openai.http_headers."X-Trace"="a \"quoted\" value""#,
+            ]
+        );
+        assert!(!command_args(&test_options()).contains(&"--config".to_string()));
+    }
+
+    #[test]
+    fn test_is_valid_header_name() {
+        assert!(is_valid_header_name("X-Org-Id"));
+        assert!(!is_valid_header_name(""));
+        assert!(!is_valid_header_name("X Org"));
+        assert!(!is_valid_header_name("X-Org:"));
+        assert!(!is_valid_header_name("X-Org\n"));
+    }
+
+    #[test]
+    fn test_build_command_prepends_agents_md_by_default() {
+        let mut opts = test_options();
+        opts.prompt = "do the task".to_string();
+
+        let args = command_args_with_agents(&opts, Some("Be terse."));
+        assert!(!args
+            .iter()
+            .any(|arg| arg.starts_with("developer_instructions=")));
+        let prompt = args.last().unwrap();
+        assert!(prompt.contains("Be terse."));
+        assert!(prompt.ends_with("do the task"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_build_command_sends_agents_md_as_system_flag() {
+        let mut opts = test_options();
+        opts.prompt = "do the task".to_string();
+        opts.agents_as_system_flag = true;
+
+        let args = command_args_with_agents(&opts, Some("Be \"terse\".\nAlways."));
+        assert!(args.windows(2).any(|pair| pair[0] == "--config"
+            && pair[1] == r#"developer_instructions="Be \"terse\".\nAlways.""#));
+        assert_eq!(args.last().unwrap(), "do the task");
+    }
+
+    #[tokio::test]
+    async fn test_read_agents_md_strips_bom() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(AGENTS_MD_FILE), "\u{FEFF}  Be terse.\n").unwrap();
+
+        let content = read_agents_md(dir.path(), MAX_AGENTS_MD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(content, "Be terse.");
+    }
+
+    #[tokio::test]
+    async fn test_read_agents_md_honours_raised_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let size = MAX_AGENTS_MD_SIZE as usize + 1024;
+        std::fs::write(dir.path().join(AGENTS_MD_FILE), "x".repeat(size)).unwrap();
+
+        let limit = agents_md_size_limit(None);
+        assert!(read_agents_md(dir.path(), limit)
+            .await
+            .unwrap_err()
+            .reason
+            .contains("exceeding"));
+        assert!(!has_agents_md(dir.path(), limit));
+
+        let limit = agents_md_size_limit(Some(2 * MAX_AGENTS_MD_SIZE));
+        let content = read_agents_md(dir.path(), limit).await.unwrap().unwrap();
+        assert_eq!(content.len(), size);
+        assert!(has_agents_md(dir.path(), limit));
+
+        assert_eq!(
+            agents_md_size_limit(Some(u64::MAX)),
+            MAX_AGENTS_ABSOLUTE_MAX
+        );
+    }
+
+    #[test]
+    fn test_is_transient_failure_requires_marker_and_no_answer() {
+        let mut result = timeout_result(1);
+        result.error = Some("codex error: Rate limit exceeded (429)".to_string());
+        assert!(is_transient_failure(&result));
+
+        result.agent_messages = "partial answer".to_string();
+        assert!(!is_transient_failure(&result));
+
+        result.agent_messages.clear();
+        result.error = Some("codex error: invalid model".to_string());
+        assert!(!is_transient_failure(&result));
+    }
+
+    /// Reader that yields `data` once and then fails, like a pipe that breaks mid-stream
+    struct FailingReader {
+        data: Option<Vec<u8>>,
+    }
+
+    impl tokio::io::AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(match self.data.take() {
+                Some(data) => {
+                    buf.put_slice(&data);
+                    Ok(())
+                }
+                None => Err(std::io::Error::other("simulated stderr failure")),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_stderr_records_read_error() {
+        let reader = FailingReader {
+            data: Some(b"first line\n".to_vec()),
+        };
+        let capture = drain_stderr(reader, 1024).await;
+
+        assert_eq!(capture.output, "first line");
+        assert!(capture
+            .read_error
+            .expect("read error should be recorded")
+            .contains("simulated stderr failure"));
+    }
+
+    #[tokio::test]
+    async fn test_drain_stderr_reads_to_eof() {
+        let capture = drain_stderr(&b"one\ntwo\n"[..], 1024).await;
+        assert_eq!(capture.output, "one\ntwo");
+        assert!(capture.read_error.is_none());
+    }
+
+    #[test]
+    fn test_is_transient_failure_needs_status_code_context() {
+        let mut result = timeout_result(1);
+        for error in [
+            "codex error: syntax error at line 4291",
+            "codex error: request 5025031 rejected",
+            "codex error: file is 504 bytes over the limit",
+            "codex error: http 5030 unknown",
+        ] {
+            result.error = Some(error.to_string());
+            assert!(!is_transient_failure(&result), "{error}");
+        }
+
+        for error in [
+            "codex error: unexpected status 503 Service Unavailable",
+            "codex error: HTTP 502 Bad Gateway",
+            "codex error: upstream returned status_code=429",
+        ] {
+            result.error = Some(error.to_string());
+            assert!(is_transient_failure(&result), "{error}");
+        }
+    }
+
+    #[test]
+    fn test_capture_raw_stdout_stops_at_cap() {
+        let mut result = timeout_result(1);
+        result.raw_stdout = Some("a".repeat(MAX_RAW_STDOUT_BYTES - 1));
+
+        // The two-byte character does not fit, so nothing of it is kept
+        capture_raw_stdout(&mut result, "é\n".as_bytes(), false);
+        assert!(result.raw_stdout_truncated);
+        assert_eq!(
+            result.raw_stdout.as_ref().unwrap().len(),
+            MAX_RAW_STDOUT_BYTES - 1
+        );
+
+        let mut disabled = timeout_result(1);
+        capture_raw_stdout(&mut disabled, b"line\n", true);
+        assert!(disabled.raw_stdout.is_none());
+        assert!(!disabled.raw_stdout_truncated);
+    }
+
+    #[test]
+    fn test_update_todos_replaces_list_from_snapshot() {
+        let item = |json: Value| json.as_object().unwrap().clone();
+        let mut todos = Vec::new();
+        update_todos(
+            &mut todos,
+            &item(
+                serde_json::json!({"type": "plan_update", "id": 1, "text": "old", "status": "pending"}),
+            ),
+        );
+        update_todos(
+            &mut todos,
+            &item(serde_json::json!({"type": "todo_list", "items": [
+                {"text": "Plan", "completed": true},
+                {"text": "Build", "completed": false},
+            ]})),
+        );
+
+        assert_eq!(todos.len(), 2);
+        assert_eq!(
+            (todos[0].text.as_str(), todos[0].status.as_str()),
+            ("Plan", "completed")
+        );
+        assert_eq!(
+            (todos[1].text.as_str(), todos[1].status.as_str()),
+            ("Build", "pending")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_command_wraps_in_login_shell() {
+        let mut opts = test_options();
+        opts.prompt = "it's \"quoted\" $HOME `id`".to_string();
+        opts.use_login_shell = true;
+
+        let cmd = build_command(&opts, None);
+        assert_eq!(cmd.as_std().get_program(), "/bin/sh");
+        let args = command_args(&opts);
+        assert_eq!(args[0], "-lc");
+        assert_eq!(args[1], LOGIN_SHELL_SCRIPT);
+        assert_eq!(args[3], "exec");
+        assert_eq!(args.last().unwrap(), &opts.prompt);
+    }
+
+    #[test]
+    fn test_spawn_jitter_stays_within_bound() {
+        assert_eq!(spawn_jitter(0), std::time::Duration::ZERO);
+        for _ in 0..100 {
+            assert!(spawn_jitter(5) <= std::time::Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_is_approval_request_matches_event_and_item_types() {
+        assert!(is_approval_request(
+            &serde_json::json!({"type": "exec_approval_request", "command": ["rm", "-rf", "x"]})
+        ));
+        assert!(is_approval_request(
+            &serde_json::json!({"type": "item.started", "item": {"type": "apply_patch_approval_request"}})
+        ));
+        assert!(is_approval_request(
+            &serde_json::json!({"id": "1", "msg": {"type": "exec_approval_request"}})
+        ));
+        assert!(!is_approval_request(
+            &serde_json::json!({"type": "item.completed", "item": {"type": "agent_message"}})
+        ));
+    }
+
+    #[test]
+    fn test_markdown_to_plain_text_flattens_fences_and_headings() {
+        let markdown = "# Summary\n\nI updated **two** files:\n\n- `src/lib.rs`\n- [the docs](https://example.com/docs)\n\n```rust\nfn main() {\n    println!(\"*hi*\");\n}\n```\n> Run `cargo test` next";
+        assert_eq!(
+            markdown_to_plain_text(markdown),
+            "Summary\n\nI updated two files:\n\nsrc/lib.rs\nthe docs\n\nfn main() {\n    println!(\"*hi*\");\n}\nRun cargo test next"
+        );
+        assert_eq!(markdown_to_plain_text("a\n***\nb"), "a\nb");
+        assert_eq!(
+            markdown_to_plain_text("snake_case and 2 * 3"),
+            "snake_case and 2 * 3"
+        );
+    }
+
+    #[test]
+    fn test_exceeds_nesting_depth_ignores_brackets_in_strings() {
+        let nested = format!("{}{}", "[".repeat(65), "]".repeat(65));
+        assert!(exceeds_nesting_depth(&nested, 64));
+        assert!(!exceeds_nesting_depth(&nested[1..nested.len() - 1], 64));
+        let in_string = format!(r#"{{"text":"{}\""}}"#, "[".repeat(100));
+        assert!(!exceeds_nesting_depth(&in_string, 64));
+    }
+
+    #[test]
+    fn test_codex_version_parse_and_compare() {
+        let version = CodexVersion::parse("codex-cli 0.46.0").unwrap();
+        assert_eq!(version.to_string(), "0.46.0");
+        assert_eq!(
+            CodexVersion::parse("codex v0.47.0-alpha.1").map(|v| v.to_string()),
+            Some("0.47.0".to_string())
+        );
+        assert_eq!(CodexVersion::parse("codex 1.2").unwrap().patch, 0);
+        assert_eq!(CodexVersion::parse("codex-cli unknown"), None);
+
+        assert!(version_compatibility_warning(version).is_none());
+        assert!(version_compatibility_warning(MIN_CODEX_VERSION).is_none());
+        let old = CodexVersion::parse("codex-cli 0.9.3").unwrap();
+        assert!(version_compatibility_warning(old)
+            .unwrap()
+            .contains("codex 0.9.3 is older than 0.44.0"));
+    }
+
+    #[test]
+    fn test_agent_message_tail_keeps_latest_messages() {
+        let mut tail = AgentMessageTail::new(10);
+        tail.push("alpha");
+        tail.push("beta");
+        tail.push("gamma");
+        assert!(tail.truncated);
+        assert_eq!(
+            tail.finish("\n"),
+            "[... Earlier agent messages truncated due to size limit ...]\nbeta\ngamma"
+        );
+
+        let mut tail = AgentMessageTail::new(10);
+        tail.push("0123456789conclusion");
+        assert_eq!(
+            tail.finish("\n"),
+            "[... Earlier agent messages truncated due to size limit ...]\nconclusion"
+        );
+
+        let mut tail = AgentMessageTail::new(10);
+        tail.push("one");
+        tail.push("");
+        tail.push("two");
+        assert!(!tail.truncated);
+        assert_eq!(tail.finish(" "), "one two");
+    }
+
+    #[test]
+    fn test_decode_line_uses_stdout_encoding() {
+        let gbk = stdout_encoding_for_label(Some("gbk")).unwrap();
+        assert!(gbk.is_some());
+        assert_eq!(
+            decode_line(b"\xc4\xe3\xba\xc3\n", Utf8Mode::Strict, gbk).unwrap(),
+            "你好\n"
+        );
+        let latin1 = stdout_encoding_for_label(Some("latin1")).unwrap();
+        assert_eq!(
+            decode_line(b"caf\xe9", Utf8Mode::Lossy, latin1).unwrap(),
+            "café"
+        );
+
+        assert_eq!(stdout_encoding_for_label(Some("UTF-8")), Ok(None));
+        assert_eq!(stdout_encoding_for_label(None), Ok(None));
+        assert_eq!(
+            decode_line(b"caf\xe9", Utf8Mode::Lossy, None).unwrap(),
+            "caf\u{fffd}"
+        );
+        assert!(stdout_encoding_for_label(Some("klingon")).is_err());
+    }
+
+    #[test]
+    fn test_parse_model_list_reads_default_and_profiles() {
+        let config = r#"
+model = "gpt-5-codex"
+model_provider = "openai"
+
+[profiles.fast]
+model = "o4-mini"
+
+[profiles.local]
+model = "llama3"
+model_provider = "ollama"
+
+[profiles.same]
+model = "gpt-5-codex"
+
+[profiles.no_model]
+approval_policy = "never"
+"#;
+        assert_eq!(
+            parse_model_list(config),
+            vec![
+                ModelInfo {
+                    name: "gpt-5-codex".to_string(),
+                    provider: Some("openai".to_string()),
+                    default: true,
+                },
+                ModelInfo {
+                    name: "o4-mini".to_string(),
+                    provider: Some("openai".to_string()),
+                    default: false,
+                },
+                ModelInfo {
+                    name: "llama3".to_string(),
+                    provider: Some("ollama".to_string()),
+                    default: false,
+                },
+            ]
+        );
+
+        assert!(parse_model_list("").is_empty());
+        assert!(parse_model_list("model = ").is_empty());
+    }
+
+    #[test]
+    fn test_retry_backoff_doubles_and_caps() {
+        assert_eq!(retry_backoff(0).as_millis(), 1000);
+        assert_eq!(retry_backoff(1).as_millis(), 2000);
+        assert_eq!(retry_backoff(2).as_millis(), 4000);
+        assert_eq!(retry_backoff(40).as_millis(), MAX_RETRY_BACKOFF_MS as u128);
+    }
+
+    #[test]
+    fn test_parse_retry_after_hints() {
+        let event = serde_json::json!({"type": "error", "error": {"message": "rate limited", "retry_after": 12}});
+        assert_eq!(parse_retry_after(&event), Some(12));
+
+        let event = serde_json::json!({"type": "turn.failed", "retry_after": "2.5"});
+        assert_eq!(parse_retry_after(&event), Some(3));
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let event = serde_json::json!({"type": "error", "error": {"reset_at": now + 60}});
+        let secs = parse_retry_after(&event).expect("reset_at should be parsed");
+        assert!((58..=60).contains(&secs), "unexpected delay: {}", secs);
+
+        let event = serde_json::json!({"type": "error", "error": {"reset_at": now - 60}});
+        assert_eq!(parse_retry_after(&event), Some(0));
+
+        let event = serde_json::json!({"type": "error", "message": "boom"});
+        assert_eq!(parse_retry_after(&event), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_describe_exit_failure_reports_signal() {
+        use std::os::unix::process::ExitStatusExt;
+
+        // Raw wait status for termination by SIGKILL
+        let status = std::process::ExitStatus::from_raw(9);
+        let message = describe_exit_failure(&status);
+        assert!(message.contains("terminated by signal 9"));
+        assert!(message.contains("OOM"));
+
+        // Raw wait status for a normal exit with code 2
+        let status = std::process::ExitStatus::from_raw(2 << 8);
+        assert!(describe_exit_failure(&status).contains("exit code: Some(2)"));
+    }
 }
@@ -0,0 +1,168 @@
+use crate::temp_dir::temp_base_dir;
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default time a writable run waits for another writable run in the same directory to finish
+pub const DEFAULT_DIR_LOCK_WAIT_SECS: u64 = 300;
+
+/// How often a waiting run retries the lock
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lock file for `dir` under `base`, named by a hash of the canonical directory path so
+/// different spellings of the same directory share one lock
+fn lock_path(base: &Path, dir: &Path) -> PathBuf {
+    let canonical = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let digest = Sha256::digest(canonical.to_string_lossy().as_bytes());
+    let name: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    base.join("codex-mcp-locks").join(format!("{}.lock", name))
+}
+
+/// Advisory lock serializing writable codex runs in one working directory.
+/// Released when dropped. On Unix this is an flock on the lock file and on Windows an open
+/// handle that shares nothing, so the OS releases it when a server crashes; on other platforms
+/// the lock file itself is the lock and is removed on drop.
+#[derive(Debug)]
+pub struct DirLock {
+    _file: File,
+    #[cfg_attr(any(unix, windows), allow(dead_code))]
+    path: PathBuf,
+}
+
+impl DirLock {
+    /// Lock `dir`, waiting up to `wait` for another run to release it
+    pub async fn acquire(dir: &Path, wait: Duration) -> Result<Self> {
+        Self::acquire_in(&temp_base_dir(), dir, wait).await
+    }
+
+    /// Lock `dir` using a lock file under `base`
+    pub async fn acquire_in(base: &Path, dir: &Path, wait: Duration) -> Result<Self> {
+        let path = lock_path(base, dir);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create lock directory {}", parent.display()))?;
+        }
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            if let Some(file) = try_lock(&path)? {
+                return Ok(Self { _file: file, path });
+            }
+            if tokio::time::Instant::now() >= deadline {
+                bail!(
+                    "another writable codex run is using {}; gave up after waiting {} seconds",
+                    dir.display(),
+                    wait.as_secs()
+                );
+            }
+            tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Try to take the lock without blocking. Ok(None) means another run holds it
+#[cfg(unix)]
+fn try_lock(path: &Path) -> Result<Option<File>> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+    // SAFETY: the descriptor belongs to `file`, which outlives the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(Some(file));
+    }
+    let err = std::io::Error::last_os_error();
+    if err.kind() == std::io::ErrorKind::WouldBlock {
+        Ok(None)
+    } else {
+        Err(err).with_context(|| format!("Failed to lock {}", path.display()))
+    }
+}
+
+/// Windows error for opening a file another handle has opened without sharing
+#[cfg(windows)]
+const ERROR_SHARING_VIOLATION: i32 = 32;
+
+/// Try to take the lock without blocking. Ok(None) means another run holds it
+#[cfg(windows)]
+fn try_lock(path: &Path) -> Result<Option<File>> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    // Opening without sharing makes the handle itself the lock; Windows closes it when the
+    // process exits, so a crashed server never leaves a stale lock
+    match std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .share_mode(0)
+        .open(path)
+    {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to open lock file {}", path.display())),
+    }
+}
+
+/// Try to take the lock without blocking. Ok(None) means another run holds it
+#[cfg(not(any(unix, windows)))]
+fn try_lock(path: &Path) -> Result<Option<File>> {
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+    {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to create lock file {}", path.display())),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_path_is_shared_by_equivalent_paths() {
+        let base = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let dotted = dir.path().join(".");
+        assert_eq!(
+            lock_path(base.path(), dir.path()),
+            lock_path(base.path(), &dotted)
+        );
+        assert_ne!(
+            lock_path(base.path(), dir.path()),
+            lock_path(base.path(), base.path())
+        );
+    }
+
+    #[tokio::test]
+    async fn second_lock_waits_for_release() {
+        let base = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let first = DirLock::acquire_in(base.path(), dir.path(), Duration::ZERO)
+            .await
+            .unwrap();
+        let err = DirLock::acquire_in(base.path(), dir.path(), Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("another writable codex run"));
+
+        drop(first);
+        DirLock::acquire_in(base.path(), dir.path(), Duration::ZERO)
+            .await
+            .expect("lock should be free after release");
+    }
+}
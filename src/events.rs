@@ -0,0 +1,85 @@
+use crate::codex::{CodexResult, Options};
+use serde_json::Value;
+use std::time::Duration;
+
+/// Outcome of one codex run, passed to EventSink::on_run_end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    pub success: bool,
+    pub session_id: String,
+    pub error: Option<String>,
+    pub exit_code: Option<i32>,
+    pub attempts: u32,
+    pub duration_ms: u64,
+}
+
+impl RunSummary {
+    pub fn from_result(result: &CodexResult, duration: Duration) -> Self {
+        Self {
+            success: result.success,
+            session_id: result.session_id.clone(),
+            error: result.error.clone(),
+            exit_code: result.exit_code,
+            attempts: result.attempts,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+
+    /// Summary of a run that could not be carried out at all, e.g. because codex failed to start
+    pub fn failed(error: String, duration: Duration) -> Self {
+        Self {
+            success: false,
+            session_id: String::new(),
+            error: Some(error),
+            exit_code: None,
+            attempts: 0,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
+
+/// Receives the lifecycle of codex runs, for pushing them to metrics or alerting backends.
+/// Hooks are called inline, so implementations should hand slow work off to another task.
+pub trait EventSink: Send + Sync + std::fmt::Debug {
+    /// Called once before codex is started
+    fn on_run_start(&self, _opts: &Options) {}
+
+    /// Called for every JSON event codex emits, in order
+    fn on_event(&self, _event: &Value) {}
+
+    /// Called once when the run has finished, successfully or not
+    fn on_run_end(&self, _summary: &RunSummary) {}
+}
+
+/// Sink that ignores everything; the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopEventSink;
+
+impl EventSink for NoopEventSink {}
+
+/// Sink that logs each run's start and end to stderr (stdout carries the MCP protocol)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoggingEventSink;
+
+impl EventSink for LoggingEventSink {
+    fn on_run_start(&self, opts: &Options) {
+        eprintln!(
+            "[codex-mcp-rs] run started in {} (sandbox: {})",
+            opts.working_dir.display(),
+            opts.sandbox.as_str()
+        );
+    }
+
+    fn on_run_end(&self, summary: &RunSummary) {
+        match &summary.error {
+            Some(error) if !summary.success => eprintln!(
+                "[codex-mcp-rs] run failed after {}ms (session: {}): {}",
+                summary.duration_ms, summary.session_id, error
+            ),
+            _ => eprintln!(
+                "[codex-mcp-rs] run finished in {}ms (session: {})",
+                summary.duration_ms, summary.session_id
+            ),
+        }
+    }
+}
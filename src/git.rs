@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+
+/// Find the git directory for `dir` or its nearest ancestor: a `.git` directory, or the directory
+/// a `.git` file points at (linked worktrees and submodules)
+fn find_git_dir(dir: &Path) -> Option<PathBuf> {
+    for ancestor in dir.ancestors() {
+        let dot_git = ancestor.join(".git");
+        if dot_git.is_dir() {
+            return Some(dot_git);
+        }
+        if dot_git.is_file() {
+            let content = std::fs::read_to_string(&dot_git).ok()?;
+            let target = PathBuf::from(content.trim().strip_prefix("gitdir:")?.trim());
+            return Some(if target.is_absolute() {
+                target
+            } else {
+                ancestor.join(target)
+            });
+        }
+    }
+    None
+}
+
+/// Whether `dir` or one of its ancestors is a git working tree
+pub fn is_git_repo(dir: &Path) -> bool {
+    find_git_dir(dir).is_some()
+}
+
+/// Top of the working tree containing `dir`: the nearest ancestor (or `dir` itself) holding a
+/// `.git` directory or file
+pub fn repo_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|ancestor| ancestor.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+fn is_commit_id(value: &str) -> bool {
+    matches!(value.len(), 40 | 64) && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Resolve a ref such as `refs/heads/main` from a loose ref file or `packed-refs`
+fn resolve_ref(git_dir: &Path, common_dir: &Path, name: &str) -> Option<String> {
+    for dir in [git_dir, common_dir] {
+        if let Ok(content) = std::fs::read_to_string(dir.join(name)) {
+            let id = content.trim();
+            if is_commit_id(id) {
+                return Some(id.to_string());
+            }
+        }
+    }
+    let packed = std::fs::read_to_string(common_dir.join("packed-refs")).ok()?;
+    packed.lines().find_map(|line| {
+        let (id, ref_name) = line.split_once(' ')?;
+        (ref_name.trim() == name && is_commit_id(id)).then(|| id.to_string())
+    })
+}
+
+/// Commit id HEAD points at in the repository containing `dir`, read straight from the git
+/// files without running git. None outside a repository, before the first commit, or when the
+/// files can't be read
+pub fn head_commit(dir: &Path) -> Option<String> {
+    let git_dir = find_git_dir(dir)?;
+    // Linked worktrees keep shared refs in the main repository, named by `commondir`
+    let common_dir = match std::fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim()),
+        Err(_) => git_dir.clone(),
+    };
+    let head = std::fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+    match head.strip_prefix("ref:") {
+        Some(name) => resolve_ref(&git_dir, &common_dir, name.trim()),
+        None => is_commit_id(head).then(|| head.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn fake_repo(head: &str) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join(".git/refs/heads")).unwrap();
+        std::fs::write(dir.path().join(".git/HEAD"), head).unwrap();
+        dir
+    }
+
+    #[test]
+    fn head_commit_follows_loose_and_packed_refs() {
+        let repo = fake_repo("ref: refs/heads/main\n");
+        assert_eq!(head_commit(repo.path()), None);
+
+        std::fs::write(
+            repo.path().join(".git/packed-refs"),
+            format!("# pack-refs with: peeled\n{} refs/heads/main\n", COMMIT),
+        )
+        .unwrap();
+        assert_eq!(head_commit(repo.path()).as_deref(), Some(COMMIT));
+
+        let loose = "fedcba9876543210fedcba9876543210fedcba98";
+        std::fs::write(repo.path().join(".git/refs/heads/main"), loose).unwrap();
+        let subdir = repo.path().join("src");
+        std::fs::create_dir(&subdir).unwrap();
+        assert_eq!(head_commit(&subdir).as_deref(), Some(loose));
+    }
+
+    #[test]
+    fn is_git_repo_checks_ancestors() {
+        let repo = fake_repo("ref: refs/heads/main\n");
+        let subdir = repo.path().join("nested/dir");
+        std::fs::create_dir_all(&subdir).unwrap();
+        assert!(is_git_repo(&subdir));
+        assert_eq!(repo_root(&subdir).as_deref(), Some(repo.path()));
+
+        let plain = tempfile::tempdir().unwrap();
+        assert!(!is_git_repo(plain.path()));
+        assert_eq!(repo_root(plain.path()), None);
+    }
+
+    #[test]
+    fn head_commit_reads_detached_head() {
+        let repo = fake_repo(&format!("{}\n", COMMIT));
+        assert_eq!(head_commit(repo.path()).as_deref(), Some(COMMIT));
+    }
+}
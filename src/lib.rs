@@ -1,2 +1,11 @@
+pub mod client;
 pub mod codex;
+pub mod dir_lock;
+pub mod events;
+pub mod git;
 pub mod server;
+pub mod session;
+pub mod temp_dir;
+
+pub use client::CodexClient;
+pub use codex::{CodexResult, SandboxPolicy};
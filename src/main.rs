@@ -1,6 +1,7 @@
 use anyhow::Result;
 use clap::Parser;
-use codex_mcp_rs::server::CodexServer;
+use codex_mcp_rs::codex;
+use codex_mcp_rs::server::{run_warmup, run_warmup_within, CodexServer};
 use rmcp::{transport::stdio, ServiceExt};
 
 /// MCP server wrapping the Codex CLI for AI-assisted coding tasks
@@ -23,8 +24,62 @@ use rmcp::{transport::stdio, ServiceExt};
   CODEX_ALLOW_SKIP_GIT_CHECK   Allow running outside git repositories (default: false)
                                Accepts: 1/true/yes/y/on/t/enable/enabled or
                                0/false/no/n/off/f/disable/disabled
+  CODEX_ALLOW_LONG_TIMEOUT     Allow timeout_secs above 3600, up to 86400 (default: false).
+                               A valid capability_token also allows it for its request
+  CODEX_TEMP_DIR               Base directory for per-call temporary files
+                               (default: the system temp directory)
+  CODEX_BLOCK_DIRTY_TREE       Refuse writable sandbox runs in git repos with uncommitted
+                               changes instead of only warning (default: false)
+  CODEX_DEFAULT_SANDBOX        Sandbox used when a call does not set one: read-only
+                               (default), workspace-write, or danger-full-access
+                               (still subject to CODEX_ALLOW_DANGEROUS)
+  CODEX_MAX_IMAGES             Maximum images attached to one request (default: 10)
+  CODEX_MAX_IMAGE_BYTES        Maximum combined size of one request's images in bytes
+                               (default: 52428800)
+  CODEX_STRICT_SECURITY        Reject requests for disallowed danger-full-access, yolo, or
+                               full_auto instead of downgrading them (default: false)
+  CODEX_ALLOW_PROVIDER_HEADERS Allow the provider_headers parameter (default: false)
+  CODEX_STARTUP_PROBE_SECS     Run the startup probe (see --probe) with this timeout in
+                               seconds (default: probe off; 60 when only --probe is given)
+  CODEX_AUTO_SKIP_GIT_CHECK    Pass --skip-git-repo-check automatically when cd is not
+                               in a git repository (default: false)
+  CODEX_ALLOW_VERIFY_CMD       Allow the verify_cmd parameter to run commands on the
+                               server (default: false)
+  CODEX_AGENTS_ABSOLUTE_MAX    Default AGENTS.md size limit in bytes (default: 1048576,
+                               max: 67108864)
+  CODEX_MAX_PROMPT_BYTES       Maximum PROMPT size in bytes (default: 262144)
+  CODEX_ALLOW_POST_PROCESS     Allow the post_process_cmd parameter to run commands on
+                               the server (default: false)
+  CODEX_ALLOW_AGENTS_ONLY_PROMPT
+                               Accept an empty PROMPT when the working directory has a
+                               non-empty AGENTS.md, which then becomes the task
+                               (default: false)
+  CODEX_ALLOWED_MODELS         Comma-separated models clients may request (default: any)
+  CODEX_DOWNGRADE_DISALLOWED_MODELS
+                               Replace a model outside CODEX_ALLOWED_MODELS with the first
+                               allowed one instead of rejecting the call (default: false)
+  CODEX_CLIENT_PROFILE_MAP     JSON object mapping MCP client names (from initialize) to the
+                               profile used when a request sets none,
+                               e.g. {\"claude-desktop\": \"fast\"}
+  CODEX_MAX_CONCURRENCY        Maximum number of codex processes run at once; further
+                               calls wait for a slot (default: unlimited)
+  CODEX_SPAWN_JITTER_MS        Wait a random 0..N milliseconds before each codex spawn to
+                               spread out parallel calls (default: 0, max: 10000)
+  CODEX_DIR_LOCK_WAIT_SECS     Seconds a writable run waits while another writable run
+                               uses the same working directory before failing
+                               (default: 300; read-only runs never wait)
+  CODEX_LOG_EVENTS             Log the start and end of every codex run to stderr
+                               (default: false)
+  CODEX_REQUIRE_MIN_VERSION    Refuse to start when the installed codex is older than the
+                               oldest supported version, instead of warning (default: false)
+  CODEX_CAPABILITY_SECRET      Secret for verifying per-request capability_token values
+                               (default: unset, all tokens are rejected)
 
 USAGE:
+  Run with --check to verify the installation: it looks for the codex binary,
+  runs a trivial read-only task in a temporary directory, prints a pass/fail
+  summary to stderr, and exits non-zero on failure.
+
   This server communicates via stdio using the Model Context Protocol (MCP).
   It should be configured in your MCP client (e.g., Claude Desktop) settings.
 
@@ -40,24 +95,145 @@ USAGE:
 SUPPORTED PARAMETERS:
   The 'codex' tool accepts the following parameters:
 
-  PROMPT (required)            Task instruction to send to Codex
+  PROMPT (required)            Task instruction to send to Codex (or use prompt_b64)
   cd (required)                Working directory for the Codex session
-  sandbox                      Sandbox policy: read-only (default), workspace-write,
-                               or danger-full-access
+  sandbox                      Sandbox policy: read-only, workspace-write, or
+                               danger-full-access (default: CODEX_DEFAULT_SANDBOX)
   SESSION_ID                   Resume an existing session (from previous response)
   skip_git_repo_check          Allow running outside git repos (default: false)
   return_all_messages          Return all messages including reasoning (default: false)
   return_all_messages_limit    Max messages to return when enabled (default: 10000)
   image                        Array of image file paths to attach to prompt
-  model                        Model to use (overrides default)
+                               (a leading ~ in cd and image paths expands to the
+                               home directory; ~user is not expanded)
+  model                        Model to use (overrides default; subject to
+                               CODEX_ALLOWED_MODELS). Surrounding whitespace is
+                               trimmed; a blank value uses Codex's default model
   yolo                         Run without approval prompts (default: false)
   profile                      Config profile from ~/.codex/config.toml
-  timeout_secs                 Timeout in seconds (default: CODEX_DEFAULT_TIMEOUT or 600, max: 3600)
+                               (default: CODEX_CLIENT_PROFILE_MAP entry for the client)
+  timeout_secs                 Timeout in seconds (default: CODEX_DEFAULT_TIMEOUT or 600, max: 3600,
+                               or 86400 with CODEX_ALLOW_LONG_TIMEOUT)
+  idle_timeout_secs            Kill codex after this many seconds without output (default: disabled)
+  return_boundary_events       Return the first and last raw JSON events (default: false)
+  compress_output              Return all_messages as all_messages_gzip_b64 instead of inline:
+                               base64 (standard, padded) of a gzip-compressed JSON array
+  color                        Codex --color mode: auto (default, omitted), always, or never
+  response_schema              JSON schema the final agent message must validate against
+  agents_as_system_flag        Send AGENTS.md as codex developer_instructions instead of
+                               prepending it to the prompt (default: false)
+  annotate_timestamps          Add _received_ms (ms since start) to each of all_messages
+  max_retries                  Retries for transient failures (default: 0, max: 5); all
+                               attempts and backoff share the timeout_secs budget
+  agent_message_join           Separator between agent messages: newline (default), none,
+                               or space
+  always_warnings              Always include warnings, as \"\" when empty (default: false)
+  prompt_b64                   Base64-encoded UTF-8 prompt, used when PROMPT is empty
+  template                     Prompt with {{name}} placeholders, used instead of PROMPT
+  variables                    Object of values for the template's placeholders
+  allow_unresolved             Keep placeholders without a value instead of failing
+                               (default: false)
+  return_delta                 On resume, return only this turn's messages (default: false)
+  post_process_cmd             Command (as an argument list) that rewrites agent_messages
+                               via stdin/stdout; needs CODEX_ALLOW_POST_PROCESS
+  capture_resource_usage       Report peak RSS and CPU time of the codex process
+                               (Unix only, default: false)
+  output_as_resource           Write the output to a JSON file and return a resource
+                               link instead of inline text (default: false)
+  full_auto                    Workspace-write sandbox with automatic approvals, like
+                               codex --full-auto. Overrides sandbox; yolo wins over it.
+                               Needs CODEX_ALLOW_YOLO (default: false)
+  force_new_session            Start a new session even if SESSION_ID is given (default: false)
+  return_raw_stdout            Also return Codex's stdout verbatim as raw_stdout (capped at
+                               10MB; raw_stdout_truncated is set when cut) (default: false)
+  return_on_first_message      Stop Codex after its first agent message and return it,
+                               with interrupted set (default: false)
+  normalize_ids                Convert numeric id, thread_id, turn_id, item_id, and call_id
+                               fields in all_messages (top level and inside item) to
+                               strings (default: false)
+  return_todos                 Return Codex's latest todo/plan checklist as
+                               todos: [{text, status}] (default: false)
+  use_login_shell              Start Codex via 'sh -lc' so PATH from login profiles
+                               applies (Unix only, default: false)
+  plain_text_output            Also return agent_messages_plain: the answer with
+                               Markdown removed (default: false)
+  after_message_index          With return_all_messages, skip the first N messages;
+                               pass the message_count from the previous response to
+                               fetch only new ones
+  all_messages_exclude_types   Event or item types to leave out of all_messages
+                               (e.g. [\"heartbeat\"])
+  all_messages_include_types   Only collect these event or item types into all_messages
+                               (e.g. [\"agent_message\", \"command_execution\"]);
+                               the exclude list applies afterwards
+  capture_git_head             Return the HEAD commit of cd before and after the run as
+                               git_before/git_after (default: false)
+  provider_headers             Object of extra HTTP headers sent to the model provider
+                               (requires CODEX_ALLOW_PROVIDER_HEADERS)
+  agent_truncate_from          Side of agent_messages dropped past the size limit: end
+                               (default) or start, which keeps the latest messages
+  stdout_encoding              Encoding of codex output on non-UTF-8 locales, e.g. gbk or
+                               latin1 (default: utf-8)
+  agents_absolute_max          AGENTS.md size limit in bytes for this run (default:
+                               CODEX_AGENTS_ABSOLUTE_MAX or 1MB, max: 64MB)
+  oss                          Use Codex's local open-source model provider (--oss)
+                               instead of the hosted API (default: false)
+  verify_cmd                   Command (as an argument list) run in cd after a successful
+                               run, within the same timeout; reported as verification.
+                               Needs CODEX_ALLOW_VERIFY_CMD
+  verify_affects_success       Fail the run when verify_cmd fails (default: false)
+  fields                       Output keys to return, e.g. ["SESSION_ID"]; success,
+                               schema_version, error, warnings, and security_downgrades
+                               are always included (default: all)
+  include_fingerprint          Return a SHA-256 fingerprint of the answer for
+                               deduplication (default: false)
+  stop_sequences               Phrases that stop the run when an agent message contains
+                               one; the response is marked interrupted (default: none)
+  return_output_images         Return image files Codex produced as output_images
+                               [{path, mime}] (max 20 MiB each, default: false)
+  context_files                Text files (relative to cd) placed ahead of the prompt in
+                               <context file="..."> blocks; 64 KiB each, 256 KiB in total
+  use_git_root                 Run Codex at the top of the git repository containing cd;
+                               the response reports cd and requested_cd (default: false)
+  capability_token             '<expiry>.<signature>': a Unix expiry time and the hex
+                               HMAC-SHA256, keyed with CODEX_CAPABILITY_SECRET, of
+                               '<canonical cd>|<expiry>|<hex SHA-256 of PROMPT>'; allows
+                               danger-full-access and yolo for this request only.
+                               Invalid or expired tokens are ignored
+
+  The 'plan' tool accepts the same parameters but always runs read-only and
+  returns the proposed steps parsed from Codex's numbered-list answer.
+
+  The 'list_models' tool takes no parameters and returns the models configured in
+  $CODEX_HOME/config.toml (default ~/.codex/config.toml) as
+  {models: [{name, provider, default}]}: the top-level 'model' (the default) and each
+  profile's 'model'. Cached for five minutes; empty if the config names no model.
+
+  The 'warmup' tool takes no parameters. It runs a trivial read-only task in a
+  temporary directory to initialize Codex and returns {ready, details}.
+
+  The 'diff_sessions' tool takes SESSION_ID_A and SESSION_ID_B and returns
+  {differ, diff}: a unified diff of the two sessions' final agent messages, read
+  from the transcripts in $CODEX_HOME/sessions (default: ~/.codex/sessions).
+
+  The 'export_session' tool takes SESSION_ID and an optional include_reasoning flag
+  and returns {markdown, truncated}: the session's requests, commands and answers as
+  a Markdown document of at most 1 MiB, read from the same transcripts.
+
+  The 'server_config' tool takes no parameters and returns the effective limits,
+  timeouts, concurrency and security settings. Secrets are never included.
+
+  The 'batch' tool takes PROMPT and a list of working directories (cds), plus
+  optional sandbox, model, profile, timeout_secs and skip_git_repo_check applied to
+  every run. It returns {results: [{cd, result | error}]} in request order; a
+  failing directory does not stop the others.
 
 AGENTS.MD SUPPORT:
   If an AGENTS.md file exists in the working directory, its content will be
   automatically prepended to the prompt as a system prompt. This allows you to
   define project-specific instructions or context for all Codex invocations.
+  Set agents_as_system_flag to pass it via codex's developer_instructions
+  config instead (not available on Windows, where it is always prepended).
+  Files larger than 1MB are skipped with a warning.
 
 SECURITY:
   - By default, dangerous operations are disabled
@@ -65,18 +241,123 @@ SECURITY:
   - Timeouts are enforced to prevent unbounded execution
   - Sandbox modes restrict file system access
   - See environment variables above for security controls
+  - Security settings are read once at startup and reported in the server
+    instructions, so clients can see which restricted modes are permitted
 
 For more information, visit: https://github.com/missdeer/codex-mcp-rs"
 )]
-struct Cli {}
+struct Cli {
+    /// Check that codex is installed and can run a read-only task, then exit
+    #[arg(long)]
+    check: bool,
+
+    /// Before serving, run a trivial read-only task and exit with status 1 if codex does not
+    /// complete it within the probe timeout
+    #[arg(long)]
+    probe: bool,
+
+    /// Timeout for the startup probe in seconds; implies --probe
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    probe_timeout: Option<u64>,
+}
+
+/// Environment variable that enables the startup probe and sets its timeout in seconds
+const STARTUP_PROBE_ENV: &str = "CODEX_STARTUP_PROBE_SECS";
+
+/// Probe timeout used when only --probe is given
+const DEFAULT_STARTUP_PROBE_SECS: u64 = 60;
+
+/// Pure function to choose the startup probe timeout, None when the probe is off.
+/// --probe-timeout wins, then CODEX_STARTUP_PROBE_SECS (which enables the probe on its own),
+/// then the default when --probe is given. An invalid variable is an error rather than a
+/// silently skipped probe.
+fn startup_probe_secs(
+    probe: bool,
+    probe_timeout: Option<u64>,
+    env_val: Option<String>,
+) -> Result<Option<u64>, String> {
+    if probe_timeout.is_some() {
+        return Ok(probe_timeout);
+    }
+    match env_val.filter(|val| !val.trim().is_empty()) {
+        Some(val) => match val.trim().parse::<u64>() {
+            Ok(secs) if secs > 0 => Ok(Some(secs)),
+            _ => Err(format!(
+                "{}='{}' is not a positive number of seconds",
+                STARTUP_PROBE_ENV,
+                val.trim()
+            )),
+        },
+        None => Ok(probe.then_some(DEFAULT_STARTUP_PROBE_SECS)),
+    }
+}
+
+/// Self-test for `--check`: find the codex binary, then run the warmup task through codex::run.
+/// Progress goes to stderr so stdout stays clean; returns whether every step passed.
+async fn run_check() -> bool {
+    match codex::codex_version().await {
+        Ok(version) if version.is_empty() => eprintln!("[ok]   codex binary found"),
+        Ok(version) => eprintln!("[ok]   codex binary found: {}", version),
+        Err(e) => {
+            eprintln!("[fail] codex binary: {:#}", e);
+            eprintln!("Check FAILED");
+            return false;
+        }
+    }
+
+    let warmup = run_warmup().await;
+    if warmup.ready {
+        eprintln!("[ok]   read-only task: {}", warmup.details);
+        eprintln!("Check PASSED");
+    } else {
+        eprintln!("[fail] read-only task: {}", warmup.details);
+        eprintln!("Check FAILED");
+    }
+    warmup.ready
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command-line arguments (this will handle -h/--help and --version)
-    let _cli = Cli::parse();
+    let cli = Cli::parse();
+
+    if cli.check {
+        let passed = run_check().await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    // Fail fast so a supervisor restarts the server instead of it serving a codex that never answers
+    match startup_probe_secs(
+        cli.probe,
+        cli.probe_timeout,
+        std::env::var(STARTUP_PROBE_ENV).ok(),
+    ) {
+        Ok(Some(secs)) => {
+            let probe = run_warmup_within(secs).await;
+            if !probe.ready {
+                eprintln!("Error: startup probe failed: {}", probe.details);
+                std::process::exit(1);
+            }
+            eprintln!("Startup probe passed: {}", probe.details);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
 
     // Create an instance of our codex server
-    let service = CodexServer::new().serve(stdio()).await.inspect_err(|e| {
+    let mut server = CodexServer::new();
+    if let Err(e) = server.check_codex_version().await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    for warning in server.config_warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+
+    let service = server.serve(stdio()).await.inspect_err(|e| {
         eprintln!("serving error: {:?}", e);
     })?;
 
@@ -1,13 +1,23 @@
-use crate::codex::{self, Options, SandboxPolicy, DEFAULT_TIMEOUT_SECS, MAX_TIMEOUT_SECS};
+use crate::codex::{
+    self, AgentMessageJoin, AgentTruncateFrom, ColorMode, Options, SandboxPolicy, TurnOffset,
+    Verification, DEFAULT_TIMEOUT_SECS, MAX_LONG_TIMEOUT_SECS, MAX_TIMEOUT_SECS,
+};
+use crate::dir_lock::DEFAULT_DIR_LOCK_WAIT_SECS;
+use crate::events::{EventSink, LoggingEventSink, NoopEventSink};
+use crate::session;
+use crate::temp_dir::{temp_base_dir, CallTempDir};
 use rmcp::{
     handler::server::{router::tool::ToolRouter, wrapper::Parameters},
     model::*,
-    schemars, tool, tool_handler, tool_router, ErrorData as McpError, ServerHandler,
+    schemars,
+    service::RequestContext,
+    tool, tool_handler, tool_router, ErrorData as McpError, RoleServer, ServerHandler,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 mod serialize_as_os_string {
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -64,20 +74,34 @@ mod serialize_as_os_string_vec {
 }
 
 /// Input parameters for codex tool
-#[derive(Debug, Deserialize, schemars::JsonSchema)]
+#[derive(Debug, Default, Deserialize, schemars::JsonSchema)]
 pub struct CodexArgs {
-    /// Instruction for task to send to codex
-    #[serde(rename = "PROMPT")]
+    /// Instruction for task to send to codex. May be omitted when prompt_b64 is given
+    #[serde(rename = "PROMPT", default)]
     pub prompt: String,
+    /// Base64-encoded UTF-8 prompt, used when PROMPT is empty. Avoids JSON escaping issues
+    /// with prompts containing control characters
+    #[serde(default)]
+    pub prompt_b64: Option<String>,
+    /// Prompt skeleton with {{name}} placeholders filled from variables. Used instead of PROMPT
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Values for the template's {{name}} placeholders
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    /// Leave placeholders without a value in the prompt instead of rejecting the request
+    #[serde(default)]
+    pub allow_unresolved: bool,
     /// Set the workspace root for codex before executing the task
     #[serde(
         serialize_with = "serialize_as_os_string::serialize",
         deserialize_with = "serialize_as_os_string::deserialize"
     )]
     pub cd: PathBuf,
-    /// Sandbox policy for model-generated commands. Defaults to 'read-only'
+    /// Sandbox policy for model-generated commands. Defaults to the server's CODEX_DEFAULT_SANDBOX,
+    /// or 'read-only' when that is unset
     #[serde(default)]
-    pub sandbox: SandboxPolicy,
+    pub sandbox: Option<SandboxPolicy>,
     /// Resume the specified session of the codex. Defaults to None, start a new session
     #[serde(rename = "SESSION_ID", default)]
     pub session_id: Option<String>,
@@ -92,11 +116,13 @@ pub struct CodexArgs {
     pub return_all_messages_limit: Option<usize>,
     /// Attach one or more image files to the initial prompt
     #[serde(
+        default,
         serialize_with = "serialize_as_os_string_vec::serialize",
         deserialize_with = "serialize_as_os_string_vec::deserialize"
     )]
     pub image: Vec<PathBuf>,
-    /// The model to use for the codex session
+    /// The model to use for the codex session, e.g. 'gpt-5-codex'. Surrounding whitespace is
+    /// trimmed; a blank value means Codex's default model
     #[serde(default)]
     pub model: Option<String>,
     /// Run every command without approvals or sandboxing
@@ -106,9 +132,166 @@ pub struct CodexArgs {
     #[serde(default)]
     pub profile: Option<String>,
     /// Timeout in seconds for codex execution. If not specified, uses CODEX_DEFAULT_TIMEOUT
-    /// environment variable or falls back to 600 seconds (10 minutes). Max: 3600 seconds.
+    /// environment variable or falls back to 600 seconds (10 minutes). Max: 3600 seconds, or
+    /// 86400 for privileged requests (CODEX_ALLOW_LONG_TIMEOUT=true).
     #[serde(default)]
     pub timeout_secs: Option<u64>,
+    /// Kill codex if it produces no output for this many seconds, even when the total timeout
+    /// has not elapsed. Defaults to None (disabled).
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// Return the first and last raw JSON events from codex, for debugging without the full
+    /// message capture of return_all_messages
+    #[serde(default)]
+    pub return_boundary_events: bool,
+    /// Return all_messages gzip-compressed and base64-encoded in all_messages_gzip_b64 instead of
+    /// inline. Only applies when return_all_messages is true
+    #[serde(default)]
+    pub compress_output: bool,
+    /// ANSI color mode forwarded to codex: 'auto' (default, flag omitted), 'always', or 'never'
+    #[serde(default)]
+    pub color: Option<ColorMode>,
+    /// JSON schema the final agent message must conform to. When set, agent_messages is parsed
+    /// as JSON after the run and success is false if it does not validate
+    #[serde(default)]
+    pub response_schema: Option<Value>,
+    /// Send AGENTS.md to codex as developer instructions instead of prepending it to the prompt
+    #[serde(default)]
+    pub agents_as_system_flag: bool,
+    /// Add a `_received_ms` key (milliseconds since run start) to each message in all_messages
+    #[serde(default)]
+    pub annotate_timestamps: bool,
+    /// Retry transient failures (rate limits, overloaded upstream) up to this many times
+    /// (default: 0, max: 5). All attempts share the timeout_secs budget
+    #[serde(default)]
+    pub max_retries: Option<u32>,
+    /// Separator between consecutive agent messages: 'newline' (default), 'none', or 'space'.
+    /// Use 'none' when codex streams one answer as many small chunks
+    #[serde(default)]
+    pub agent_message_join: AgentMessageJoin,
+    /// Always include the warnings field, as an empty string when there is nothing to report,
+    /// so clients can parse responses uniformly
+    #[serde(default)]
+    pub always_warnings: bool,
+    /// Always start a brand-new session, ignoring SESSION_ID if one is given
+    #[serde(default)]
+    pub force_new_session: bool,
+    /// When resuming, return only the agent messages and events produced by this turn rather
+    /// than the whole replayed session. Relies on counts the server recorded for earlier turns
+    #[serde(default)]
+    pub return_delta: bool,
+    /// Command and arguments that receive agent_messages on stdin; its stdout replaces them.
+    /// Requires CODEX_ALLOW_POST_PROCESS=true on the server
+    #[serde(default)]
+    pub post_process_cmd: Option<Vec<String>>,
+    /// Report the codex process's peak memory and CPU time (Unix only)
+    #[serde(default)]
+    pub capture_resource_usage: bool,
+    /// Write the full output to a JSON file and return a resource link to it instead of
+    /// inlining it, for very large results. The file can be read back via resources/read
+    #[serde(default)]
+    pub output_as_resource: bool,
+    /// Shorthand for the workspace-write sandbox with automatic approvals (codex --full-auto).
+    /// Overrides an explicit sandbox; yolo takes precedence over it. Requires CODEX_ALLOW_YOLO=true
+    #[serde(default)]
+    pub full_auto: bool,
+    /// `<expiry>.<signature>`: a Unix expiry time and the hex HMAC-SHA256, keyed with the
+    /// server's CODEX_CAPABILITY_SECRET, of `<canonical cd>|<expiry>|<hex SHA-256 of the prompt>`.
+    /// A valid, unexpired token lifts the danger-full-access and yolo restrictions for this
+    /// request only
+    #[serde(default)]
+    pub capability_token: Option<String>,
+    /// Also return Codex's stdout verbatim as raw_stdout (capped at 10MB, with
+    /// raw_stdout_truncated set when the cap is hit)
+    #[serde(default)]
+    pub return_raw_stdout: bool,
+    /// Return as soon as Codex sends its first agent message, stopping the rest of the run.
+    /// Useful as a quick check that a task started correctly
+    #[serde(default)]
+    pub return_on_first_message: bool,
+    /// Convert numeric id fields (id, thread_id, turn_id, item_id, call_id, at the top level and
+    /// inside item) in all_messages to strings, so their type does not vary across Codex versions
+    #[serde(default)]
+    pub normalize_ids: bool,
+    /// Return Codex's latest todo/plan checklist as todos: [{text, status}]
+    #[serde(default)]
+    pub return_todos: bool,
+    /// Start Codex through a login shell (sh -lc) so PATH set up in shell profiles applies.
+    /// Ignored on Windows
+    #[serde(default)]
+    pub use_login_shell: bool,
+    /// Also return agent_messages with Markdown removed, as agent_messages_plain
+    #[serde(default)]
+    pub plain_text_output: bool,
+    /// With return_all_messages, leave out the first N messages (e.g. the message_count returned
+    /// by the previous call) so only newer ones are returned
+    #[serde(default)]
+    pub after_message_index: Option<usize>,
+    /// Event or item types to leave out of all_messages, e.g. ["heartbeat"]
+    #[serde(default)]
+    pub all_messages_exclude_types: Vec<String>,
+    /// Only collect these event or item types into all_messages, e.g. ["agent_message",
+    /// "command_execution"]. all_messages_exclude_types still applies afterwards
+    #[serde(default)]
+    pub all_messages_include_types: Vec<String>,
+    /// Return the working directory's HEAD commit before and after the run as
+    /// git_before/git_after, for auditing what the task committed
+    #[serde(default)]
+    pub capture_git_head: bool,
+    /// Extra HTTP headers codex sends to the model provider, e.g. {"X-Org-Id": "acme"}.
+    /// Requires CODEX_ALLOW_PROVIDER_HEADERS=true
+    #[serde(default)]
+    pub provider_headers: HashMap<String, String>,
+    /// Which side of agent_messages to drop past the size limit: 'end' (default) keeps the
+    /// beginning, 'start' keeps the latest messages such as the conclusion
+    #[serde(default)]
+    pub agent_truncate_from: AgentTruncateFrom,
+    /// Encoding of codex's output on systems with a non-UTF-8 locale, e.g. 'gbk' or 'latin1'
+    /// (default: utf-8)
+    #[serde(default)]
+    pub stdout_encoding: Option<String>,
+    /// Raise the AGENTS.md size limit in bytes for this run (default: CODEX_AGENTS_ABSOLUTE_MAX
+    /// or 1MB, capped at 64MB)
+    #[serde(default)]
+    pub agents_absolute_max: Option<u64>,
+    /// Use Codex's local open-source model provider (--oss, e.g. Ollama) instead of the hosted
+    /// API. Pair with a local model such as 'gpt-oss:20b'
+    #[serde(default)]
+    pub oss: bool,
+    /// Command and arguments run in cd after a successful run to check the result, e.g.
+    /// ["cargo", "test"]. It counts against timeout_secs and its outcome is returned as
+    /// verification. Requires CODEX_ALLOW_VERIFY_CMD=true on the server
+    #[serde(default)]
+    pub verify_cmd: Option<Vec<String>>,
+    /// Report the run as failed when verify_cmd fails (default: only warn)
+    #[serde(default)]
+    pub verify_affects_success: bool,
+    /// Return only these output keys (e.g. ["agent_messages", "SESSION_ID"]) to keep the
+    /// response small; success, schema_version, error, warnings, and security_downgrades are
+    /// always included. Empty returns every key. Not applied to output_as_resource files
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Return a SHA-256 fingerprint of the answer (agent_messages, plus all_messages when they
+    /// are returned) so identical answers across runs can be detected
+    #[serde(default)]
+    pub include_fingerprint: bool,
+    /// Phrases that stop the run as soon as an agent message contains one, e.g. a secret-leak
+    /// marker. The response then has interrupted set and a warning naming the phrase
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    /// Return image files Codex produced (e.g. generated diagrams) as output_images: [{path, mime}]
+    #[serde(default)]
+    pub return_output_images: bool,
+    /// Text files whose content is placed ahead of the prompt in <context file="..."> blocks.
+    /// Relative paths are resolved against cd. Each file is cut at MAX_CONTEXT_FILE_BYTES and
+    /// all of them together at MAX_CONTEXT_TOTAL_BYTES, with a warning
+    #[serde(default)]
+    pub context_files: Vec<PathBuf>,
+    /// Run Codex at the top of the git repository containing cd instead of in cd itself. Falls
+    /// back to cd with a warning outside a repository. A capability_token must then be issued
+    /// for the repository root
+    #[serde(default)]
+    pub use_git_root: bool,
 }
 
 /// Result of parsing the default timeout from environment
@@ -182,7 +365,318 @@ fn get_default_timeout_with_warning() -> DefaultTimeoutResult {
     resolve_timeout_from_env(std::env::var("CODEX_DEFAULT_TIMEOUT"))
 }
 
+/// Environment variable capping the prompt size in bytes
+const MAX_PROMPT_BYTES_ENV: &str = "CODEX_MAX_PROMPT_BYTES";
+
+/// Default prompt size cap, comfortably below typical OS command-line limits
+pub const DEFAULT_MAX_PROMPT_BYTES: usize = 256 * 1024;
+
+/// Pure function to resolve the prompt size cap from an environment variable result.
+/// Empty values count as unset; zero or unparsable values fall back to the default with a warning.
+fn resolve_max_prompt_bytes(
+    env_result: Result<String, std::env::VarError>,
+) -> (usize, Option<String>) {
+    let val = match env_result {
+        Ok(val) => val,
+        Err(std::env::VarError::NotPresent) => return (DEFAULT_MAX_PROMPT_BYTES, None),
+        Err(std::env::VarError::NotUnicode(_)) => {
+            return (
+                DEFAULT_MAX_PROMPT_BYTES,
+                Some(format!(
+                    "{} contains invalid UTF-8; using default of {} bytes",
+                    MAX_PROMPT_BYTES_ENV, DEFAULT_MAX_PROMPT_BYTES
+                )),
+            )
+        }
+    };
+    let trimmed = val.trim();
+    if trimmed.is_empty() {
+        return (DEFAULT_MAX_PROMPT_BYTES, None);
+    }
+    match trimmed.parse::<usize>() {
+        Ok(bytes) if bytes > 0 => (bytes, None),
+        _ => (
+            DEFAULT_MAX_PROMPT_BYTES,
+            Some(format!(
+                "{}='{}' is not a positive number; using default of {} bytes",
+                MAX_PROMPT_BYTES_ENV, trimmed, DEFAULT_MAX_PROMPT_BYTES
+            )),
+        ),
+    }
+}
+
+/// Environment variable selecting the sandbox used when a client does not choose one
+const DEFAULT_SANDBOX_ENV: &str = "CODEX_DEFAULT_SANDBOX";
+
+/// Pure function to resolve the server-wide default sandbox from an environment variable result.
+/// Empty values count as unset; unknown values fall back to read-only with a warning.
+fn resolve_default_sandbox(
+    env_result: Result<String, std::env::VarError>,
+) -> (SandboxPolicy, Option<String>) {
+    let val = match env_result {
+        Ok(val) if !val.trim().is_empty() => val,
+        Ok(_) | Err(std::env::VarError::NotPresent) => return (SandboxPolicy::default(), None),
+        Err(std::env::VarError::NotUnicode(_)) => {
+            return (
+                SandboxPolicy::default(),
+                Some(format!(
+                    "{} contains invalid UTF-8; using read-only",
+                    DEFAULT_SANDBOX_ENV
+                )),
+            )
+        }
+    };
+    let trimmed = val.trim();
+    match SandboxPolicy::ALL
+        .iter()
+        .find(|policy| policy.as_str().eq_ignore_ascii_case(trimmed))
+    {
+        Some(policy) => (policy.clone(), None),
+        None => (
+            SandboxPolicy::default(),
+            Some(format!(
+                "{}='{}' is not a sandbox mode; using read-only",
+                DEFAULT_SANDBOX_ENV, trimmed
+            )),
+        ),
+    }
+}
+
+/// Give args without a client-chosen sandbox the server-wide default resolved from
+/// `env_result`. Returns a warning when that environment value is unusable
+fn apply_default_sandbox(
+    args: &mut CodexArgs,
+    env_result: Result<String, std::env::VarError>,
+) -> Option<String> {
+    if args.sandbox.is_some() {
+        return None;
+    }
+    let (default_sandbox, warning) = resolve_default_sandbox(env_result);
+    args.sandbox = Some(default_sandbox);
+    warning
+}
+
+/// Reject prompts larger than `max_bytes` before they reach the command line
+fn check_prompt_size(prompt: &str, max_bytes: usize) -> Result<(), McpError> {
+    if prompt.len() > max_bytes {
+        return Err(McpError::invalid_params(
+            format!(
+                "PROMPT is {} bytes, which exceeds the limit of {} bytes. Shorten it or raise {}.",
+                prompt.len(),
+                max_bytes,
+                MAX_PROMPT_BYTES_ENV
+            ),
+            None,
+        ));
+    }
+    Ok(())
+}
+
+/// Environment variable capping how many images one request may attach
+const MAX_IMAGES_ENV: &str = "CODEX_MAX_IMAGES";
+
+/// Environment variable capping the combined size of a request's images in bytes
+const MAX_IMAGE_BYTES_ENV: &str = "CODEX_MAX_IMAGE_BYTES";
+
+/// Default number of images per request
+pub const DEFAULT_MAX_IMAGES: u64 = 10;
+
+/// Default combined image size per request
+pub const DEFAULT_MAX_IMAGE_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Pure function to resolve a positive limit from an environment variable result.
+/// Empty values count as unset; zero or unparsable values fall back to `default` with a warning.
+fn resolve_positive_limit(
+    key: &str,
+    env_result: Result<String, std::env::VarError>,
+    default: u64,
+) -> (u64, Option<String>) {
+    let val = match env_result {
+        Ok(val) if !val.trim().is_empty() => val,
+        _ => return (default, None),
+    };
+    match val.trim().parse::<u64>() {
+        Ok(limit) if limit > 0 => (limit, None),
+        _ => (
+            default,
+            Some(format!(
+                "{}='{}' is not a positive number; using default of {}",
+                key,
+                val.trim(),
+                default
+            )),
+        ),
+    }
+}
+
+/// Reject requests attaching more than `max_images` images
+fn check_image_count(count: usize, max_images: u64) -> Result<(), McpError> {
+    if count as u64 > max_images {
+        return Err(McpError::invalid_params(
+            format!(
+                "{} images were attached, which exceeds the limit of {}. Attach fewer or raise {}.",
+                count, max_images, MAX_IMAGES_ENV
+            ),
+            None,
+        ));
+    }
+    Ok(())
+}
+
+/// Reject requests attaching more than `max_images` images or more than `max_total_bytes`
+/// bytes of images in total. `sizes` holds the size of each attached image
+fn check_image_limits(
+    sizes: &[u64],
+    max_images: u64,
+    max_total_bytes: u64,
+) -> Result<(), McpError> {
+    check_image_count(sizes.len(), max_images)?;
+    let total: u64 = sizes.iter().sum();
+    if total > max_total_bytes {
+        return Err(McpError::invalid_params(
+            format!(
+                "attached images total {} bytes, which exceeds the limit of {} bytes. Attach smaller images or raise {}.",
+                total, max_total_bytes, MAX_IMAGE_BYTES_ENV
+            ),
+            None,
+        ));
+    }
+    Ok(())
+}
+
+/// Largest part of one context file placed in the prompt
+pub const MAX_CONTEXT_FILE_BYTES: usize = 64 * 1024;
+
+/// Largest combined content of all context files placed in the prompt
+pub const MAX_CONTEXT_TOTAL_BYTES: usize = 256 * 1024;
+
+/// Start of a context file, as read for the prompt
+#[derive(Debug)]
+struct ContextFile {
+    /// Path shown in the block, relative to cd when the file is inside it
+    name: String,
+    content: String,
+    /// Whether the file was longer than MAX_CONTEXT_FILE_BYTES
+    truncated: bool,
+}
+
+/// Read at most `cap` bytes of a context file, which must be UTF-8 text. A character split by
+/// the cap is dropped
+fn read_context_file(path: &Path, name: String, cap: usize) -> Result<ContextFile, McpError> {
+    use std::io::Read;
+
+    let mut bytes = Vec::new();
+    std::fs::File::open(path)
+        .and_then(|file| file.take(cap as u64 + 1).read_to_end(&mut bytes))
+        .map_err(|e| {
+            McpError::invalid_params(
+                format!("context file could not be read: {} ({})", path.display(), e),
+                None,
+            )
+        })?;
+    let truncated = bytes.len() > cap;
+    bytes.truncate(cap);
+    let content = match String::from_utf8(bytes) {
+        Ok(content) => content,
+        Err(e) if truncated && e.utf8_error().error_len().is_none() => {
+            let valid = e.utf8_error().valid_up_to();
+            let mut bytes = e.into_bytes();
+            bytes.truncate(valid);
+            String::from_utf8(bytes).unwrap_or_default()
+        }
+        Err(_) => {
+            return Err(McpError::invalid_params(
+                format!("context file is not UTF-8 text: {}", path.display()),
+                None,
+            ))
+        }
+    };
+    Ok(ContextFile {
+        name,
+        content,
+        truncated,
+    })
+}
+
+/// Pure function to place context files ahead of `prompt` as `<context file="...">` blocks.
+/// Content stops at `total_cap` bytes across all files; cut or left-out files are reported as
+/// warnings
+fn inject_context_files(
+    prompt: &str,
+    files: &[ContextFile],
+    total_cap: usize,
+) -> (String, Vec<String>) {
+    use std::fmt::Write;
+
+    let mut injected = String::new();
+    let mut warnings = Vec::new();
+    let mut remaining = total_cap;
+    for file in files {
+        if file.truncated {
+            warnings.push(format!(
+                "context file {} was cut to its first {} bytes",
+                file.name, MAX_CONTEXT_FILE_BYTES
+            ));
+        }
+        if remaining == 0 {
+            warnings.push(format!(
+                "context file {} was left out: context files are limited to {} bytes in total",
+                file.name, total_cap
+            ));
+            continue;
+        }
+        let mut content = file.content.as_str();
+        let mut truncated = file.truncated;
+        if content.len() > remaining {
+            let mut end = remaining;
+            while !content.is_char_boundary(end) {
+                end -= 1;
+            }
+            content = &content[..end];
+            truncated = true;
+            warnings.push(format!(
+                "context file {} was cut off: context files are limited to {} bytes in total",
+                file.name, total_cap
+            ));
+        }
+        remaining -= content.len();
+
+        let _ = write!(
+            injected,
+            "<context file=\"{}\">\n{}",
+            file.name.replace('"', "&quot;"),
+            content
+        );
+        if truncated {
+            injected.push_str("\n[... truncated ...]");
+        }
+        injected.push_str("\n</context>\n\n");
+    }
+    injected.push_str(prompt);
+    (injected, warnings)
+}
+
+/// Restricted settings apply_security_restrictions turned off, as flags for clients that would
+/// otherwise parse the security warnings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, schemars::JsonSchema)]
+pub struct SecurityDowngrades {
+    /// danger-full-access was downgraded to read-only
+    pub sandbox_downgraded: bool,
+    /// yolo was turned off
+    pub yolo_disabled: bool,
+    /// skip_git_repo_check was turned off
+    pub git_check_disabled: bool,
+}
+
+impl SecurityDowngrades {
+    /// Whether any downgrade was applied
+    pub fn any(&self) -> bool {
+        self.sandbox_downgraded || self.yolo_disabled || self.git_check_disabled
+    }
+}
+
 /// Security configuration for server-side restrictions
+#[derive(Debug, Clone, Serialize, schemars::JsonSchema)]
 pub struct SecurityConfig {
     /// Allow dangerous sandbox modes
     pub allow_danger_full_access: bool,
@@ -190,6 +684,25 @@ pub struct SecurityConfig {
     pub allow_yolo: bool,
     /// Allow skipping git repo checks
     pub allow_skip_git_check: bool,
+    /// Refuse (instead of warn) when a writable sandbox targets a git repo with uncommitted changes
+    pub block_dirty_tree: bool,
+    /// Allow piping agent output through a client-supplied post_process_cmd
+    pub allow_post_process: bool,
+    /// Models clients may request; None allows any model
+    pub allowed_models: Option<Vec<String>>,
+    /// Replace a disallowed model with the first allowed one instead of rejecting the request
+    pub downgrade_disallowed_models: bool,
+    /// Allow timeouts up to MAX_LONG_TIMEOUT_SECS instead of MAX_TIMEOUT_SECS
+    pub allow_long_timeout: bool,
+    /// Reject requests for disallowed danger-full-access, yolo, or full_auto instead of downgrading them
+    pub strict_security: bool,
+    /// Allow clients to send extra HTTP headers to the model provider via provider_headers
+    pub allow_provider_headers: bool,
+    /// Allow running a client-supplied verify_cmd after each run
+    pub allow_verify_cmd: bool,
+    /// Pass --skip-git-repo-check automatically when cd is not inside a git repository, without
+    /// requiring allow_skip_git_check
+    pub auto_skip_git_check: bool,
 }
 
 /// Pure function to resolve a boolean from an environment variable value.
@@ -228,7 +741,267 @@ fn get_security_config(warnings: &mut Vec<String>) -> SecurityConfig {
         allow_yolo: parse_env_bool("CODEX_ALLOW_YOLO", warnings).unwrap_or(false),
         allow_skip_git_check: parse_env_bool("CODEX_ALLOW_SKIP_GIT_CHECK", warnings)
             .unwrap_or(false),
+        block_dirty_tree: parse_env_bool("CODEX_BLOCK_DIRTY_TREE", warnings).unwrap_or(false),
+        allow_post_process: parse_env_bool("CODEX_ALLOW_POST_PROCESS", warnings).unwrap_or(false),
+        allowed_models: resolve_allowed_models(std::env::var("CODEX_ALLOWED_MODELS").ok()),
+        downgrade_disallowed_models: parse_env_bool("CODEX_DOWNGRADE_DISALLOWED_MODELS", warnings)
+            .unwrap_or(false),
+        allow_long_timeout: parse_env_bool("CODEX_ALLOW_LONG_TIMEOUT", warnings).unwrap_or(false),
+        strict_security: parse_env_bool("CODEX_STRICT_SECURITY", warnings).unwrap_or(false),
+        allow_provider_headers: parse_env_bool("CODEX_ALLOW_PROVIDER_HEADERS", warnings)
+            .unwrap_or(false),
+        allow_verify_cmd: parse_env_bool("CODEX_ALLOW_VERIFY_CMD", warnings).unwrap_or(false),
+        auto_skip_git_check: parse_env_bool("CODEX_AUTO_SKIP_GIT_CHECK", warnings).unwrap_or(false),
+    }
+}
+
+/// Pure function to parse a comma-separated model allowlist. Blank entries are dropped, and an
+/// unset or empty list allows any model.
+fn resolve_allowed_models(env_val: Option<String>) -> Option<Vec<String>> {
+    let models: Vec<String> = env_val?
+        .split(',')
+        .map(str::trim)
+        .filter(|model| !model.is_empty())
+        .map(str::to_string)
+        .collect();
+    (!models.is_empty()).then_some(models)
+}
+
+/// Trim a requested model name, keeping its case. A blank name is treated as unset with a
+/// warning, so codex is never started with an empty `--model`
+fn normalize_model(model: Option<String>) -> (Option<String>, Option<String>) {
+    let Some(model) = model else {
+        return (None, None);
+    };
+    let normalized = model.trim().to_string();
+    if normalized.is_empty() {
+        let warning = "model was empty after trimming whitespace; using Codex's default model";
+        return (None, Some(warning.to_string()));
+    }
+    (Some(normalized), None)
+}
+
+/// Warn when a local (--oss) run asks for a hosted model the local provider cannot serve
+fn oss_model_warning(oss: bool, model: Option<&str>) -> Option<String> {
+    let model = model.filter(|model| oss && codex::is_cloud_only_model(model))?;
+    Some(format!(
+        "model '{}' is a hosted model and is unlikely to be available with oss; choose a local model such as 'gpt-oss:20b'",
+        model
+    ))
+}
+
+/// Check a requested model against the allowlist. A disallowed model is rejected, or replaced by
+/// the first allowed model with a warning when downgrading is enabled.
+fn apply_model_allowlist(
+    model: Option<String>,
+    allowed_models: Option<&[String]>,
+    downgrade: bool,
+) -> Result<(Option<String>, Option<String>), McpError> {
+    let (Some(requested), Some(allowed)) = (model.as_deref(), allowed_models) else {
+        return Ok((model, None));
+    };
+    if allowed.iter().any(|m| m == requested) {
+        return Ok((model, None));
+    }
+    if !downgrade {
+        return Err(McpError::invalid_params(
+            format!(
+                "model '{}' is not allowed on this server; allowed models: {}",
+                requested,
+                allowed.join(", ")
+            ),
+            None,
+        ));
+    }
+    let replacement = allowed[0].clone();
+    let warning = format!(
+        "Security warning: model '{}' is not allowed on this server and was replaced with '{}'.",
+        requested, replacement
+    );
+    Ok((Some(replacement), Some(warning)))
+}
+
+/// Environment variable mapping MCP client names to the codex profile used for their requests
+const CLIENT_PROFILE_MAP_ENV: &str = "CODEX_CLIENT_PROFILE_MAP";
+
+/// Pure function to parse the client-name-to-profile map from an environment variable result.
+/// The value is a JSON object of strings; anything else is ignored with a warning.
+fn resolve_client_profile_map(
+    env_result: Result<String, std::env::VarError>,
+) -> (HashMap<String, String>, Option<String>) {
+    let val = match env_result {
+        Ok(val) if !val.trim().is_empty() => val,
+        Ok(_) | Err(std::env::VarError::NotPresent) => return (HashMap::new(), None),
+        Err(std::env::VarError::NotUnicode(_)) => {
+            return (
+                HashMap::new(),
+                Some(format!(
+                    "{} contains invalid UTF-8; ignoring it",
+                    CLIENT_PROFILE_MAP_ENV
+                )),
+            )
+        }
+    };
+    match serde_json::from_str::<HashMap<String, String>>(&val) {
+        Ok(map) => (map, None),
+        Err(e) => (
+            HashMap::new(),
+            Some(format!(
+                "{} is not a JSON object of client names to profile names ({}); ignoring it",
+                CLIENT_PROFILE_MAP_ENV, e
+            )),
+        ),
+    }
+}
+
+/// Environment variable overriding the default AGENTS.md size limit (MAX_AGENTS_MD_SIZE)
+const AGENTS_ABSOLUTE_MAX_ENV: &str = "CODEX_AGENTS_ABSOLUTE_MAX";
+
+/// Environment variable letting a non-empty AGENTS.md stand in for an empty PROMPT
+const AGENTS_ONLY_PROMPT_ENV: &str = "CODEX_ALLOW_AGENTS_ONLY_PROMPT";
+
+/// Require a prompt, unless `agents_only_prompt` is enabled and `cd` has an AGENTS.md with
+/// content, which then becomes the task
+fn check_prompt_present(
+    prompt: &str,
+    agents_only_prompt: bool,
+    cd: &Path,
+    agents_md_limit: u64,
+) -> Result<(), McpError> {
+    if !prompt.is_empty() || (agents_only_prompt && codex::has_agents_md(cd, agents_md_limit)) {
+        return Ok(());
+    }
+    let message = if agents_only_prompt {
+        "PROMPT (or prompt_b64) is required when the working directory has no AGENTS.md"
+    } else {
+        "PROMPT (or prompt_b64) is required and must be a non-empty string"
+    };
+    Err(McpError::invalid_params(message, None))
+}
+
+/// Environment variable holding the secret that capability tokens are signed with
+const CAPABILITY_SECRET_ENV: &str = "CODEX_CAPABILITY_SECRET";
+
+/// Decode a hex string, returning None for odd lengths or non-hex characters
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Message a capability token signs: the canonical working directory, the expiry, and the
+/// prompt's SHA-256, so a token cannot be replayed for another directory or prompt
+fn capability_message(canonical_cd: &Path, expiry: u64, prompt: &str) -> String {
+    use sha2::Digest;
+
+    let prompt_hash: String = sha2::Sha256::digest(prompt.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(
+        "{}|{}|{}",
+        canonical_cd.to_string_lossy(),
+        expiry,
+        prompt_hash
+    )
+}
+
+/// Check a `<expiry>.<hex signature>` capability token for this directory and prompt. The
+/// signature is compared in constant time; `now` is the current Unix time in seconds.
+/// Returns why the token was rejected
+fn verify_capability_token(
+    secret: &str,
+    canonical_cd: &Path,
+    prompt: &str,
+    token: &str,
+    now: u64,
+) -> Result<(), &'static str> {
+    use hmac::{Hmac, Mac};
+
+    let (expiry, signature) = token
+        .trim()
+        .split_once('.')
+        .and_then(|(expiry, signature)| Some((expiry.parse::<u64>().ok()?, decode_hex(signature)?)))
+        .ok_or("it is not in the form <expiry>.<hex signature>")?;
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|_| "the server's secret cannot be used")?;
+    mac.update(capability_message(canonical_cd, expiry, prompt).as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| "its signature does not match this cd and prompt")?;
+    if expiry < now {
+        return Err("it has expired");
+    }
+    Ok(())
+}
+
+/// Best-effort check for uncommitted changes via `git status --porcelain`.
+/// Returns None when the directory is not a git repository or git is unavailable.
+fn is_dirty_git_tree(dir: &Path) -> Option<bool> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["status", "--porcelain"])
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(!output.stdout.is_empty())
+}
+
+/// Guard against writable runs clobbering uncommitted work.
+/// Returns a warning when the tree is dirty, or an error instead when block_dirty_tree is set.
+/// Runs that cannot write (see codex::can_write) and directories outside git repositories are
+/// never checked.
+fn check_dirty_tree(
+    dir: &Path,
+    sandbox: &SandboxPolicy,
+    yolo: bool,
+    full_auto: bool,
+    block_dirty_tree: bool,
+) -> Result<Option<String>, McpError> {
+    if !codex::can_write(sandbox, yolo, full_auto) || is_dirty_git_tree(dir) != Some(true) {
+        return Ok(None);
+    }
+
+    let mode = if yolo {
+        "yolo".to_string()
+    } else if full_auto {
+        "full_auto".to_string()
+    } else {
+        format!("the {} sandbox", sandbox.as_str())
+    };
+    if block_dirty_tree {
+        return Err(McpError::invalid_params(
+            format!(
+                "working directory has uncommitted changes; refusing to run with {}. Commit or stash them, or unset CODEX_BLOCK_DIRTY_TREE.",
+                mode
+            ),
+            None,
+        ));
     }
+
+    Ok(Some(format!(
+        "Warning: working directory has uncommitted changes and Codex runs with {}, so it may overwrite them. Set CODEX_BLOCK_DIRTY_TREE=true to refuse such runs.",
+        mode
+    )))
+}
+
+/// Error for a request that asks for a disallowed mode while CODEX_STRICT_SECURITY is set
+fn strict_security_error(mode: &str, env_var: &str) -> McpError {
+    McpError::invalid_params(
+        format!(
+            "{} is not allowed on this server (CODEX_STRICT_SECURITY is set). Set {}=true to enable it.",
+            mode, env_var
+        ),
+        None,
+    )
 }
 
 fn merge_warnings(
@@ -246,6 +1019,14 @@ fn merge_warnings(
     }
 }
 
+/// Ensure the warnings field is present (possibly empty) when the caller asked for it
+fn finalize_warnings(warnings: Option<String>, always_warnings: bool) -> Option<String> {
+    match warnings {
+        None if always_warnings => Some(String::new()),
+        other => other,
+    }
+}
+
 fn attach_warnings(mut error_msg: String, warnings: Option<String>) -> String {
     if let Some(w) = warnings {
         if !w.is_empty() {
@@ -255,9 +1036,16 @@ fn attach_warnings(mut error_msg: String, warnings: Option<String>) -> String {
     error_msg
 }
 
+/// Version of the codex tool's output shape, returned as schema_version.
+/// Adding optional fields does not change it; it is bumped when a field is removed or renamed, or
+/// when an existing field changes type or meaning, so clients can branch on it.
+pub const OUTPUT_SCHEMA_VERSION: u32 = 1;
+
 /// Output from the codex tool
 #[derive(Debug, Serialize, schemars::JsonSchema)]
 struct CodexOutput {
+    /// Always OUTPUT_SCHEMA_VERSION
+    schema_version: u32,
     success: bool,
     #[serde(rename = "SESSION_ID")]
     session_id: String,
@@ -268,33 +1056,909 @@ struct CodexOutput {
     all_messages: Option<Vec<HashMap<String, Value>>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     all_messages_truncated: Option<bool>,
+    /// all_messages serialized as a JSON array, gzip-compressed, then base64-encoded with the
+    /// standard padded alphabet. Set instead of all_messages when compress_output is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    all_messages_gzip_b64: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     warnings: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_event: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_event: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resource_usage: Option<codex::ResourceUsage>,
+    /// The individual errors joined into `error`, for programmatic handling
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+    /// Codex's stdout verbatim, when return_raw_stdout is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_stdout_truncated: Option<bool>,
+    /// Set when Codex was stopped after its first message (return_on_first_message)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interrupted: Option<bool>,
+    /// The all_messages cap that was applied, after clamping return_all_messages_limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effective_message_limit: Option<usize>,
+    /// Codex's latest todo list, when return_todos is requested
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    todos: Vec<codex::TodoItem>,
+    /// Whether AGENTS.md from the working directory was passed to Codex. False when it is
+    /// absent or was skipped (see warnings)
+    agents_md_applied: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    agents_md_path: Option<String>,
+    /// agent_messages with Markdown removed, when plain_text_output is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    agent_messages_plain: Option<String>,
+    /// Messages produced so far, to pass as the next after_message_index (with return_all_messages)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_count: Option<usize>,
+    /// Times Codex was started, including retries (1 when there were none)
+    attempts: u32,
+    /// HEAD commit before and after the run, when capture_git_head is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    git_before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    git_after: Option<String>,
+    /// The final answer alone, separate from the intermediate agent_messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    final_answer: Option<String>,
+    /// Exit code of the codex process, when it exited normally
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    /// Milliseconds until Codex's first agent message (time to first token)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttft_ms: Option<u64>,
+    /// Outcome of verify_cmd, when one was run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verification: Option<Verification>,
+    /// Hex SHA-256 of the canonicalized answer, when include_fingerprint is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+    /// Image files Codex produced, when return_output_images is requested
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    output_images: Vec<codex::OutputImage>,
+    /// Directory Codex ran in, when use_git_root is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cd: Option<String>,
+    /// The cd given in the request, when use_git_root is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requested_cd: Option<String>,
+    /// Which restricted settings the server turned off for this request, matching the security
+    /// warnings; omitted when nothing was downgraded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    security_downgrades: Option<SecurityDowngrades>,
+}
+
+/// Keys of the codex tool's output that `fields` can select
+const CODEX_OUTPUT_FIELDS: &[&str] = &[
+    "schema_version",
+    "success",
+    "SESSION_ID",
+    "agent_messages",
+    "agent_messages_truncated",
+    "all_messages",
+    "all_messages_truncated",
+    "all_messages_gzip_b64",
+    "error",
+    "warnings",
+    "first_event",
+    "last_event",
+    "retry_after_secs",
+    "resource_usage",
+    "errors",
+    "raw_stdout",
+    "raw_stdout_truncated",
+    "interrupted",
+    "effective_message_limit",
+    "todos",
+    "agents_md_applied",
+    "agents_md_path",
+    "agent_messages_plain",
+    "message_count",
+    "attempts",
+    "git_before",
+    "git_after",
+    "final_answer",
+    "exit_code",
+    "ttft_ms",
+    "verification",
+    "fingerprint",
+    "output_images",
+    "cd",
+    "requested_cd",
+    "security_downgrades",
+];
+
+/// Pure function to reject requested output fields that the codex tool never returns
+fn check_output_fields(fields: &[String]) -> Result<(), McpError> {
+    let unknown: Vec<&str> = fields
+        .iter()
+        .map(String::as_str)
+        .filter(|field| !CODEX_OUTPUT_FIELDS.contains(field))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    Err(McpError::invalid_params(
+        format!(
+            "Unknown output field(s): {}. Known fields: {}",
+            unknown.join(", "),
+            CODEX_OUTPUT_FIELDS.join(", ")
+        ),
+        None,
+    ))
+}
+
+/// Output keys returned whatever `fields` asks for, so a filtered response still identifies
+/// its schema and reports failures, warnings, and security downgrades
+const ALWAYS_RETURNED_FIELDS: &[&str] = &[
+    "success",
+    "schema_version",
+    "error",
+    "warnings",
+    "security_downgrades",
+];
+
+/// Serialize `output` keeping only `fields` and ALWAYS_RETURNED_FIELDS; every key when `fields`
+/// is empty
+fn select_output_fields(output: &CodexOutput, fields: &[String]) -> Result<Value, McpError> {
+    let mut value = serde_json::to_value(output).map_err(|e| {
+        McpError::internal_error(format!("Failed to serialize output: {}", e), None)
+    })?;
+    if let (false, Value::Object(map)) = (fields.is_empty(), &mut value) {
+        map.retain(|key, _| {
+            ALWAYS_RETURNED_FIELDS.contains(&key.as_str())
+                || fields.iter().any(|field| field == key)
+        });
+    }
+    Ok(value)
+}
+
+/// Pure function to expand a leading `~` (alone or followed by a path separator) to `home`.
+/// `~user` forms and paths without a leading `~` are returned unchanged.
+fn expand_tilde(path: &Path, home: Option<&Path>) -> PathBuf {
+    let Some(home) = home else {
+        return path.to_path_buf();
+    };
+    let mut components = path.components();
+    match components.next() {
+        Some(std::path::Component::Normal(first)) if first == "~" => {
+            home.join(components.as_path())
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Expand a leading `~` in a client-supplied path to the current user's home directory
+fn expand_home(path: &Path) -> PathBuf {
+    expand_tilde(path, dirs::home_dir().as_deref())
+}
+
+/// Expand full_auto into the workspace-write sandbox it implies. `requested` is the sandbox
+/// the client asked for, if any; returns a note when full_auto overrides it or when yolo
+/// makes full_auto redundant.
+fn expand_full_auto(args: &mut CodexArgs, requested: Option<&SandboxPolicy>) -> Option<String> {
+    if !args.full_auto {
+        return None;
+    }
+    if args.yolo {
+        return Some("full_auto has no effect because yolo is set".to_string());
+    }
+    args.sandbox = Some(SandboxPolicy::WorkspaceWrite);
+    requested
+        .filter(|sandbox| **sandbox != SandboxPolicy::WorkspaceWrite)
+        .map(|sandbox| {
+            format!(
+                "full_auto overrides sandbox={}; running with workspace-write",
+                sandbox.as_str()
+            )
+        })
+}
+
+/// Force a plan request to run read-only: every argument that could grant write access is
+/// cleared before the request reaches the security gate
+fn read_only_plan_args(mut args: CodexArgs) -> CodexArgs {
+    args.sandbox = Some(SandboxPolicy::ReadOnly);
+    args.yolo = false;
+    args.full_auto = false;
+    args
+}
+
+/// Error message for a `cd` that exists but is not a directory. When it is a regular file,
+/// suggest its parent directory, which is usually what the client meant.
+fn describe_non_directory_cd(cd: &Path, canonical: &Path) -> String {
+    match canonical.parent() {
+        Some(parent) if canonical.is_file() => format!(
+            "cd is a file; did you mean its parent {}? (got {})",
+            parent.display(),
+            cd.display()
+        ),
+        _ => format!("working directory is not a directory: {}", cd.display()),
+    }
+}
+
+/// Decode a standard-alphabet Base64 prompt and check that it is valid UTF-8
+fn decode_prompt_b64(encoded: &str) -> Result<String, McpError> {
+    use base64::Engine;
+
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| {
+            McpError::invalid_params(format!("prompt_b64 is not valid base64: {}", e), None)
+        })?;
+    String::from_utf8(bytes).map_err(|e| {
+        McpError::invalid_params(
+            format!("prompt_b64 does not decode to valid UTF-8: {}", e),
+            None,
+        )
+    })
+}
+
+/// Replace each `{{name}}` in `template` with its value from `variables` (whitespace around the
+/// name is ignored). Unknown names are an error listing all of them, unless `allow_unresolved`
+/// keeps them verbatim
+fn expand_template(
+    template: &str,
+    variables: &HashMap<String, String>,
+    allow_unresolved: bool,
+) -> Result<String, McpError> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut unresolved = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        let placeholder = &rest[start..start + 2 + len + 2];
+        let name = rest[start + 2..start + 2 + len].trim();
+        match variables.get(name) {
+            Some(value) => expanded.push_str(value),
+            None => {
+                expanded.push_str(placeholder);
+                if !unresolved.contains(&name) {
+                    unresolved.push(name);
+                }
+            }
+        }
+        rest = &rest[start + placeholder.len()..];
+    }
+    expanded.push_str(rest);
+
+    if !unresolved.is_empty() && !allow_unresolved {
+        return Err(McpError::invalid_params(
+            format!(
+                "template has no value for: {}; add them to variables or set allow_unresolved",
+                unresolved.join(", ")
+            ),
+            None,
+        ));
+    }
+    Ok(expanded)
+}
+
+/// Hex SHA-256 over agent_messages with line endings and trailing whitespace normalized, followed
+/// by all_messages as JSON with sorted keys when given, so equal answers hash equally
+fn output_fingerprint(
+    agent_messages: &str,
+    all_messages: Option<&[HashMap<String, Value>]>,
+) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    hasher.update(agent_messages.replace("\r\n", "\n").trim_end().as_bytes());
+    if let Some(messages) = all_messages {
+        let canonical: Vec<Value> = messages
+            .iter()
+            .map(|message| sort_json_keys(&serde_json::json!(message)))
+            .collect();
+        // The separator keeps an answer from colliding with a shorter answer plus messages
+        hasher.update([0u8]);
+        hasher.update(Value::Array(canonical).to_string().as_bytes());
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Copy of `value` with every object's keys in sorted order, whatever map ordering serde_json
+/// was built with
+fn sort_json_keys(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key.clone(), sort_json_keys(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.iter().map(sort_json_keys).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Serialize messages to a JSON array, gzip it, and base64-encode the result.
+/// Clients reverse this with base64-decode, gunzip, then JSON-parse.
+fn compress_messages(messages: &[HashMap<String, Value>]) -> std::io::Result<String> {
+    use base64::Engine;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let json = serde_json::to_vec(messages)?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&json)?;
+    let compressed = encoder.finish()?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(compressed))
 }
 
 fn build_codex_output(
     result: &codex::CodexResult,
     return_all_messages: bool,
+    compress_output: bool,
     warnings: Option<String>,
 ) -> CodexOutput {
+    // Fall back to inline messages in the (practically impossible) case compression fails
+    let all_messages_gzip_b64 = (return_all_messages && compress_output)
+        .then(|| compress_messages(&result.all_messages).ok())
+        .flatten();
+    let inline_messages = return_all_messages && all_messages_gzip_b64.is_none();
+
     CodexOutput {
+        schema_version: OUTPUT_SCHEMA_VERSION,
         success: result.success,
         session_id: result.session_id.clone(),
         agent_messages: result.agent_messages.clone(),
         agent_messages_truncated: result.agent_messages_truncated.then_some(true),
-        all_messages: return_all_messages.then_some(result.all_messages.clone()),
+        all_messages: inline_messages.then(|| result.all_messages.clone()),
         all_messages_truncated: (return_all_messages && result.all_messages_truncated)
             .then_some(true),
+        all_messages_gzip_b64,
         error: result.error.clone(),
         warnings,
+        first_event: result.first_event.clone(),
+        last_event: result.last_event.clone(),
+        retry_after_secs: result.retry_after_secs,
+        resource_usage: result.resource_usage,
+        errors: result.errors.clone(),
+        raw_stdout: result.raw_stdout.clone(),
+        raw_stdout_truncated: result.raw_stdout_truncated.then_some(true),
+        interrupted: result.interrupted.then_some(true),
+        effective_message_limit: result.effective_message_limit,
+        todos: result.todos.clone(),
+        agents_md_applied: result.agents_md_applied(),
+        agents_md_path: result
+            .agents_md_path
+            .as_ref()
+            .map(|path| path.to_string_lossy().into_owned()),
+        agent_messages_plain: result.agent_messages_plain.clone(),
+        message_count: result.message_count,
+        attempts: result.attempts,
+        git_before: result.git_before.clone(),
+        git_after: result.git_after.clone(),
+        final_answer: result.final_answer.clone(),
+        exit_code: result.exit_code,
+        ttft_ms: result.ttft_ms,
+        verification: result.verification.clone(),
+        fingerprint: None,
+        output_images: result.output_images.clone(),
+        cd: None,
+        requested_cd: None,
+        security_downgrades: None,
     }
 }
 
-#[derive(Clone)]
-pub struct CodexServer {
-    tool_router: ToolRouter<CodexServer>,
+/// Retriever that refuses every external `$ref`, so a client-supplied response_schema cannot
+/// make the server fetch URLs or read local files
+struct NoExternalRefs;
+
+impl jsonschema::Retrieve for NoExternalRefs {
+    fn retrieve(
+        &self,
+        uri: &jsonschema::Uri<&str>,
+    ) -> Result<Value, Box<dyn std::error::Error + Send + Sync>> {
+        Err(format!("external references are not allowed: {}", uri.as_str()).into())
+    }
+}
+
+fn compile_response_schema(schema: &Value) -> Result<jsonschema::Validator, McpError> {
+    jsonschema::options()
+        .with_retriever(NoExternalRefs)
+        .build(schema)
+        .map_err(|e| {
+            McpError::invalid_params(
+                format!("response_schema is not a valid JSON schema: {}", e),
+                None,
+            )
+        })
+}
+
+/// Validate the agent's answer against the response schema.
+/// Returns a human-readable description of every violation when it does not conform.
+fn check_response_schema(
+    validator: &jsonschema::Validator,
+    agent_messages: &str,
+) -> Result<(), String> {
+    let answer: Value = serde_json::from_str(agent_messages.trim()).map_err(|e| {
+        format!(
+            "agent_messages is not valid JSON, cannot check response_schema: {}",
+            e
+        )
+    })?;
+
+    let violations: Vec<String> = validator
+        .iter_errors(&answer)
+        .map(|e| format!("{} (at '{}')", e, e.instance_path))
+        .collect();
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "agent_messages does not conform to response_schema: {}",
+            violations.join("; ")
+        ))
+    }
+}
+
+/// Output from the plan tool
+#[derive(Debug, Serialize, schemars::JsonSchema)]
+struct PlanOutput {
+    success: bool,
+    #[serde(rename = "SESSION_ID")]
+    session_id: String,
+    steps: Vec<String>,
+    agent_messages: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warnings: Option<String>,
+}
+
+/// How long the model list read from codex's config is reused before reading it again
+const MODEL_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Input parameters for the diff_sessions tool
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct DiffSessionsArgs {
+    /// Session whose final answer is the old side of the diff
+    #[serde(rename = "SESSION_ID_A")]
+    pub session_id_a: String,
+    /// Session whose final answer is the new side of the diff
+    #[serde(rename = "SESSION_ID_B")]
+    pub session_id_b: String,
+}
+
+/// Output from the diff_sessions tool
+#[derive(Debug, Serialize, schemars::JsonSchema)]
+pub struct DiffSessionsOutput {
+    /// Whether the two final answers differ
+    pub differ: bool,
+    /// Unified diff of the final answers, empty when they are identical
+    pub diff: String,
+}
+
+/// Diff the final agent messages of two sessions whose transcripts live under `sessions_dir`
+pub fn diff_session_answers(
+    sessions_dir: &Path,
+    args: &DiffSessionsArgs,
+) -> Result<DiffSessionsOutput, McpError> {
+    let read = |session_id: &str| {
+        session::read_final_message(sessions_dir, session_id)
+            .map_err(|e| McpError::invalid_params(e, None))
+    };
+    let answer_a = read(&args.session_id_a)?;
+    let answer_b = read(&args.session_id_b)?;
+
+    let diff = similar::TextDiff::from_lines(&answer_a, &answer_b)
+        .unified_diff()
+        .header(&args.session_id_a, &args.session_id_b)
+        .to_string();
+    Ok(DiffSessionsOutput {
+        differ: answer_a != answer_b,
+        diff,
+    })
+}
+
+/// Input parameters for the export_session tool
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ExportSessionArgs {
+    /// Session to export
+    #[serde(rename = "SESSION_ID")]
+    pub session_id: String,
+    /// Include Codex's reasoning summaries (default: false)
+    #[serde(default)]
+    pub include_reasoning: bool,
+}
+
+/// Output from the export_session tool
+#[derive(Debug, Serialize, schemars::JsonSchema)]
+pub struct ExportSessionOutput {
+    /// The session as a Markdown document, one section per turn
+    pub markdown: String,
+    /// Whether the document was cut off at MAX_EXPORT_BYTES
+    pub truncated: bool,
+}
+
+/// Render the transcript of a session stored under `sessions_dir` as Markdown
+pub fn export_session_markdown(
+    sessions_dir: &Path,
+    args: &ExportSessionArgs,
+) -> Result<ExportSessionOutput, McpError> {
+    let transcript = session::read_transcript(sessions_dir, &args.session_id)
+        .map_err(|e| McpError::invalid_params(e, None))?;
+    let (markdown, truncated) = session::transcript_to_markdown(
+        &args.session_id,
+        &transcript,
+        args.include_reasoning,
+        session::MAX_EXPORT_BYTES,
+    );
+    Ok(ExportSessionOutput {
+        markdown,
+        truncated,
+    })
+}
+
+/// Input parameters for the batch tool
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchArgs {
+    /// Instruction sent to codex in every directory
+    #[serde(rename = "PROMPT")]
+    pub prompt: String,
+    /// Working directories to run the task in, one codex run each
+    pub cds: Vec<PathBuf>,
+    /// Sandbox policy for every run. Defaults to the server's CODEX_DEFAULT_SANDBOX
+    #[serde(default)]
+    pub sandbox: Option<SandboxPolicy>,
+    /// Model for every run (subject to CODEX_ALLOWED_MODELS)
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Configuration profile for every run
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Timeout in seconds for each run, with the same default and cap as the codex tool
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Allow runs outside a Git repository
+    #[serde(default)]
+    pub skip_git_repo_check: bool,
+}
+
+impl BatchArgs {
+    /// Arguments for the single codex run in `cd`
+    fn entry_args(&self, cd: &Path) -> CodexArgs {
+        CodexArgs {
+            prompt: self.prompt.clone(),
+            cd: cd.to_path_buf(),
+            sandbox: self.sandbox.clone(),
+            model: self.model.clone(),
+            profile: self.profile.clone(),
+            timeout_secs: self.timeout_secs,
+            skip_git_repo_check: self.skip_git_repo_check,
+            ..Default::default()
+        }
+    }
+}
+
+/// Result of one directory in a batch. `error` is set instead of `result` when the entry failed
+/// validation or codex could not be run
+#[derive(Debug, Serialize)]
+pub struct BatchEntryOutput {
+    pub cd: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<CodexOutput>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchEntryOutput {
+    /// Whether codex ran in this directory and reported success
+    pub fn success(&self) -> bool {
+        self.result.as_ref().is_some_and(|output| output.success)
+    }
+
+    /// The agent's answer for this directory, if codex ran
+    pub fn agent_messages(&self) -> Option<&str> {
+        self.result
+            .as_ref()
+            .map(|output| output.agent_messages.as_str())
+    }
+}
+
+/// Output from the batch tool, with one entry per requested directory in request order
+#[derive(Debug, Serialize)]
+pub struct BatchOutput {
+    pub results: Vec<BatchEntryOutput>,
+}
+
+/// Environment variable limiting how many codex processes this server runs at once
+const MAX_CONCURRENCY_ENV: &str = "CODEX_MAX_CONCURRENCY";
+
+/// Pure function to resolve the concurrency limit from an environment variable result.
+/// Unset means no limit; values that are not positive numbers are ignored with a warning.
+fn resolve_max_concurrency(
+    env_result: Result<String, std::env::VarError>,
+) -> (usize, Option<String>) {
+    let unlimited = tokio::sync::Semaphore::MAX_PERMITS;
+    let val = match env_result {
+        Ok(val) if !val.trim().is_empty() => val,
+        _ => return (unlimited, None),
+    };
+    match val.trim().parse::<usize>() {
+        Ok(limit) if limit > 0 => (limit.min(unlimited), None),
+        _ => (
+            unlimited,
+            Some(format!(
+                "{}='{}' is not a positive number; running without a concurrency limit",
+                MAX_CONCURRENCY_ENV,
+                val.trim()
+            )),
+        ),
+    }
+}
+
+/// Environment variable setting the maximum random delay before each codex spawn
+const SPAWN_JITTER_ENV: &str = "CODEX_SPAWN_JITTER_MS";
+
+/// Upper bound for CODEX_SPAWN_JITTER_MS, so a typo can't stall every call
+pub const MAX_SPAWN_JITTER_MS: u64 = 10_000;
+
+/// Pure function to resolve the spawn jitter from an environment variable result.
+/// Unset or empty means no jitter; unparsable values are ignored and large ones capped, with a warning.
+fn resolve_spawn_jitter_ms(
+    env_result: Result<String, std::env::VarError>,
+) -> (u64, Option<String>) {
+    let val = match env_result {
+        Ok(val) if !val.trim().is_empty() => val,
+        _ => return (0, None),
+    };
+    match val.trim().parse::<u64>() {
+        Ok(ms) if ms > MAX_SPAWN_JITTER_MS => (
+            MAX_SPAWN_JITTER_MS,
+            Some(format!(
+                "{}={} exceeds the maximum; using {} ms",
+                SPAWN_JITTER_ENV, ms, MAX_SPAWN_JITTER_MS
+            )),
+        ),
+        Ok(ms) => (ms, None),
+        Err(_) => (
+            0,
+            Some(format!(
+                "{}='{}' is not a number of milliseconds; spawning without jitter",
+                SPAWN_JITTER_ENV,
+                val.trim()
+            )),
+        ),
+    }
+}
+
+/// Environment variable setting how long a writable run waits for the working directory lock
+const DIR_LOCK_WAIT_ENV: &str = "CODEX_DIR_LOCK_WAIT_SECS";
+
+/// Pure function to resolve the directory lock wait from an environment variable result.
+/// Unset or empty uses the default; unparsable values fall back to it with a warning.
+fn resolve_dir_lock_wait_secs(
+    env_result: Result<String, std::env::VarError>,
+) -> (u64, Option<String>) {
+    let val = match env_result {
+        Ok(val) if !val.trim().is_empty() => val,
+        _ => return (DEFAULT_DIR_LOCK_WAIT_SECS, None),
+    };
+    match val.trim().parse::<u64>() {
+        Ok(secs) => (secs, None),
+        Err(_) => (
+            DEFAULT_DIR_LOCK_WAIT_SECS,
+            Some(format!(
+                "{}='{}' is not a number of seconds; using default of {} seconds",
+                DIR_LOCK_WAIT_ENV,
+                val.trim(),
+                DEFAULT_DIR_LOCK_WAIT_SECS
+            )),
+        ),
+    }
+}
+
+/// Environment variable making a codex older than MIN_CODEX_VERSION a startup error
+const REQUIRE_MIN_VERSION_ENV: &str = "CODEX_REQUIRE_MIN_VERSION";
+
+/// Environment variable enabling LoggingEventSink
+const LOG_EVENTS_ENV: &str = "CODEX_LOG_EVENTS";
+
+/// Output from the server_config tool: the effective limits and security settings, resolved
+/// the same way as for a codex call. Secrets are only reported as being set or not
+#[derive(Debug, Serialize, schemars::JsonSchema)]
+pub struct ServerConfigOutput {
+    codex_version: Option<String>,
+    default_timeout_secs: u64,
+    max_timeout_secs: u64,
+    default_sandbox: SandboxPolicy,
+    max_prompt_bytes: usize,
+    max_images: u64,
+    max_image_bytes: u64,
+    agents_md_max_bytes: u64,
+    agents_only_prompt: bool,
+    max_message_limit: usize,
+    max_concurrency: usize,
+    spawn_jitter_ms: u64,
+    dir_lock_wait_secs: u64,
+    security: SecurityConfig,
+    capability_tokens_enabled: bool,
+    /// Problems with the configuration, e.g. unparsable environment values
+    warnings: Vec<String>,
+}
+
+/// Output from the list_models tool
+#[derive(Debug, Serialize, schemars::JsonSchema)]
+struct ListModelsOutput {
+    models: Vec<codex::ModelInfo>,
+}
+
+/// Timeout for the warmup tool's no-op run
+const WARMUP_TIMEOUT_SECS: u64 = 60;
+
+/// Trivial prompt that makes codex initialize auth and config without touching any files
+const WARMUP_PROMPT: &str = "Reply with the single word: ready";
+
+/// Output from the warmup tool
+#[derive(Debug, Serialize, schemars::JsonSchema)]
+pub struct WarmupOutput {
+    pub ready: bool,
+    pub details: String,
+}
+
+/// Run a read-only no-op task in a throwaway directory to prime codex's caches, and report
+/// whether it answered
+pub async fn run_warmup() -> WarmupOutput {
+    run_warmup_within(WARMUP_TIMEOUT_SECS).await
+}
+
+/// run_warmup with its own timeout, e.g. for the startup probe
+pub async fn run_warmup_within(timeout_secs: u64) -> WarmupOutput {
+    let temp_dir = match CallTempDir::new() {
+        Ok(dir) => dir,
+        Err(e) => {
+            return WarmupOutput {
+                ready: false,
+                details: format!("{:#}", e),
+            }
+        }
+    };
+    let opts = Options::builder(WARMUP_PROMPT, temp_dir.path())
+        .with_skip_git_repo_check(true)
+        .with_timeout_secs(timeout_secs)
+        .build();
+
+    let started = std::time::Instant::now();
+    match codex::run(opts).await {
+        Ok(result) if result.success => WarmupOutput {
+            ready: true,
+            details: format!("codex answered in {} ms", started.elapsed().as_millis()),
+        },
+        Ok(result) => WarmupOutput {
+            ready: false,
+            details: result
+                .error
+                .unwrap_or_else(|| "codex did not complete the warmup task".to_string()),
+        },
+        Err(e) => WarmupOutput {
+            ready: false,
+            details: format!("Failed to run codex: {}", e),
+        },
+    }
+}
+
+/// Extra time granted beyond the codex timeout before the server-side request guard fires
+const REQUEST_GUARD_GRACE_SECS: u64 = 30;
+
+/// Guard limit for a request: the codex timeout plus a grace period, so the normal codex
+/// timeout path always gets to report first
+fn request_guard_duration(timeout_secs: Option<u64>) -> std::time::Duration {
+    let timeout_secs = timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS);
+    std::time::Duration::from_secs(timeout_secs.saturating_add(REQUEST_GUARD_GRACE_SECS))
+}
+
+/// Safety net around a request in case codex::run itself hangs (e.g. the child closed its
+/// output but never exited). Returns an error message when the limit is exceeded.
+async fn guard_request<F: std::future::Future>(
+    limit: std::time::Duration,
+    fut: F,
+) -> Result<F::Output, String> {
+    tokio::time::timeout(limit, fut).await.map_err(|_| {
+        format!(
+            "Request exceeded the server-side guard of {} seconds without codex finishing",
+            limit.as_secs()
+        )
+    })
+}
+
+/// Run codex, mapping execution failures (e.g. spawn errors) to an MCP internal error that
+/// still carries the collected warnings
+async fn run_codex(
+    opts: Options,
+    security_warnings: &[String],
+) -> Result<codex::CodexResult, McpError> {
+    let guard = request_guard_duration(opts.timeout_secs);
+    let error_msg = match guard_request(guard, codex::run(opts)).await {
+        Ok(Ok(result)) => return Ok(result),
+        Ok(Err(e)) => format!("Failed to execute codex: {}", e),
+        Err(guard_msg) => guard_msg,
+    };
+    let warning_text = merge_warnings(security_warnings.to_vec(), None);
+    Err(McpError::internal_error(
+        attach_warnings(error_msg, warning_text),
+        None,
+    ))
+}
+
+/// Build a `file://` URI for an absolute path
+fn file_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    if path.starts_with('/') {
+        format!("file://{}", path)
+    } else {
+        format!("file:///{}", path)
+    }
+}
+
+/// Small inline summary returned next to the resource link, so clients that ignore resource
+/// links still see the outcome and where to find the full output
+#[derive(Debug, Serialize)]
+struct ResourceSummary<'a> {
+    success: bool,
+    #[serde(rename = "SESSION_ID")]
+    session_id: &'a str,
+    resource_uri: &'a str,
+}
+
+fn serialize_tool_output<T: Serialize>(output: &T) -> Result<CallToolResult, McpError> {
+    let json_output = serde_json::to_string(output).map_err(|e| {
+        McpError::internal_error(format!("Failed to serialize output: {}", e), None)
+    })?;
+    Ok(CallToolResult::success(vec![Content::text(json_output)]))
+}
+
+#[derive(Clone)]
+pub struct CodexServer {
+    tool_router: ToolRouter<CodexServer>,
+    /// Messages and events seen so far per session, for return_delta
+    turn_offsets: Arc<Mutex<HashMap<String, TurnOffset>>>,
+    /// Output files written for output_as_resource, by URI. Only these can be read back
+    output_resources: Arc<Mutex<HashMap<String, PathBuf>>>,
+    /// Last successful model discovery, reused for MODEL_CACHE_TTL
+    model_cache: Arc<tokio::sync::Mutex<Option<(std::time::Instant, Vec<codex::ModelInfo>)>>>,
+    /// Security configuration read from the environment once at startup
+    security: SecurityConfig,
+    /// Warnings about invalid security environment values, repeated on every call
+    config_warnings: Vec<String>,
+    /// Secret for verifying per-request capability tokens; tokens are rejected when unset
+    capability_secret: Option<String>,
+    /// Default profile per MCP client name, from CODEX_CLIENT_PROFILE_MAP
+    client_profiles: HashMap<String, String>,
+    /// Client name reported in the initialize handshake
+    client_name: Arc<Mutex<Option<String>>>,
+    /// Limits concurrent codex runs to CODEX_MAX_CONCURRENCY
+    concurrency: Arc<tokio::sync::Semaphore>,
+    /// Permits `concurrency` started with
+    max_concurrency: usize,
+    /// Maximum random delay before each codex spawn, from CODEX_SPAWN_JITTER_MS
+    spawn_jitter_ms: u64,
+    /// How long writable runs wait for the working directory lock, from CODEX_DIR_LOCK_WAIT_SECS
+    dir_lock_wait_secs: u64,
+    /// Installed codex version, detected at startup by check_codex_version
+    codex_version: Option<codex::CodexVersion>,
+    /// Observer for every run; logs to stderr with CODEX_LOG_EVENTS, otherwise a no-op
+    event_sink: Arc<dyn EventSink>,
 }
 
 impl Default for CodexServer {
@@ -307,79 +1971,293 @@ impl CodexServer {
     /// Apply server-side security restrictions based on configuration
     /// Returns the modified args and any warning messages about security downgrades
     pub fn apply_security_restrictions(
+        &self,
+        args: CodexArgs,
+        security: &SecurityConfig,
+    ) -> Result<(CodexArgs, Vec<String>), McpError> {
+        self.apply_security_restrictions_with_flags(args, security)
+            .map(|(args, warnings, _)| (args, warnings))
+    }
+
+    /// apply_security_restrictions, also reporting the main downgrades as flags
+    pub fn apply_security_restrictions_with_flags(
         &self,
         mut args: CodexArgs,
         security: &SecurityConfig,
-    ) -> (CodexArgs, Vec<String>) {
+    ) -> Result<(CodexArgs, Vec<String>, SecurityDowngrades), McpError> {
         let mut warnings = Vec::new();
+        let mut downgrades = SecurityDowngrades::default();
 
         // Restrict dangerous sandbox mode unless explicitly allowed
-        if !security.allow_danger_full_access && args.sandbox == SandboxPolicy::DangerFullAccess {
+        if !security.allow_danger_full_access
+            && args.sandbox == Some(SandboxPolicy::DangerFullAccess)
+        {
+            if security.strict_security {
+                return Err(strict_security_error(
+                    "danger-full-access sandbox mode",
+                    "CODEX_ALLOW_DANGEROUS",
+                ));
+            }
             warnings.push("Security warning: danger-full-access sandbox mode was downgraded to read-only. Set CODEX_ALLOW_DANGEROUS=true to enable.".to_string());
-            args.sandbox = SandboxPolicy::ReadOnly;
+            args.sandbox = Some(SandboxPolicy::ReadOnly);
+            downgrades.sandbox_downgraded = true;
         }
 
         // Restrict yolo mode unless explicitly allowed
         if !security.allow_yolo && args.yolo {
+            if security.strict_security {
+                return Err(strict_security_error("yolo mode", "CODEX_ALLOW_YOLO"));
+            }
             warnings.push(
                 "Security warning: yolo mode was disabled. Set CODEX_ALLOW_YOLO=true to enable."
                     .to_string(),
             );
             args.yolo = false;
+            downgrades.yolo_disabled = true;
         }
 
         // Restrict git repo skip unless explicitly allowed
         if !security.allow_skip_git_check && args.skip_git_repo_check {
             warnings.push("Security warning: skip_git_repo_check was disabled. Set CODEX_ALLOW_SKIP_GIT_CHECK=true to enable.".to_string());
             args.skip_git_repo_check = false;
+            downgrades.git_check_disabled = true;
+        }
+
+        // full_auto approves commands without asking, so it shares yolo's gate
+        if !security.allow_yolo && args.full_auto {
+            if security.strict_security {
+                return Err(strict_security_error("full_auto", "CODEX_ALLOW_YOLO"));
+            }
+            warnings.push(
+                "Security warning: full_auto was disabled. Set CODEX_ALLOW_YOLO=true to enable."
+                    .to_string(),
+            );
+            args.full_auto = false;
+        }
+
+        // Running arbitrary commands on the server requires an explicit opt-in
+        if !security.allow_post_process && args.post_process_cmd.is_some() {
+            warnings.push("Security warning: post_process_cmd was ignored. Set CODEX_ALLOW_POST_PROCESS=true to enable.".to_string());
+            args.post_process_cmd = None;
+        }
+
+        // Headers can carry credentials or redirect billing, so they are opt-in too
+        if !security.allow_provider_headers && !args.provider_headers.is_empty() {
+            warnings.push("Security warning: provider_headers were ignored. Set CODEX_ALLOW_PROVIDER_HEADERS=true to enable.".to_string());
+            args.provider_headers.clear();
+        }
+
+        if !security.allow_verify_cmd && args.verify_cmd.is_some() {
+            warnings.push("Security warning: verify_cmd was ignored. Set CODEX_ALLOW_VERIFY_CMD=true to enable.".to_string());
+            args.verify_cmd = None;
         }
 
-        (args, warnings)
+        Ok((args, warnings, downgrades))
     }
 
-    pub fn new() -> Self {
-        Self {
-            tool_router: Self::tool_router(),
+    /// prepare_options_with_downgrades on the blocking thread pool. Validation runs `git status`
+    /// for the dirty-tree check, which must not stall the async tool handlers
+    async fn prepare_options_off_runtime(
+        &self,
+        args: CodexArgs,
+    ) -> Result<(Options, Vec<String>, SecurityDowngrades), McpError> {
+        let server = self.clone();
+        tokio::task::spawn_blocking(move || server.prepare_options_with_downgrades(args))
+            .await
+            .map_err(|e| {
+                McpError::internal_error(format!("Failed to validate arguments: {}", e), None)
+            })?
+    }
+
+    /// Security configuration for one request. A valid capability token allows the
+    /// danger-full-access sandbox and yolo regardless of the environment gates; an invalid or
+    /// expired one is ignored with a warning. The token is checked against the canonical
+    /// working directory, so it must be called with the decoded prompt
+    fn request_security(&self, args: &mut CodexArgs) -> (SecurityConfig, Option<String>) {
+        let mut security = self.security.clone();
+        let Some(token) = args.capability_token.take() else {
+            return (security, None);
+        };
+        let Some(secret) = &self.capability_secret else {
+            return (
+                security,
+                Some(format!("Security warning: capability_token was not accepted because {} is not set; the server's security settings apply.", CAPABILITY_SECRET_ENV)),
+            );
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        let verified = expand_home(&args.cd)
+            .canonicalize()
+            .map_err(|_| "cd does not exist")
+            .and_then(|cd| verify_capability_token(secret, &cd, &args.prompt, &token, now));
+        match verified {
+            Ok(()) => {
+                security.allow_danger_full_access = true;
+                security.allow_yolo = true;
+                (security, None)
+            }
+            Err(reason) => (
+                security,
+                Some(format!("Security warning: capability_token was not accepted because {}; the server's security settings apply.", reason)),
+            ),
         }
     }
-}
 
-#[tool_router]
-impl CodexServer {
-    /// Executes a non-interactive Codex session via CLI to perform AI-assisted coding tasks in a secure workspace.
-    /// This tool wraps the 'codex exec' command, enabling model-driven code generation, debugging, or automation based on natural language prompts.
-    /// It supports resuming ongoing sessions for continuity and enforces sandbox policies to prevent unsafe operations.
-    #[tool(
-        name = "codex",
-        description = "Execute Codex CLI for AI-assisted coding tasks"
-    )]
-    async fn codex(
+    /// Validate tool arguments, apply security restrictions and timeout limits, and build the
+    /// options for a codex run. Returns the options plus any warnings collected along the way.
+    pub fn prepare_options(&self, args: CodexArgs) -> Result<(Options, Vec<String>), McpError> {
+        self.prepare_options_with_downgrades(args)
+            .map(|(opts, warnings, _)| (opts, warnings))
+    }
+
+    /// prepare_options, also reporting which security downgrades were applied
+    pub fn prepare_options_with_downgrades(
         &self,
-        Parameters(args): Parameters<CodexArgs>,
-    ) -> Result<CallToolResult, McpError> {
-        // Get security configuration
-        let mut security_warnings = Vec::new();
-        let security = get_security_config(&mut security_warnings);
+        args: CodexArgs,
+    ) -> Result<(Options, Vec<String>, SecurityDowngrades), McpError> {
+        let mut security_warnings = self.config_warnings.clone();
+
+        let mut args = args;
+        // Move to the repository root first, so a capability token is checked against the
+        // directory Codex will actually run in
+        if args.use_git_root {
+            let cd = expand_home(&args.cd);
+            match cd
+                .canonicalize()
+                .ok()
+                .and_then(|dir| crate::git::repo_root(&dir))
+            {
+                Some(root) => args.cd = root,
+                None => security_warnings.push(format!(
+                    "use_git_root: {} is not inside a git repository; running in cd",
+                    cd.display()
+                )),
+            }
+        }
+        if args.prompt.is_empty() {
+            if let Some(encoded) = args.prompt_b64.take() {
+                args.prompt = decode_prompt_b64(&encoded)?;
+            }
+        }
+        if let Some(template) = args.template.take() {
+            if !args.prompt.is_empty() {
+                return Err(McpError::invalid_params(
+                    "provide either PROMPT (or prompt_b64) or template, not both",
+                    None,
+                ));
+            }
+            args.prompt = expand_template(&template, &args.variables, args.allow_unresolved)?;
+        }
 
         // Validate required parameters
+        let agents_only_prompt =
+            parse_env_bool(AGENTS_ONLY_PROMPT_ENV, &mut security_warnings).unwrap_or(false);
+        let (default_agents_max, agents_max_warning) = resolve_positive_limit(
+            AGENTS_ABSOLUTE_MAX_ENV,
+            std::env::var(AGENTS_ABSOLUTE_MAX_ENV),
+            codex::MAX_AGENTS_MD_SIZE,
+        );
+        security_warnings.extend(agents_max_warning);
+        let agents_absolute_max = args.agents_absolute_max.unwrap_or(default_agents_max);
+        check_prompt_present(
+            &args.prompt,
+            agents_only_prompt,
+            &expand_home(&args.cd),
+            codex::agents_md_size_limit(Some(agents_absolute_max)),
+        )?;
         if args.prompt.is_empty() {
+            // AGENTS.md is the whole task, so it has to go in the prompt itself
+            args.agents_as_system_flag = false;
+        }
+
+        let (max_prompt_bytes, prompt_cap_warning) =
+            resolve_max_prompt_bytes(std::env::var(MAX_PROMPT_BYTES_ENV));
+        security_warnings.extend(prompt_cap_warning);
+        check_prompt_size(&args.prompt, max_prompt_bytes)?;
+
+        if args.cd.as_os_str().is_empty() {
             return Err(McpError::invalid_params(
-                "PROMPT is required and must be a non-empty string",
+                "cd is required and must be a non-empty string",
                 None,
             ));
         }
 
-        if args.cd.as_os_str().is_empty() {
+        if args
+            .post_process_cmd
+            .as_ref()
+            .is_some_and(|cmd| cmd.is_empty())
+        {
             return Err(McpError::invalid_params(
-                "cd is required and must be a non-empty string",
+                "post_process_cmd must name a command when provided",
+                None,
+            ));
+        }
+
+        if args
+            .stop_sequences
+            .iter()
+            .any(|sequence| sequence.is_empty())
+        {
+            return Err(McpError::invalid_params(
+                "stop_sequences must not contain empty strings",
+                None,
+            ));
+        }
+
+        if args.verify_cmd.as_ref().is_some_and(|cmd| cmd.is_empty()) {
+            return Err(McpError::invalid_params(
+                "verify_cmd must name a command when provided",
                 None,
             ));
         }
 
+        check_provider_headers(&args.provider_headers)?;
+        codex::stdout_encoding_for_label(args.stdout_encoding.as_deref())
+            .map_err(|message| McpError::invalid_params(message, None))?;
+        let (security, token_warning) = self.request_security(&mut args);
+        security_warnings.extend(token_warning);
+        let security = &security;
+
+        let (model, normalize_warning) = normalize_model(args.model.take());
+        security_warnings.extend(normalize_warning);
+        let (model, model_warning) = apply_model_allowlist(
+            model,
+            security.allowed_models.as_deref(),
+            security.downgrade_disallowed_models,
+        )?;
+        args.model = model;
+        security_warnings.extend(model_warning);
+        security_warnings.extend(oss_model_warning(args.oss, args.model.as_deref()));
+
+        // An explicit profile wins over the one mapped to the connected client
+        if args.profile.is_none() {
+            args.profile = self.client_profile();
+        }
+
+        // Fill in the server-wide default sandbox before gating, so it is restricted like any
+        // client choice
+        let requested_sandbox = args.sandbox.clone();
+        security_warnings.extend(apply_default_sandbox(
+            &mut args,
+            std::env::var(DEFAULT_SANDBOX_ENV),
+        ));
+
         // Apply security restrictions
-        let (mut args, restriction_warnings) = self.apply_security_restrictions(args, &security);
+        let (mut args, restriction_warnings, downgrades) =
+            self.apply_security_restrictions_with_flags(args, security)?;
         security_warnings.extend(restriction_warnings);
 
+        security_warnings.extend(expand_full_auto(&mut args, requested_sandbox.as_ref()));
+
+        if args.force_new_session && args.session_id.take().is_some() {
+            security_warnings.push(
+                "SESSION_ID was ignored because force_new_session is set; starting a new session"
+                    .to_string(),
+            );
+        }
+
         // Enforce timeout requirements: always set and within limits
         // Only parse env var when we actually need the default (None or Some(0))
         match args.timeout_secs {
@@ -403,12 +2281,13 @@ impl CodexServer {
                 }
                 args.timeout_secs = Some(default_result.value);
             }
-            Some(timeout) if timeout > MAX_TIMEOUT_SECS => {
+            Some(timeout) if timeout > codex::max_timeout_secs(security.allow_long_timeout) => {
+                let max_timeout = codex::max_timeout_secs(security.allow_long_timeout);
                 security_warnings.push(format!(
                     "Timeout of {} seconds exceeds maximum of {} seconds; capping to maximum",
-                    timeout, MAX_TIMEOUT_SECS
+                    timeout, max_timeout
                 ));
-                args.timeout_secs = Some(MAX_TIMEOUT_SECS);
+                args.timeout_secs = Some(max_timeout);
             }
             Some(_) => {
                 // Valid timeout within range
@@ -416,7 +2295,7 @@ impl CodexServer {
         }
 
         // Validate working directory exists and is a directory
-        let working_dir = &args.cd;
+        let working_dir = &expand_home(&args.cd);
         let canonical_working_dir = working_dir.canonicalize().map_err(|e| {
             McpError::invalid_params(
                 format!(
@@ -430,20 +2309,45 @@ impl CodexServer {
 
         if !canonical_working_dir.is_dir() {
             return Err(McpError::invalid_params(
-                format!(
-                    "working directory is not a directory: {}",
-                    working_dir.display()
-                ),
+                describe_non_directory_cd(working_dir, &canonical_working_dir),
                 None,
             ));
         }
 
+        let sandbox = args.sandbox.clone().unwrap_or_default();
+        if let Some(warning) = check_dirty_tree(
+            &canonical_working_dir,
+            &sandbox,
+            args.yolo,
+            args.full_auto,
+            security.block_dirty_tree,
+        )? {
+            security_warnings.push(warning);
+        }
+
+        let (max_images, max_images_warning) = resolve_positive_limit(
+            MAX_IMAGES_ENV,
+            std::env::var(MAX_IMAGES_ENV),
+            DEFAULT_MAX_IMAGES,
+        );
+        let (max_image_bytes, max_image_bytes_warning) = resolve_positive_limit(
+            MAX_IMAGE_BYTES_ENV,
+            std::env::var(MAX_IMAGE_BYTES_ENV),
+            DEFAULT_MAX_IMAGE_BYTES,
+        );
+        security_warnings.extend(max_images_warning);
+        security_warnings.extend(max_image_bytes_warning);
+        // Check the count before touching the filesystem
+        check_image_count(args.image.len(), max_images)?;
+
         // Validate image files exist and are files
         let mut canonical_image_paths = Vec::new();
+        let mut image_sizes = Vec::new();
         for img_path in &args.image {
             // Resolve image path relative to working directory first, then canonicalize
+            let img_path = expand_home(img_path);
             let resolved_path = if img_path.is_absolute() {
-                img_path.clone()
+                img_path
             } else {
                 // For relative paths, resolve against the working directory
                 canonical_working_dir.join(img_path)
@@ -467,16 +2371,61 @@ impl CodexServer {
                 ));
             }
 
+            image_sizes.push(canonical.metadata().map(|m| m.len()).unwrap_or(0));
             canonical_image_paths.push(canonical);
         }
+        check_image_limits(&image_sizes, max_images, max_image_bytes)?;
+
+        // Context files are resolved and checked like images, then read into the prompt
+        let mut context_files = Vec::new();
+        for context_path in &args.context_files {
+            let context_path = expand_home(context_path);
+            let resolved_path = if context_path.is_absolute() {
+                context_path
+            } else {
+                canonical_working_dir.join(context_path)
+            };
+            let canonical = resolved_path.canonicalize().map_err(|e| {
+                McpError::invalid_params(
+                    format!(
+                        "context file does not exist or is not accessible: {} ({})",
+                        resolved_path.display(),
+                        e
+                    ),
+                    None,
+                )
+            })?;
+            if !canonical.is_file() {
+                return Err(McpError::invalid_params(
+                    format!("context path is not a file: {}", resolved_path.display()),
+                    None,
+                ));
+            }
+            let name = canonical
+                .strip_prefix(&canonical_working_dir)
+                .unwrap_or(&canonical)
+                .display()
+                .to_string();
+            context_files.push(read_context_file(&canonical, name, MAX_CONTEXT_FILE_BYTES)?);
+        }
+        let prompt = if context_files.is_empty() {
+            args.prompt
+        } else {
+            let (prompt, context_warnings) =
+                inject_context_files(&args.prompt, &context_files, MAX_CONTEXT_TOTAL_BYTES);
+            security_warnings.extend(context_warnings);
+            prompt
+        };
 
         // Create options for codex client
         let opts = Options {
-            prompt: args.prompt,
+            prompt,
             working_dir: canonical_working_dir,
-            sandbox: args.sandbox,
+            sandbox,
             session_id: args.session_id,
-            skip_git_repo_check: args.skip_git_repo_check,
+            skip_git_repo_check: args.skip_git_repo_check
+                || (security.auto_skip_git_check
+                    && !crate::git::is_git_repo(&canonical_working_dir)),
             return_all_messages: args.return_all_messages,
             return_all_messages_limit: args.return_all_messages_limit,
             image_paths: canonical_image_paths,
@@ -484,31 +2433,614 @@ impl CodexServer {
             yolo: args.yolo,
             profile: args.profile,
             timeout_secs: args.timeout_secs,
+            idle_timeout_secs: args.idle_timeout_secs,
+            return_boundary_events: args.return_boundary_events,
+            color: args.color,
+            agents_as_system_flag: args.agents_as_system_flag,
+            annotate_timestamps: args.annotate_timestamps,
+            max_retries: args.max_retries.unwrap_or(0),
+            agent_message_join: args.agent_message_join,
+            delta_offset: Default::default(),
+            utf8_mode: Default::default(),
+            post_process_cmd: args.post_process_cmd,
+            success_event_types: Vec::new(),
+            capture_resource_usage: args.capture_resource_usage,
+            full_auto: args.full_auto,
+            return_raw_stdout: args.return_raw_stdout,
+            return_on_first_message: args.return_on_first_message,
+            replay_file: None,
+            normalize_ids: args.normalize_ids,
+            allow_long_timeout: security.allow_long_timeout,
+            return_todos: args.return_todos,
+            use_login_shell: args.use_login_shell,
+            spawn_jitter_ms: self.spawn_jitter_ms,
+            plain_text_output: args.plain_text_output,
+            dir_lock_wait_secs: self.dir_lock_wait_secs,
+            after_message_index: args.after_message_index,
+            all_messages_exclude_types: args.all_messages_exclude_types,
+            all_messages_include_types: args.all_messages_include_types,
+            capture_git_head: args.capture_git_head,
+            provider_headers: args.provider_headers.into_iter().collect(),
+            agent_truncate_from: args.agent_truncate_from,
+            stdout_encoding: args.stdout_encoding,
+            agents_absolute_max: Some(agents_absolute_max),
+            agent_messages_sink: None,
+            agent_messages_sink_only: false,
+            cancel: None,
+            event_sink: Some(self.event_sink.clone()),
+            oss: args.oss,
+            verify_cmd: args.verify_cmd,
+            verify_affects_success: args.verify_affects_success,
+            agent_message_strip_prefix: None,
+            stop_sequences: args.stop_sequences,
+            return_output_images: args.return_output_images,
+            agents_read_error_fatal: false,
         };
 
-        // Execute codex
-        let result = match codex::run(opts).await {
-            Ok(r) => r,
-            Err(e) => {
-                let warning_text = merge_warnings(security_warnings.clone(), None);
-                let error_msg =
-                    attach_warnings(format!("Failed to execute codex: {}", e), warning_text);
-                return Err(McpError::internal_error(error_msg, None));
-            }
-        };
-
-        let combined_warnings = merge_warnings(security_warnings.clone(), result.warnings.clone());
+        Ok((opts, security_warnings, downgrades))
+    }
 
-        // Prepare the response
-        let output = build_codex_output(&result, args.return_all_messages, combined_warnings);
+    /// Write the output to a JSON file under the temp base directory and return a resource
+    /// link to it, remembering the file so resources/read can serve it
+    fn output_as_resource(&self, output: &CodexOutput) -> Result<CallToolResult, McpError> {
+        let json = serde_json::to_string(output).map_err(|e| {
+            McpError::internal_error(format!("Failed to serialize output: {}", e), None)
+        })?;
+        let dir = temp_base_dir();
+        let path = dir.join(format!("codex-output-{}.json", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)
+            .and_then(|_| std::fs::write(&path, &json))
+            .map_err(|e| {
+                McpError::internal_error(
+                    format!("Failed to write output to {}: {}", path.display(), e),
+                    None,
+                )
+            })?;
 
-        let json_output = serde_json::to_string(&output).map_err(|e| {
+        let uri = file_uri(&path);
+        self.output_resources
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(uri.clone(), path);
+
+        let summary = serde_json::to_string(&ResourceSummary {
+            success: output.success,
+            session_id: &output.session_id,
+            resource_uri: &uri,
+        })
+        .map_err(|e| {
             McpError::internal_error(format!("Failed to serialize output: {}", e), None)
         })?;
 
+        let mut resource = RawResource::new(uri, "codex-output");
+        resource.mime_type = Some("application/json".to_string());
+        Ok(CallToolResult::success(vec![
+            Content::text(summary),
+            Annotated::new(RawContent::ResourceLink(resource), None),
+        ]))
+    }
+
+    /// Read back an output file previously written for output_as_resource
+    pub fn read_output_resource(&self, uri: &str) -> Result<String, McpError> {
+        let path = self
+            .output_resources
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(uri)
+            .cloned()
+            .ok_or_else(|| {
+                McpError::resource_not_found(format!("unknown resource: {}", uri), None)
+            })?;
+        std::fs::read_to_string(&path).map_err(|e| {
+            McpError::resource_not_found(
+                format!("resource {} is no longer available: {}", uri, e),
+                None,
+            )
+        })
+    }
+
+    /// Models configured for codex, cached for MODEL_CACHE_TTL. An empty list is not cached,
+    /// so the next call reads the config again.
+    pub async fn models(&self) -> Vec<codex::ModelInfo> {
+        let mut cache = self.model_cache.lock().await;
+        if let Some((fetched_at, models)) = cache.as_ref() {
+            if fetched_at.elapsed() < MODEL_CACHE_TTL {
+                return models.clone();
+            }
+        }
+        let models = codex::list_models();
+        *cache = (!models.is_empty()).then(|| (std::time::Instant::now(), models.clone()));
+        models
+    }
+
+    /// Offset recorded after the last turn of a session, or zero for an unknown session
+    pub fn turn_offset(&self, session_id: &str) -> TurnOffset {
+        let offsets = self.turn_offsets.lock().unwrap_or_else(|e| e.into_inner());
+        offsets.get(session_id).copied().unwrap_or_default()
+    }
+
+    /// Remember how far a session has progressed so the next return_delta call can skip it.
+    /// Counts never move backwards, so a run that died early does not rewind the offset.
+    pub fn record_turn_offset(&self, result: &codex::CodexResult) {
+        if result.session_id.is_empty() {
+            return;
+        }
+        let mut offsets = self.turn_offsets.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = offsets.entry(result.session_id.clone()).or_default();
+        entry.agent_messages = entry.agent_messages.max(result.turn_offset.agent_messages);
+        entry.events = entry.events.max(result.turn_offset.events);
+    }
+
+    /// run_codex, waiting for a slot when CODEX_MAX_CONCURRENCY runs are already in progress
+    async fn run_limited(
+        &self,
+        opts: Options,
+        security_warnings: &[String],
+    ) -> Result<codex::CodexResult, McpError> {
+        let _permit = self.concurrency.acquire().await.map_err(|e| {
+            McpError::internal_error(format!("Concurrency limiter closed: {}", e), None)
+        })?;
+        run_codex(opts, security_warnings).await
+    }
+
+    /// Run one directory of a batch through the same validation and execution as the codex tool
+    async fn run_batch_entry(&self, args: CodexArgs) -> Result<CodexOutput, McpError> {
+        let (opts, security_warnings, _) = self.prepare_options_off_runtime(args).await?;
+        let result = self.run_limited(opts, &security_warnings).await?;
+        self.record_turn_offset(&result);
+        let warnings = merge_warnings(security_warnings, result.warnings.clone());
+        Ok(build_codex_output(&result, false, false, warnings))
+    }
+
+    /// Run the batch prompt in every directory concurrently (subject to CODEX_MAX_CONCURRENCY).
+    /// A failing directory is reported in its entry and does not stop the others.
+    pub async fn run_batch(&self, args: BatchArgs) -> BatchOutput {
+        let mut tasks = tokio::task::JoinSet::new();
+        for (index, cd) in args.cds.iter().enumerate() {
+            let server = self.clone();
+            let entry_args = args.entry_args(cd);
+            tasks.spawn(async move { (index, server.run_batch_entry(entry_args).await) });
+        }
+
+        let mut outcomes: Vec<Option<Result<CodexOutput, String>>> =
+            args.cds.iter().map(|_| None).collect();
+        while let Some(joined) = tasks.join_next().await {
+            if let Ok((index, outcome)) = joined {
+                outcomes[index] = Some(outcome.map_err(|e| e.message.to_string()));
+            }
+        }
+
+        let results = args
+            .cds
+            .iter()
+            .zip(outcomes)
+            .map(|(cd, outcome)| {
+                let outcome =
+                    outcome.unwrap_or_else(|| Err("batch entry task panicked".to_string()));
+                let (result, error) = match outcome {
+                    Ok(output) => (Some(output), None),
+                    Err(e) => (None, Some(e)),
+                };
+                BatchEntryOutput {
+                    cd: cd.to_string_lossy().into_owned(),
+                    result,
+                    error,
+                }
+            })
+            .collect();
+        BatchOutput { results }
+    }
+
+    /// Remember which client connected, so CODEX_CLIENT_PROFILE_MAP can pick its profile
+    pub fn record_client_info(&self, client: &Implementation) {
+        *self.client_name.lock().unwrap_or_else(|e| e.into_inner()) = Some(client.name.clone());
+    }
+
+    /// Profile mapped to the connected client, if any
+    fn client_profile(&self) -> Option<String> {
+        let client_name = self.client_name.lock().unwrap_or_else(|e| e.into_inner());
+        self.client_profiles.get(client_name.as_deref()?).cloned()
+    }
+
+    /// Warnings about invalid security-related environment values found at startup
+    pub fn config_warnings(&self) -> &[String] {
+        &self.config_warnings
+    }
+
+    /// Codex version found by check_codex_version, if it could be determined
+    pub fn codex_version(&self) -> Option<codex::CodexVersion> {
+        self.codex_version
+    }
+
+    /// Check the installed codex against MIN_CODEX_VERSION once at startup and remember its
+    /// version. An older codex adds a warning to every response, or is an error when
+    /// CODEX_REQUIRE_MIN_VERSION is set. A version that cannot be determined is not reported
+    /// here; the first run explains why codex could not start.
+    pub async fn check_codex_version(&mut self) -> Result<Option<String>, String> {
+        let Some(version) = codex::codex_version()
+            .await
+            .ok()
+            .and_then(|line| codex::CodexVersion::parse(&line))
+        else {
+            return Ok(None);
+        };
+        self.codex_version = Some(version);
+        let Some(warning) = codex::version_compatibility_warning(version) else {
+            return Ok(None);
+        };
+        if parse_env_bool(REQUIRE_MIN_VERSION_ENV, &mut self.config_warnings).unwrap_or(false) {
+            return Err(warning);
+        }
+        self.config_warnings.push(warning.clone());
+        Ok(Some(warning))
+    }
+
+    /// Effective configuration for the server_config tool. Per-request settings are read from
+    /// the environment now, exactly as the next codex call would read them
+    pub fn effective_config(&self) -> ServerConfigOutput {
+        let mut warnings = self.config_warnings.clone();
+        let default_timeout = get_default_timeout_with_warning();
+        warnings.extend(default_timeout.warning);
+        let (default_sandbox, sandbox_warning) =
+            resolve_default_sandbox(std::env::var(DEFAULT_SANDBOX_ENV));
+        warnings.extend(sandbox_warning);
+        let (max_prompt_bytes, prompt_warning) =
+            resolve_max_prompt_bytes(std::env::var(MAX_PROMPT_BYTES_ENV));
+        warnings.extend(prompt_warning);
+        let (max_images, max_images_warning) = resolve_positive_limit(
+            MAX_IMAGES_ENV,
+            std::env::var(MAX_IMAGES_ENV),
+            DEFAULT_MAX_IMAGES,
+        );
+        warnings.extend(max_images_warning);
+        let (max_image_bytes, max_image_bytes_warning) = resolve_positive_limit(
+            MAX_IMAGE_BYTES_ENV,
+            std::env::var(MAX_IMAGE_BYTES_ENV),
+            DEFAULT_MAX_IMAGE_BYTES,
+        );
+        warnings.extend(max_image_bytes_warning);
+        let (agents_md_max_bytes, agents_max_warning) = resolve_positive_limit(
+            AGENTS_ABSOLUTE_MAX_ENV,
+            std::env::var(AGENTS_ABSOLUTE_MAX_ENV),
+            codex::MAX_AGENTS_MD_SIZE,
+        );
+        warnings.extend(agents_max_warning);
+        let agents_only_prompt =
+            parse_env_bool(AGENTS_ONLY_PROMPT_ENV, &mut warnings).unwrap_or(false);
+
+        ServerConfigOutput {
+            codex_version: self.codex_version.map(|version| version.to_string()),
+            default_timeout_secs: default_timeout.value,
+            max_timeout_secs: codex::max_timeout_secs(self.security.allow_long_timeout),
+            default_sandbox,
+            max_prompt_bytes,
+            max_images,
+            max_image_bytes,
+            agents_md_max_bytes: codex::agents_md_size_limit(Some(agents_md_max_bytes)),
+            agents_only_prompt,
+            max_message_limit: codex::MAX_MESSAGE_LIMIT,
+            max_concurrency: self.max_concurrency,
+            spawn_jitter_ms: self.spawn_jitter_ms,
+            dir_lock_wait_secs: self.dir_lock_wait_secs,
+            security: self.security.clone(),
+            capability_tokens_enabled: self.capability_secret.is_some(),
+            warnings,
+        }
+    }
+
+    pub fn new() -> Self {
+        let mut config_warnings = Vec::new();
+        let security = get_security_config(&mut config_warnings);
+        config_warnings.extend(session::codex_home().1);
+        let event_sink: Arc<dyn EventSink> =
+            if parse_env_bool(LOG_EVENTS_ENV, &mut config_warnings).unwrap_or(false) {
+                Arc::new(LoggingEventSink)
+            } else {
+                Arc::new(NoopEventSink)
+            };
+        let (client_profiles, profile_map_warning) =
+            resolve_client_profile_map(std::env::var(CLIENT_PROFILE_MAP_ENV));
+        config_warnings.extend(profile_map_warning);
+        let (max_concurrency, concurrency_warning) =
+            resolve_max_concurrency(std::env::var(MAX_CONCURRENCY_ENV));
+        config_warnings.extend(concurrency_warning);
+        let (spawn_jitter_ms, jitter_warning) =
+            resolve_spawn_jitter_ms(std::env::var(SPAWN_JITTER_ENV));
+        config_warnings.extend(jitter_warning);
+        let (dir_lock_wait_secs, dir_lock_warning) =
+            resolve_dir_lock_wait_secs(std::env::var(DIR_LOCK_WAIT_ENV));
+        config_warnings.extend(dir_lock_warning);
+        Self {
+            tool_router: Self::tool_router(),
+            turn_offsets: Arc::new(Mutex::new(HashMap::new())),
+            output_resources: Arc::new(Mutex::new(HashMap::new())),
+            model_cache: Arc::new(tokio::sync::Mutex::new(None)),
+            security,
+            config_warnings,
+            capability_secret: std::env::var(CAPABILITY_SECRET_ENV)
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            client_profiles,
+            client_name: Arc::new(Mutex::new(None)),
+            concurrency: Arc::new(tokio::sync::Semaphore::new(max_concurrency)),
+            max_concurrency,
+            spawn_jitter_ms,
+            dir_lock_wait_secs,
+            codex_version: None,
+            event_sink,
+        }
+    }
+
+    /// Send every run's lifecycle to `sink`, e.g. a metrics or alerting backend
+    pub fn with_event_sink(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.event_sink = sink;
+        self
+    }
+}
+
+#[tool_router]
+impl CodexServer {
+    /// Executes a non-interactive Codex session via CLI to perform AI-assisted coding tasks in a secure workspace.
+    /// This tool wraps the 'codex exec' command, enabling model-driven code generation, debugging, or automation based on natural language prompts.
+    /// It supports resuming ongoing sessions for continuity and enforces sandbox policies to prevent unsafe operations.
+    #[tool(
+        name = "codex",
+        description = "Execute Codex CLI for AI-assisted coding tasks"
+    )]
+    async fn codex(
+        &self,
+        Parameters(args): Parameters<CodexArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        let compress_output = args.compress_output;
+        let always_warnings = args.always_warnings;
+        let output_as_resource = args.output_as_resource;
+        // Compile the schema up front so an invalid schema fails before codex is spawned
+        let schema_validator = args
+            .response_schema
+            .as_ref()
+            .map(compile_response_schema)
+            .transpose()?;
+        let return_delta = args.return_delta;
+        check_output_fields(&args.fields)?;
+        let fields = args.fields.clone();
+        let include_fingerprint = args.include_fingerprint;
+        let requested_cd = args.use_git_root.then(|| args.cd.clone());
+        let (mut opts, security_warnings, downgrades) =
+            self.prepare_options_off_runtime(args).await?;
+        let working_dir = opts.working_dir.clone();
+        let return_all_messages = opts.return_all_messages;
+        if return_delta {
+            if let Some(session_id) = opts.session_id.as_deref() {
+                opts.delta_offset = self.turn_offset(session_id);
+            }
+        }
+
+        // Execute codex
+        let mut result = self.run_limited(opts, &security_warnings).await?;
+        self.record_turn_offset(&result);
+
+        if let Some(ref validator) = schema_validator {
+            if result.success {
+                if let Err(details) = check_response_schema(validator, &result.agent_messages) {
+                    result.success = false;
+                    result.error = Some(details);
+                }
+            }
+        }
+
+        let combined_warnings = finalize_warnings(
+            merge_warnings(security_warnings, result.warnings.clone()),
+            always_warnings,
+        );
+
+        // Prepare the response
+        let mut output = build_codex_output(
+            &result,
+            return_all_messages,
+            compress_output,
+            combined_warnings,
+        );
+        output.security_downgrades = downgrades.any().then_some(downgrades);
+        if let Some(requested_cd) = requested_cd {
+            output.cd = Some(working_dir.display().to_string());
+            output.requested_cd = Some(requested_cd.display().to_string());
+        }
+        if include_fingerprint {
+            output.fingerprint = Some(output_fingerprint(
+                &result.agent_messages,
+                return_all_messages.then_some(result.all_messages.as_slice()),
+            ));
+        }
+
+        if output_as_resource {
+            return self.output_as_resource(&output);
+        }
+
         // Always return structured content so callers can inspect success, error, and warning fields.
-        Ok(CallToolResult::success(vec![Content::text(json_output)]))
+        serialize_tool_output(&select_output_fields(&output, &fields)?)
+    }
+
+    /// Asks Codex for a step-by-step plan of how it would handle a prompt, without acting on it.
+    /// The sandbox is forced to read-only and yolo and full_auto are disabled regardless of the
+    /// arguments given.
+    #[tool(
+        name = "plan",
+        description = "Preview what Codex would do for a prompt as a read-only, step-by-step plan"
+    )]
+    async fn plan(
+        &self,
+        Parameters(args): Parameters<CodexArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        let (mut opts, security_warnings, _) = self
+            .prepare_options_off_runtime(read_only_plan_args(args))
+            .await?;
+        opts.prompt = format!("{}{}", codex::PLAN_INSTRUCTION, opts.prompt);
+
+        let result = self.run_limited(opts, &security_warnings).await?;
+        self.record_turn_offset(&result);
+        let warnings = merge_warnings(security_warnings, result.warnings.clone());
+
+        let output = PlanOutput {
+            success: result.success,
+            session_id: result.session_id.clone(),
+            steps: codex::parse_plan_steps(&result.agent_messages),
+            agent_messages: result.agent_messages.clone(),
+            error: result.error.clone(),
+            warnings,
+        };
+
+        serialize_tool_output(&output)
+    }
+
+    /// Runs one prompt in several directories, e.g. the subprojects of a monorepo, and returns
+    /// the result for each directory. Each entry is validated like a codex call.
+    #[tool(
+        name = "batch",
+        description = "Run the same Codex task in several working directories and return per-directory results"
+    )]
+    async fn batch(
+        &self,
+        Parameters(args): Parameters<BatchArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        if args.cds.is_empty() {
+            return Err(McpError::invalid_params(
+                "cds must list at least one working directory",
+                None,
+            ));
+        }
+        serialize_tool_output(&self.run_batch(args).await)
+    }
+
+    /// Compares two sessions' final answers, e.g. to weigh alternative approaches to one task.
+    /// Sessions are read from Codex's transcripts in $CODEX_HOME/sessions (default ~/.codex).
+    #[tool(
+        name = "diff_sessions",
+        description = "Show a unified diff between the final agent messages of two Codex sessions"
+    )]
+    async fn diff_sessions(
+        &self,
+        Parameters(args): Parameters<DiffSessionsArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        serialize_tool_output(&diff_session_answers(&require_sessions_dir()?, &args)?)
     }
+
+    /// Exports a session as a readable Markdown document for sharing, read from Codex's
+    /// transcript in $CODEX_HOME/sessions (default ~/.codex).
+    #[tool(
+        name = "export_session",
+        description = "Export a Codex session transcript as Markdown: requests, commands run, answers, and optionally reasoning"
+    )]
+    async fn export_session(
+        &self,
+        Parameters(args): Parameters<ExportSessionArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        serialize_tool_output(&export_session_markdown(&require_sessions_dir()?, &args)?)
+    }
+
+    /// Lists the models configured in Codex's config.toml, for building model pickers. The list
+    /// is cached briefly and is empty when the config is missing or names no model.
+    #[tool(
+        name = "list_models",
+        description = "List the models available to Codex"
+    )]
+    async fn list_models(&self) -> Result<CallToolResult, McpError> {
+        serialize_tool_output(&ListModelsOutput {
+            models: self.models().await,
+        })
+    }
+
+    /// Reports the effective configuration (timeouts, size limits, concurrency and security
+    /// settings) for support and debugging. Secrets are never included.
+    #[tool(
+        name = "server_config",
+        description = "Show the server's effective configuration, limits and security settings"
+    )]
+    async fn server_config(&self) -> Result<CallToolResult, McpError> {
+        serialize_tool_output(&self.effective_config())
+    }
+
+    /// Primes Codex's auth and configuration with a trivial read-only run in a temporary
+    /// directory, so the first real call is not slowed down by initialization.
+    #[tool(
+        name = "warmup",
+        description = "Initialize Codex auth and config with a trivial read-only run and report readiness"
+    )]
+    async fn warmup(&self) -> Result<CallToolResult, McpError> {
+        serialize_tool_output(&run_warmup().await)
+    }
+}
+
+/// Codex's sessions directory, or an error explaining why it cannot be located
+fn require_sessions_dir() -> Result<PathBuf, McpError> {
+    session::sessions_dir().ok_or_else(|| {
+        let reason = session::codex_home()
+            .1
+            .unwrap_or_else(|| "the home directory is unknown".to_string());
+        McpError::internal_error(
+            format!("Cannot locate the Codex sessions directory: {}", reason),
+            None,
+        )
+    })
+}
+
+/// Reject provider_headers whose names are not HTTP tokens or whose values could split the header
+fn check_provider_headers(headers: &HashMap<String, String>) -> Result<(), McpError> {
+    for (name, value) in headers {
+        if !codex::is_valid_header_name(name) {
+            return Err(McpError::invalid_params(
+                format!(
+                    "provider_headers name '{}' is not a valid HTTP header name",
+                    name
+                ),
+                None,
+            ));
+        }
+        if value.contains(['\r', '\n', '\0']) {
+            return Err(McpError::invalid_params(
+                format!(
+                    "provider_headers value for '{}' must not contain line breaks or NUL",
+                    name
+                ),
+                None,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Summary of which restricted features this server instance permits
+fn describe_security(security: &SecurityConfig) -> String {
+    let allowed = |flag: bool| if flag { "allowed" } else { "not allowed" };
+    format!(
+        "Security settings:\n- danger-full-access sandbox: {} (CODEX_ALLOW_DANGEROUS)\n- yolo: {} (CODEX_ALLOW_YOLO)\n- skip_git_repo_check: {} (CODEX_ALLOW_SKIP_GIT_CHECK)\n- post_process_cmd: {} (CODEX_ALLOW_POST_PROCESS)\n- writable runs on dirty git trees: {} (CODEX_BLOCK_DIRTY_TREE)\n- models: {} (CODEX_ALLOWED_MODELS)\n- timeouts above {} seconds: {} (CODEX_ALLOW_LONG_TIMEOUT)\n- disallowed danger modes: {} (CODEX_STRICT_SECURITY)\n- provider_headers: {} (CODEX_ALLOW_PROVIDER_HEADERS)\n- verify_cmd: {} (CODEX_ALLOW_VERIFY_CMD)\n- skip_git_repo_check outside git repositories: {} (CODEX_AUTO_SKIP_GIT_CHECK)",
+        allowed(security.allow_danger_full_access),
+        allowed(security.allow_yolo),
+        allowed(security.allow_skip_git_check),
+        allowed(security.allow_post_process),
+        if security.block_dirty_tree { "blocked" } else { "warned" },
+        security
+            .allowed_models
+            .as_ref()
+            .map_or_else(|| "any".to_string(), |models| models.join(", ")),
+        MAX_TIMEOUT_SECS,
+        allowed(security.allow_long_timeout),
+        if security.strict_security { "rejected" } else { "downgraded" },
+        allowed(security.allow_provider_headers),
+        allowed(security.allow_verify_cmd),
+        if security.auto_skip_git_check { "automatic" } else { "on request" },
+    )
+}
+
+/// Server instructions, including the sandbox modes a client can choose from and the
+/// security settings in effect
+fn build_instructions(security: &SecurityConfig) -> String {
+    let mut instructions = String::from("This server provides a codex tool for AI-assisted coding tasks. Use the codex tool to execute coding tasks via the Codex CLI, or the plan tool to preview the steps Codex would take without modifying anything. Call the warmup tool once to initialize Codex ahead of the first real task.\n\nSandbox modes:");
+    for policy in SandboxPolicy::ALL {
+        instructions.push_str(&format!("\n- {}: {}", policy.as_str(), policy.describe()));
+    }
+    instructions.push_str("\n\n");
+    instructions.push_str(&describe_security(security));
+    instructions
 }
 
 #[tool_handler]
@@ -518,10 +3050,34 @@ impl ServerHandler for CodexServer {
             protocol_version: ProtocolVersion::V_2024_11_05,
             capabilities: ServerCapabilities::builder()
                 .enable_tools()
+                .enable_resources()
                 .build(),
             server_info: Implementation::from_build_env(),
-            instructions: Some("This server provides a codex tool for AI-assisted coding tasks. Use the codex tool to execute coding tasks via the Codex CLI.".to_string()),
+            instructions: Some(build_instructions(&self.security)),
+        }
+    }
+
+    async fn initialize(
+        &self,
+        request: InitializeRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<InitializeResult, McpError> {
+        self.record_client_info(&request.client_info);
+        if context.peer.peer_info().is_none() {
+            context.peer.set_peer_info(request);
         }
+        Ok(self.get_info())
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, McpError> {
+        let text = self.read_output_resource(&request.uri)?;
+        Ok(ReadResourceResult {
+            contents: vec![ResourceContents::text(text, request.uri)],
+        })
     }
 }
 
@@ -603,25 +3159,108 @@ mod tests {
         let args = CodexArgs {
             prompt: "test".to_string(),
             cd: PathBuf::from("/tmp"),
-            sandbox: SandboxPolicy::DangerFullAccess,
-            session_id: None,
+            sandbox: Some(SandboxPolicy::DangerFullAccess),
             skip_git_repo_check: true,
-            return_all_messages: false,
-            return_all_messages_limit: None,
-            image: vec![],
-            model: None,
             yolo: true,
-            profile: None,
-            timeout_secs: None,
+            ..Default::default()
         };
         let security = SecurityConfig {
             allow_danger_full_access: false,
             allow_yolo: false,
             allow_skip_git_check: false,
+            block_dirty_tree: false,
+            allow_post_process: false,
+            allowed_models: None,
+            downgrade_disallowed_models: false,
+            allow_long_timeout: false,
+            strict_security: false,
+            allow_provider_headers: false,
+            allow_verify_cmd: false,
+            auto_skip_git_check: false,
         };
 
-        let (_updated, warnings) = server.apply_security_restrictions(args, &security);
+        let (_updated, warnings) = server
+            .apply_security_restrictions(args, &security)
+            .expect("lenient mode downgrades instead of failing");
+        assert_eq!(warnings.len(), 3);
+    }
+
+    #[test]
+    fn apply_security_restrictions_drops_post_process_cmd_unless_allowed() {
+        let server = CodexServer::new();
+        let args = || -> CodexArgs {
+            serde_json::from_value(serde_json::json!({
+                "PROMPT": "test",
+                "cd": "/tmp",
+                "image": [],
+                "post_process_cmd": ["cat"],
+            }))
+            .unwrap()
+        };
+        let security = SecurityConfig {
+            allow_post_process: false,
+            strict_security: false,
+            ..get_security_config(&mut Vec::new())
+        };
+
+        let (updated, warnings) = server
+            .apply_security_restrictions(args(), &security)
+            .unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("CODEX_ALLOW_POST_PROCESS"));
+        assert!(updated.post_process_cmd.is_none());
+
+        let security = SecurityConfig {
+            allow_post_process: true,
+            ..security
+        };
+        let (updated, warnings) = server
+            .apply_security_restrictions(args(), &security)
+            .unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(updated.post_process_cmd, Some(vec!["cat".to_string()]));
+    }
+
+    #[test]
+    fn security_downgrade_flags_match_warnings() {
+        let server = CodexServer::new();
+        let args: CodexArgs = serde_json::from_value(serde_json::json!({
+            "PROMPT": "test",
+            "cd": "/tmp",
+            "sandbox": "danger-full-access",
+            "yolo": true,
+            "skip_git_repo_check": true,
+        }))
+        .unwrap();
+        let mut security = server.security.clone();
+        security.allow_danger_full_access = false;
+        security.allow_yolo = false;
+        security.allow_skip_git_check = false;
+        security.strict_security = false;
+
+        let (_, warnings, downgrades) = server
+            .apply_security_restrictions_with_flags(args, &security)
+            .unwrap();
+        assert_eq!(
+            downgrades,
+            SecurityDowngrades {
+                sandbox_downgraded: true,
+                yolo_disabled: true,
+                git_check_disabled: true,
+            }
+        );
         assert_eq!(warnings.len(), 3);
+        assert!(warnings[0].contains("danger-full-access sandbox mode was downgraded"));
+        assert!(warnings[1].contains("yolo mode was disabled"));
+        assert!(warnings[2].contains("skip_git_repo_check was disabled"));
+
+        let args: CodexArgs =
+            serde_json::from_value(serde_json::json!({"PROMPT": "test", "cd": "/tmp"})).unwrap();
+        let (_, warnings, downgrades) = server
+            .apply_security_restrictions_with_flags(args, &security)
+            .unwrap();
+        assert!(warnings.is_empty());
+        assert!(!downgrades.any());
     }
 
     #[test]
@@ -693,4 +3332,1306 @@ mod tests {
         assert!(result.warning.is_some());
         assert!(result.warning.unwrap().contains("not a valid number"));
     }
+
+    #[test]
+    fn resolve_max_prompt_bytes_parses_and_defaults() {
+        assert_eq!(
+            resolve_max_prompt_bytes(Err(VarError::NotPresent)),
+            (DEFAULT_MAX_PROMPT_BYTES, None)
+        );
+        assert_eq!(resolve_max_prompt_bytes(Ok(" 1024 ".into())), (1024, None));
+
+        let (bytes, warning) = resolve_max_prompt_bytes(Ok("0".into()));
+        assert_eq!(bytes, DEFAULT_MAX_PROMPT_BYTES);
+        assert!(warning.unwrap().contains("not a positive number"));
+    }
+
+    #[test]
+    fn check_prompt_size_accepts_prompts_within_limit() {
+        assert!(check_prompt_size("hello", 5).is_ok());
+        assert!(check_prompt_size(&"x".repeat(1024), DEFAULT_MAX_PROMPT_BYTES).is_ok());
+    }
+
+    #[test]
+    fn check_prompt_size_rejects_oversized_prompts() {
+        let err = check_prompt_size(&"x".repeat(2048), 1024).unwrap_err();
+        assert!(err.message.contains("2048 bytes"));
+        assert!(err.message.contains("limit of 1024 bytes"));
+    }
+
+    #[test]
+    fn context_files_are_injected_into_the_prompt() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("notes.md"), "Use tabs.\n").unwrap();
+        let args: CodexArgs = serde_json::from_value(serde_json::json!({
+            "PROMPT": "Format the code",
+            "cd": dir.path(),
+            "context_files": ["notes.md"],
+        }))
+        .unwrap();
+
+        let (opts, _) = CodexServer::new().prepare_options(args).unwrap();
+        assert_eq!(
+            opts.prompt,
+            "<context file=\"notes.md\">\nUse tabs.\n\n</context>\n\nFormat the code"
+        );
+
+        let missing: CodexArgs = serde_json::from_value(serde_json::json!({
+            "PROMPT": "Format the code",
+            "cd": dir.path(),
+            "context_files": ["missing.md"],
+        }))
+        .unwrap();
+        let err = CodexServer::new().prepare_options(missing).unwrap_err();
+        assert!(err.message.contains("context file does not exist"));
+    }
+
+    #[test]
+    fn use_git_root_runs_codex_at_the_repository_top() {
+        let repo = tempfile::tempdir().unwrap();
+        std::fs::create_dir(repo.path().join(".git")).unwrap();
+        let nested = repo.path().join("crates/core");
+        std::fs::create_dir_all(&nested).unwrap();
+        let plain = tempfile::tempdir().unwrap();
+        let args = |dir: &Path, use_git_root: bool| -> CodexArgs {
+            serde_json::from_value(serde_json::json!({
+                "PROMPT": "test",
+                "cd": dir,
+                "use_git_root": use_git_root,
+            }))
+            .unwrap()
+        };
+        let server = CodexServer::new();
+
+        let (opts, _) = server.prepare_options(args(&nested, true)).unwrap();
+        assert_eq!(opts.working_dir, repo.path().canonicalize().unwrap());
+
+        let (opts, _) = server.prepare_options(args(&nested, false)).unwrap();
+        assert_eq!(opts.working_dir, nested.canonicalize().unwrap());
+
+        let (opts, warnings) = server.prepare_options(args(plain.path(), true)).unwrap();
+        assert_eq!(opts.working_dir, plain.path().canonicalize().unwrap());
+        assert!(warnings
+            .iter()
+            .any(|w| w.contains("is not inside a git repository")));
+    }
+
+    #[test]
+    fn context_files_respect_the_total_cap() {
+        let file = |name: &str, content: &str| ContextFile {
+            name: name.to_string(),
+            content: content.to_string(),
+            truncated: false,
+        };
+        let files = [
+            file("a.txt", "0123456789"),
+            file("b.txt", "abcdef"),
+            file("c.txt", "xyz"),
+        ];
+
+        let (prompt, warnings) = inject_context_files("task", &files, 14);
+        assert_eq!(
+            prompt,
+            "<context file=\"a.txt\">\n0123456789\n</context>\n\n<context file=\"b.txt\">\nabcd\n[... truncated ...]\n</context>\n\ntask"
+        );
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("b.txt was cut off"));
+        assert!(warnings[1].contains("c.txt was left out"));
+
+        let (prompt, warnings) = inject_context_files("task", &files[..1], 14);
+        assert!(prompt.ends_with("0123456789\n</context>\n\ntask"));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn check_image_limits_accepts_images_within_limits() {
+        assert!(check_image_limits(&[], 10, 100).is_ok());
+        assert!(check_image_limits(&[40, 60], 2, 100).is_ok());
+    }
+
+    #[test]
+    fn check_image_limits_rejects_too_many_images() {
+        let err = check_image_limits(&[1, 1, 1], 2, 100).unwrap_err();
+        assert!(err.message.contains("3 images"));
+        assert!(err.message.contains("CODEX_MAX_IMAGES"));
+    }
+
+    #[test]
+    fn check_image_limits_rejects_too_large_total() {
+        let err = check_image_limits(&[60, 60], 10, 100).unwrap_err();
+        assert!(err.message.contains("total 120 bytes"));
+        assert!(err.message.contains("CODEX_MAX_IMAGE_BYTES"));
+    }
+
+    #[test]
+    fn resolve_positive_limit_falls_back_on_invalid_values() {
+        assert_eq!(
+            resolve_positive_limit(MAX_IMAGES_ENV, Err(VarError::NotPresent), 10),
+            (10, None)
+        );
+        assert_eq!(
+            resolve_positive_limit(MAX_IMAGES_ENV, Ok(" 3 ".into()), 10),
+            (3, None)
+        );
+        let (limit, warning) = resolve_positive_limit(MAX_IMAGES_ENV, Ok("0".into()), 10);
+        assert_eq!(limit, 10);
+        assert!(warning.unwrap().contains("CODEX_MAX_IMAGES"));
+    }
+
+    #[test]
+    fn output_as_resource_links_to_written_output() {
+        let result = codex::CodexResult {
+            success: true,
+            session_id: "resource-session".to_string(),
+            agent_messages: "done".to_string(),
+            attempts: 1,
+            ..Default::default()
+        };
+        let output = build_codex_output(&result, false, false, None);
+
+        let server = CodexServer::new();
+        let tool_result = server.output_as_resource(&output).unwrap();
+        let uri = tool_result
+            .content
+            .iter()
+            .find_map(|content| match &content.raw {
+                RawContent::ResourceLink(resource) => Some(resource.uri.clone()),
+                _ => None,
+            })
+            .expect("result should contain a resource link");
+        assert!(uri.starts_with("file://"));
+
+        let written: Value = serde_json::from_str(&server.read_output_resource(&uri).unwrap())
+            .expect("resource should contain valid JSON");
+        assert_eq!(written["SESSION_ID"], "resource-session");
+        assert_eq!(written["agent_messages"], "done");
+
+        let path = server.output_resources.lock().unwrap()[&uri].clone();
+        std::fs::remove_file(path).unwrap();
+        assert!(server.read_output_resource("file:///etc/passwd").is_err());
+    }
+
+    fn full_auto_args(sandbox: SandboxPolicy) -> CodexArgs {
+        serde_json::from_value(serde_json::json!({
+            "PROMPT": "test",
+            "cd": "/tmp",
+            "sandbox": sandbox,
+            "full_auto": true,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn expand_full_auto_selects_workspace_write() {
+        let mut args = full_auto_args(SandboxPolicy::ReadOnly);
+        assert!(expand_full_auto(&mut args, None).is_none());
+        assert_eq!(args.sandbox, Some(SandboxPolicy::WorkspaceWrite));
+
+        let mut args = full_auto_args(SandboxPolicy::ReadOnly);
+        let note = expand_full_auto(&mut args, Some(&SandboxPolicy::ReadOnly)).unwrap();
+        assert!(note.contains("overrides sandbox=read-only"));
+        assert_eq!(args.sandbox, Some(SandboxPolicy::WorkspaceWrite));
+
+        let mut args = full_auto_args(SandboxPolicy::DangerFullAccess);
+        let note = expand_full_auto(&mut args, Some(&SandboxPolicy::DangerFullAccess)).unwrap();
+        assert!(note.contains("overrides sandbox=danger-full-access"));
+        assert_eq!(args.sandbox, Some(SandboxPolicy::WorkspaceWrite));
+
+        let mut args = full_auto_args(SandboxPolicy::WorkspaceWrite);
+        assert!(expand_full_auto(&mut args, Some(&SandboxPolicy::WorkspaceWrite)).is_none());
+
+        let mut args = full_auto_args(SandboxPolicy::ReadOnly);
+        args.yolo = true;
+        assert!(expand_full_auto(&mut args, Some(&SandboxPolicy::ReadOnly))
+            .unwrap()
+            .contains("yolo"));
+        assert_eq!(args.sandbox, Some(SandboxPolicy::ReadOnly));
+    }
+
+    #[test]
+    fn full_auto_requires_yolo_allowance() {
+        let server = CodexServer::new();
+        let mut security = SecurityConfig {
+            allow_danger_full_access: false,
+            allow_yolo: false,
+            allow_skip_git_check: false,
+            block_dirty_tree: false,
+            allow_post_process: false,
+            allowed_models: None,
+            downgrade_disallowed_models: false,
+            allow_long_timeout: false,
+            strict_security: false,
+            allow_provider_headers: false,
+            allow_verify_cmd: false,
+            auto_skip_git_check: false,
+        };
+
+        let (args, warnings) = server
+            .apply_security_restrictions(full_auto_args(SandboxPolicy::ReadOnly), &security)
+            .unwrap();
+        assert!(!args.full_auto);
+        assert!(warnings
+            .iter()
+            .any(|w| w.contains("full_auto was disabled")));
+
+        security.allow_yolo = true;
+        let (args, warnings) = server
+            .apply_security_restrictions(full_auto_args(SandboxPolicy::ReadOnly), &security)
+            .unwrap();
+        assert!(args.full_auto);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn plan_runs_read_only_even_with_full_auto_and_yolo_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let args: CodexArgs = serde_json::from_value(serde_json::json!({
+            "PROMPT": "test",
+            "cd": dir.path(),
+            "sandbox": "workspace-write",
+            "yolo": true,
+            "full_auto": true,
+        }))
+        .unwrap();
+
+        let mut server = CodexServer::new();
+        server.security.allow_yolo = true;
+        let (opts, _) = server.prepare_options(read_only_plan_args(args)).unwrap();
+        assert_eq!(opts.sandbox, SandboxPolicy::ReadOnly);
+        assert!(!opts.full_auto);
+        assert!(!opts.yolo);
+    }
+
+    #[test]
+    fn resolve_default_sandbox_parses_modes() {
+        assert_eq!(
+            resolve_default_sandbox(Err(VarError::NotPresent)),
+            (SandboxPolicy::ReadOnly, None)
+        );
+        assert_eq!(
+            resolve_default_sandbox(Ok(" workspace-write ".into())),
+            (SandboxPolicy::WorkspaceWrite, None)
+        );
+        let (policy, warning) = resolve_default_sandbox(Ok("everything".into()));
+        assert_eq!(policy, SandboxPolicy::ReadOnly);
+        assert!(warning.unwrap().contains("not a sandbox mode"));
+    }
+
+    #[test]
+    fn apply_default_sandbox_applies_unless_client_chooses() {
+        let args = |sandbox: Option<&str>| -> CodexArgs {
+            let mut value = serde_json::json!({"PROMPT": "test", "cd": "/tmp"});
+            if let Some(sandbox) = sandbox {
+                value["sandbox"] = sandbox.into();
+            }
+            serde_json::from_value(value).unwrap()
+        };
+
+        let mut defaulted = args(None);
+        assert!(apply_default_sandbox(&mut defaulted, Ok("workspace-write".into())).is_none());
+        assert_eq!(defaulted.sandbox, Some(SandboxPolicy::WorkspaceWrite));
+
+        let mut explicit = args(Some("read-only"));
+        assert!(apply_default_sandbox(&mut explicit, Ok("workspace-write".into())).is_none());
+        assert_eq!(explicit.sandbox, Some(SandboxPolicy::ReadOnly));
+
+        let mut invalid = args(None);
+        let warning = apply_default_sandbox(&mut invalid, Ok("everything".into()));
+        assert!(warning.unwrap().contains("not a sandbox mode"));
+        assert_eq!(invalid.sandbox, Some(SandboxPolicy::ReadOnly));
+    }
+
+    fn capability_args(dir: &Path, token: &str) -> CodexArgs {
+        serde_json::from_value(serde_json::json!({
+            "PROMPT": "test",
+            "cd": dir,
+            "sandbox": "danger-full-access",
+            "yolo": true,
+            "capability_token": token,
+        }))
+        .unwrap()
+    }
+
+    fn sign_capability(secret: &str, cd: &Path, expiry: u64, prompt: &str) -> String {
+        use hmac::{Hmac, Mac};
+
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        let cd = cd.canonicalize().unwrap();
+        mac.update(capability_message(&cd, expiry, prompt).as_bytes());
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("{}.{}", expiry, signature)
+    }
+
+    fn capability_server() -> CodexServer {
+        CodexServer {
+            capability_secret: Some("s3cret".to_string()),
+            ..CodexServer::new()
+        }
+    }
+
+    /// An expiry an hour from now
+    fn future_expiry() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600
+    }
+
+    #[test]
+    fn valid_capability_token_enables_danger_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let token = sign_capability("s3cret", dir.path(), future_expiry(), "test");
+
+        // The token is checked against the canonical directory, however cd is spelled
+        let (opts, warnings) = capability_server()
+            .prepare_options(capability_args(&dir.path().join("."), &token))
+            .unwrap();
+        assert_eq!(opts.sandbox, SandboxPolicy::DangerFullAccess);
+        assert!(opts.yolo);
+        assert!(!warnings.iter().any(|w| w.starts_with("Security warning")));
+    }
+
+    #[test]
+    fn invalid_capability_token_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let expiry = future_expiry();
+
+        for token in [
+            sign_capability("guessed", dir.path(), expiry, "test"),
+            sign_capability("s3cret", other.path(), expiry, "test"),
+            sign_capability("s3cret", dir.path(), expiry, "another prompt"),
+            "not-a-token".to_string(),
+        ] {
+            let (opts, warnings) = capability_server()
+                .prepare_options(capability_args(dir.path(), &token))
+                .unwrap();
+            assert_eq!(opts.sandbox, SandboxPolicy::ReadOnly, "{token}");
+            assert!(!opts.yolo);
+            assert!(warnings
+                .iter()
+                .any(|w| w.contains("capability_token was not accepted")));
+        }
+    }
+
+    #[test]
+    fn expired_capability_token_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let cd = dir.path().canonicalize().unwrap();
+        let token = sign_capability("s3cret", dir.path(), 1_000, "test");
+
+        assert_eq!(
+            verify_capability_token("s3cret", &cd, "test", &token, 999),
+            Ok(())
+        );
+        assert_eq!(
+            verify_capability_token("s3cret", &cd, "test", &token, 1_001),
+            Err("it has expired")
+        );
+
+        let (opts, warnings) = capability_server()
+            .prepare_options(capability_args(dir.path(), &token))
+            .unwrap();
+        assert_eq!(opts.sandbox, SandboxPolicy::ReadOnly);
+        assert!(warnings.iter().any(|w| w.contains("expired")));
+    }
+
+    #[test]
+    fn client_profile_map_defaults_profile_for_known_client() {
+        let (map, warning) = resolve_client_profile_map(Ok(r#"{"claude-desktop":"fast"}"#.into()));
+        assert!(warning.is_none());
+        assert!(resolve_client_profile_map(Ok("[1]".into())).1.is_some());
+
+        let dir = tempfile::tempdir().unwrap();
+        let args = |profile: Option<&str>| -> CodexArgs {
+            serde_json::from_value(
+                serde_json::json!({"PROMPT": "test", "cd": dir.path(), "profile": profile}),
+            )
+            .unwrap()
+        };
+        let server = CodexServer {
+            client_profiles: map,
+            ..CodexServer::new()
+        };
+        assert_eq!(server.prepare_options(args(None)).unwrap().0.profile, None);
+
+        server.record_client_info(&Implementation {
+            name: "claude-desktop".to_string(),
+            ..Implementation::from_build_env()
+        });
+        assert_eq!(
+            server.prepare_options(args(None)).unwrap().0.profile,
+            Some("fast".to_string())
+        );
+        assert_eq!(
+            server
+                .prepare_options(args(Some("mine")))
+                .unwrap()
+                .0
+                .profile,
+            Some("mine".to_string())
+        );
+    }
+
+    fn allowlist() -> Vec<String> {
+        resolve_allowed_models(Some(" gpt-5-codex, ,o3 ".to_string())).unwrap()
+    }
+
+    #[test]
+    fn oss_model_warning_flags_hosted_models() {
+        assert!(oss_model_warning(true, Some("gpt-5-codex"))
+            .unwrap()
+            .contains("'gpt-5-codex' is a hosted model"));
+        assert_eq!(oss_model_warning(true, Some("gpt-oss:20b")), None);
+        assert_eq!(oss_model_warning(true, None), None);
+        assert_eq!(oss_model_warning(false, Some("gpt-5-codex")), None);
+    }
+
+    #[test]
+    fn normalize_model_trims_and_keeps_case() {
+        assert_eq!(
+            normalize_model(Some("  GPT-5-Codex\n".to_string())),
+            (Some("GPT-5-Codex".to_string()), None)
+        );
+        assert_eq!(normalize_model(None), (None, None));
+    }
+
+    #[test]
+    fn normalize_model_treats_blank_as_unset() {
+        let (model, warning) = normalize_model(Some(" \t ".to_string()));
+        assert_eq!(model, None);
+        assert!(warning.unwrap().contains("empty after trimming"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let server = CodexServer::new();
+        let (opts, warnings) = server
+            .prepare_options(CodexArgs {
+                prompt: "task".to_string(),
+                cd: dir.path().to_path_buf(),
+                model: Some("   ".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(opts.model, None);
+        assert!(warnings.iter().any(|w| w.contains("empty after trimming")));
+    }
+
+    #[test]
+    fn model_allowlist_accepts_listed_and_unset_models() {
+        assert_eq!(allowlist(), vec!["gpt-5-codex", "o3"]);
+        assert!(resolve_allowed_models(Some(" , ".to_string())).is_none());
+
+        let (model, warning) =
+            apply_model_allowlist(Some("o3".to_string()), Some(allowlist().as_slice()), false)
+                .unwrap();
+        assert_eq!(model.as_deref(), Some("o3"));
+        assert!(warning.is_none());
+
+        let (model, _) = apply_model_allowlist(None, Some(allowlist().as_slice()), false).unwrap();
+        assert!(model.is_none());
+        let (model, _) = apply_model_allowlist(Some("anything".to_string()), None, false).unwrap();
+        assert_eq!(model.as_deref(), Some("anything"));
+    }
+
+    #[test]
+    fn model_allowlist_rejects_unlisted_model() {
+        let err = apply_model_allowlist(
+            Some("gpt-4.5".to_string()),
+            Some(allowlist().as_slice()),
+            false,
+        )
+        .unwrap_err();
+        assert!(err.message.contains("model 'gpt-4.5' is not allowed"));
+        assert!(err.message.contains("gpt-5-codex, o3"));
+    }
+
+    #[test]
+    fn model_allowlist_downgrades_unlisted_model_when_configured() {
+        let (model, warning) = apply_model_allowlist(
+            Some("gpt-4.5".to_string()),
+            Some(allowlist().as_slice()),
+            true,
+        )
+        .unwrap();
+        assert_eq!(model.as_deref(), Some("gpt-5-codex"));
+        assert!(warning.unwrap().contains("replaced with 'gpt-5-codex'"));
+    }
+
+    fn write_transcript(dir: &Path, session_id: &str, answer: &str) {
+        let day = dir.join("2025/10/01");
+        std::fs::create_dir_all(&day).unwrap();
+        let event = serde_json::json!({
+            "type": "event_msg",
+            "payload": {"type": "agent_message", "message": answer},
+        });
+        std::fs::write(
+            day.join(format!("rollout-2025-10-01T10-00-00-{}.jsonl", session_id)),
+            format!("{}\n", event),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn diff_session_answers_reports_differences() {
+        let dir = tempfile::tempdir().unwrap();
+        write_transcript(dir.path(), "session-a", "Use a HashMap.\nDone.");
+        write_transcript(dir.path(), "session-b", "Use a BTreeMap.\nDone.");
+        let args = |a: &str, b: &str| DiffSessionsArgs {
+            session_id_a: a.to_string(),
+            session_id_b: b.to_string(),
+        };
+
+        let output = diff_session_answers(dir.path(), &args("session-a", "session-b")).unwrap();
+        assert!(output.differ);
+        assert!(output.diff.contains("-Use a HashMap."));
+        assert!(output.diff.contains("+Use a BTreeMap."));
+
+        let same = diff_session_answers(dir.path(), &args("session-a", "session-a")).unwrap();
+        assert!(!same.differ);
+        assert!(same.diff.is_empty());
+
+        let err = diff_session_answers(dir.path(), &args("session-a", "missing")).unwrap_err();
+        assert!(err.message.contains("session 'missing' was not found"));
+    }
+
+    #[test]
+    fn export_session_markdown_renders_stored_transcript() {
+        let dir = tempfile::tempdir().unwrap();
+        write_transcript(dir.path(), "session-a", "All tests pass.");
+        let args = |session_id: &str| ExportSessionArgs {
+            session_id: session_id.to_string(),
+            include_reasoning: false,
+        };
+
+        let output = export_session_markdown(dir.path(), &args("session-a")).unwrap();
+        assert!(!output.truncated);
+        assert!(output.markdown.starts_with("# Codex session session-a\n"));
+        assert!(output.markdown.contains("## Turn 1\n"));
+        assert!(output.markdown.contains("### Codex\n\nAll tests pass.\n"));
+
+        let err = export_session_markdown(dir.path(), &args("missing")).unwrap_err();
+        assert!(err.message.contains("session 'missing' was not found"));
+    }
+
+    #[test]
+    fn empty_prompt_is_allowed_with_agents_md_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("AGENTS.md"), "Run the test suite.\n").unwrap();
+
+        assert!(check_prompt_present("", true, dir.path(), codex::MAX_AGENTS_MD_SIZE).is_ok());
+        // The flag is required even when AGENTS.md exists
+        assert!(check_prompt_present("", false, dir.path(), codex::MAX_AGENTS_MD_SIZE).is_err());
+    }
+
+    #[test]
+    fn empty_prompt_without_agents_md_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let err =
+            check_prompt_present("", true, dir.path(), codex::MAX_AGENTS_MD_SIZE).unwrap_err();
+        assert!(err.message.contains("no AGENTS.md"));
+
+        std::fs::write(dir.path().join("AGENTS.md"), "\u{FEFF}  \n").unwrap();
+        assert!(check_prompt_present("", true, dir.path(), codex::MAX_AGENTS_MD_SIZE).is_err());
+        assert!(check_prompt_present("task", false, dir.path(), codex::MAX_AGENTS_MD_SIZE).is_ok());
+    }
+
+    fn timeout_args(dir: &Path, timeout_secs: u64) -> CodexArgs {
+        serde_json::from_value(serde_json::json!({
+            "PROMPT": "test",
+            "cd": dir,
+            "timeout_secs": timeout_secs,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn auto_skip_git_check_only_applies_outside_git_repos() {
+        let plain = tempfile::tempdir().unwrap();
+        let repo = tempfile::tempdir().unwrap();
+        std::fs::create_dir(repo.path().join(".git")).unwrap();
+        let args = |dir: &Path| -> CodexArgs {
+            serde_json::from_value(serde_json::json!({"PROMPT": "test", "cd": dir})).unwrap()
+        };
+        let mut server = CodexServer::new();
+        server.security.allow_skip_git_check = false;
+
+        server.security.auto_skip_git_check = false;
+        let (opts, _) = server.prepare_options(args(plain.path())).unwrap();
+        assert!(!opts.skip_git_repo_check);
+
+        server.security.auto_skip_git_check = true;
+        let (opts, _) = server.prepare_options(args(plain.path())).unwrap();
+        assert!(opts.skip_git_repo_check);
+        let (opts, _) = server.prepare_options(args(repo.path())).unwrap();
+        assert!(!opts.skip_git_repo_check);
+    }
+
+    #[test]
+    fn privileged_request_may_exceed_max_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = CodexServer::new();
+        server.security.allow_long_timeout = true;
+
+        let (opts, _) = server
+            .prepare_options(timeout_args(dir.path(), 7200))
+            .unwrap();
+        assert_eq!(opts.timeout_secs, Some(7200));
+        assert!(opts.allow_long_timeout);
+
+        let (opts, warnings) = server
+            .prepare_options(timeout_args(dir.path(), MAX_LONG_TIMEOUT_SECS + 1))
+            .unwrap();
+        assert_eq!(opts.timeout_secs, Some(MAX_LONG_TIMEOUT_SECS));
+        assert!(warnings.iter().any(|w| w.contains("capping to maximum")));
+    }
+
+    #[test]
+    fn unprivileged_request_is_capped_at_max_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = CodexServer::new();
+        server.security.allow_long_timeout = false;
+
+        let (opts, warnings) = server
+            .prepare_options(timeout_args(dir.path(), 7200))
+            .unwrap();
+        assert_eq!(opts.timeout_secs, Some(MAX_TIMEOUT_SECS));
+        assert!(!opts.allow_long_timeout);
+        assert!(warnings
+            .iter()
+            .any(|w| w.contains("exceeds maximum of 3600 seconds")));
+    }
+
+    #[test]
+    fn capability_token_does_not_lift_timeout_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = capability_server();
+        server.security.allow_long_timeout = false;
+        let mut args = timeout_args(dir.path(), 7200);
+        args.capability_token = Some(sign_capability(
+            "s3cret",
+            dir.path(),
+            future_expiry(),
+            "test",
+        ));
+
+        let (opts, warnings) = server.prepare_options(args).unwrap();
+        assert!(!warnings.iter().any(|w| w.starts_with("Security warning")));
+        assert_eq!(opts.timeout_secs, Some(MAX_TIMEOUT_SECS));
+        assert!(!opts.allow_long_timeout);
+    }
+
+    #[test]
+    fn resolve_max_concurrency_defaults_to_unlimited() {
+        let unlimited = tokio::sync::Semaphore::MAX_PERMITS;
+        assert_eq!(
+            resolve_max_concurrency(Err(std::env::VarError::NotPresent)),
+            (unlimited, None)
+        );
+        assert_eq!(resolve_max_concurrency(Ok(" 4 ".to_string())), (4, None));
+        let (limit, warning) = resolve_max_concurrency(Ok("0".to_string()));
+        assert_eq!(limit, unlimited);
+        assert!(warning.unwrap().contains("CODEX_MAX_CONCURRENCY"));
+    }
+
+    #[test]
+    fn resolve_spawn_jitter_ms_parses_and_caps() {
+        assert_eq!(
+            resolve_spawn_jitter_ms(Err(std::env::VarError::NotPresent)),
+            (0, None)
+        );
+        assert_eq!(resolve_spawn_jitter_ms(Ok("250".to_string())), (250, None));
+        let (ms, warning) = resolve_spawn_jitter_ms(Ok("999999".to_string()));
+        assert_eq!(ms, MAX_SPAWN_JITTER_MS);
+        assert!(warning.is_some());
+        let (ms, warning) = resolve_spawn_jitter_ms(Ok("soon".to_string()));
+        assert_eq!(ms, 0);
+        assert!(warning.unwrap().contains("CODEX_SPAWN_JITTER_MS"));
+    }
+
+    #[test]
+    fn strict_security_rejects_danger_requests_instead_of_downgrading() {
+        let server = CodexServer::new();
+        let danger_args = || CodexArgs {
+            prompt: "test".to_string(),
+            cd: PathBuf::from("/tmp"),
+            sandbox: Some(SandboxPolicy::DangerFullAccess),
+            yolo: true,
+            ..Default::default()
+        };
+        let mut security = server.security.clone();
+        security.allow_danger_full_access = false;
+        security.allow_yolo = false;
+
+        security.strict_security = false;
+        let (args, warnings) = server
+            .apply_security_restrictions(danger_args(), &security)
+            .expect("lenient mode downgrades instead of failing");
+        assert_eq!(args.sandbox, Some(SandboxPolicy::ReadOnly));
+        assert!(!args.yolo);
+        assert_eq!(warnings.len(), 2);
+
+        security.strict_security = true;
+        let err = server
+            .apply_security_restrictions(danger_args(), &security)
+            .expect_err("strict mode should reject the request");
+        assert!(err.message.contains("danger-full-access"));
+
+        security.allow_danger_full_access = true;
+        let err = server
+            .apply_security_restrictions(danger_args(), &security)
+            .expect_err("yolo is still disallowed");
+        assert!(err.message.contains("CODEX_ALLOW_YOLO"));
+    }
+
+    #[test]
+    fn provider_headers_are_gated_and_validated() {
+        let server = CodexServer::new();
+        let header_args = || CodexArgs {
+            prompt: "test".to_string(),
+            cd: PathBuf::from("/tmp"),
+            provider_headers: HashMap::from([("X-Org-Id".to_string(), "acme".to_string())]),
+            ..Default::default()
+        };
+        let mut security = server.security.clone();
+
+        security.allow_provider_headers = false;
+        let (args, warnings) = server
+            .apply_security_restrictions(header_args(), &security)
+            .unwrap();
+        assert!(args.provider_headers.is_empty());
+        assert!(warnings[0].contains("CODEX_ALLOW_PROVIDER_HEADERS"));
+
+        security.allow_provider_headers = true;
+        let (args, warnings) = server
+            .apply_security_restrictions(header_args(), &security)
+            .unwrap();
+        assert_eq!(args.provider_headers["X-Org-Id"], "acme");
+        assert!(warnings.is_empty());
+
+        assert!(check_provider_headers(&args.provider_headers).is_ok());
+        let bad_name = HashMap::from([("X Org".to_string(), "acme".to_string())]);
+        assert!(check_provider_headers(&bad_name)
+            .unwrap_err()
+            .message
+            .contains("not a valid HTTP header name"));
+        let bad_value = HashMap::from([("X-Org".to_string(), "a\r\nX-Evil: 1".to_string())]);
+        assert!(check_provider_headers(&bad_value).is_err());
+    }
+
+    #[test]
+    fn resolve_dir_lock_wait_secs_parses_and_defaults() {
+        assert_eq!(
+            resolve_dir_lock_wait_secs(Err(VarError::NotPresent)),
+            (DEFAULT_DIR_LOCK_WAIT_SECS, None)
+        );
+        assert_eq!(resolve_dir_lock_wait_secs(Ok("0".to_string())), (0, None));
+        let (secs, warning) = resolve_dir_lock_wait_secs(Ok("forever".to_string()));
+        assert_eq!(secs, DEFAULT_DIR_LOCK_WAIT_SECS);
+        assert!(warning.unwrap().contains("CODEX_DIR_LOCK_WAIT_SECS"));
+    }
+
+    #[test]
+    fn compress_messages_round_trips() {
+        use base64::Engine;
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let messages = vec![
+            HashMap::from([
+                ("type".to_string(), Value::String("thread.started".into())),
+                ("thread_id".to_string(), Value::String("abc".into())),
+            ]),
+            HashMap::from([("type".to_string(), Value::String("turn.completed".into()))]),
+        ];
+
+        let encoded = compress_messages(&messages).unwrap();
+        let compressed = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .unwrap();
+        let mut json = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut json)
+            .unwrap();
+        let decoded: Vec<HashMap<String, Value>> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(decoded, messages);
+    }
+
+    fn init_git_repo() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let status = std::process::Command::new("git")
+            .arg("init")
+            .arg("-q")
+            .arg(dir.path())
+            .status()
+            .expect("git must be installed to run dirty-tree tests");
+        assert!(status.success());
+        dir
+    }
+
+    #[test]
+    fn check_dirty_tree_ignores_clean_repo() {
+        let repo = init_git_repo();
+        for block in [false, true] {
+            let outcome = check_dirty_tree(
+                repo.path(),
+                &SandboxPolicy::WorkspaceWrite,
+                false,
+                false,
+                block,
+            )
+            .unwrap();
+            assert!(outcome.is_none());
+        }
+    }
+
+    #[test]
+    fn check_dirty_tree_warns_on_dirty_repo() {
+        let repo = init_git_repo();
+        std::fs::write(repo.path().join("uncommitted.txt"), "wip").unwrap();
+
+        let warning = check_dirty_tree(
+            repo.path(),
+            &SandboxPolicy::WorkspaceWrite,
+            false,
+            false,
+            false,
+        )
+        .unwrap()
+        .expect("dirty tree should produce a warning");
+        assert!(warning.contains("uncommitted changes"));
+        assert!(warning.contains("CODEX_BLOCK_DIRTY_TREE"));
+    }
+
+    #[test]
+    fn check_dirty_tree_blocks_dirty_repo_when_configured() {
+        let repo = init_git_repo();
+        std::fs::write(repo.path().join("uncommitted.txt"), "wip").unwrap();
+
+        let err = check_dirty_tree(
+            repo.path(),
+            &SandboxPolicy::DangerFullAccess,
+            false,
+            false,
+            true,
+        )
+        .expect_err("dirty tree should be refused");
+        assert!(err.message.contains("uncommitted changes"));
+    }
+
+    #[test]
+    fn check_dirty_tree_treats_yolo_and_full_auto_as_writable() {
+        let repo = init_git_repo();
+        std::fs::write(repo.path().join("uncommitted.txt"), "wip").unwrap();
+
+        let err = check_dirty_tree(repo.path(), &SandboxPolicy::ReadOnly, true, false, true)
+            .expect_err("yolo bypasses the read-only sandbox, so the dirty tree is refused");
+        assert!(err.message.contains("yolo"));
+
+        let err = check_dirty_tree(repo.path(), &SandboxPolicy::ReadOnly, false, true, true)
+            .expect_err("full_auto implies workspace-write, so the dirty tree is refused");
+        assert!(err.message.contains("full_auto"));
+    }
+
+    #[test]
+    fn check_dirty_tree_skips_read_only_and_non_repos() {
+        let repo = init_git_repo();
+        std::fs::write(repo.path().join("uncommitted.txt"), "wip").unwrap();
+        assert!(
+            check_dirty_tree(repo.path(), &SandboxPolicy::ReadOnly, false, false, true)
+                .unwrap()
+                .is_none()
+        );
+
+        let plain = tempfile::tempdir().unwrap();
+        assert!(check_dirty_tree(
+            plain.path(),
+            &SandboxPolicy::WorkspaceWrite,
+            false,
+            false,
+            true
+        )
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    fn check_response_schema_accepts_conforming_output() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "count": { "type": "integer" } },
+            "required": ["count"]
+        });
+        let validator = compile_response_schema(&schema).unwrap();
+
+        assert!(check_response_schema(&validator, " {\"count\": 3}\n").is_ok());
+    }
+
+    #[test]
+    fn check_response_schema_rejects_non_conforming_output() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "count": { "type": "integer" } },
+            "required": ["count"]
+        });
+        let validator = compile_response_schema(&schema).unwrap();
+
+        let err = check_response_schema(&validator, "{\"count\": \"three\"}").unwrap_err();
+        assert!(err.contains("does not conform"));
+        assert!(err.contains("/count"));
+
+        let err = check_response_schema(&validator, "three").unwrap_err();
+        assert!(err.contains("not valid JSON"));
+    }
+
+    #[test]
+    fn compile_response_schema_rejects_invalid_schema() {
+        let schema = serde_json::json!({ "type": "not-a-type" });
+        assert!(compile_response_schema(&schema).is_err());
+    }
+
+    #[test]
+    fn compile_response_schema_rejects_external_refs() {
+        for reference in ["http://127.0.0.1:1/schema.json", "file:///etc/passwd"] {
+            let schema = serde_json::json!({ "$ref": reference });
+            assert!(compile_response_schema(&schema).is_err(), "{reference}");
+        }
+
+        let local = serde_json::json!({
+            "$defs": { "name": { "type": "string" } },
+            "properties": { "name": { "$ref": "#/$defs/name" } }
+        });
+        assert!(compile_response_schema(&local).is_ok());
+    }
+
+    #[test]
+    fn decode_prompt_b64_accepts_valid_prompt() {
+        use base64::Engine;
+
+        let prompt = "line one\n\tline two \u{1b}[0m \"quoted\"";
+        let encoded = base64::engine::general_purpose::STANDARD.encode(prompt);
+        assert_eq!(decode_prompt_b64(&encoded).unwrap(), prompt);
+    }
+
+    #[test]
+    fn expand_template_substitutes_variables() {
+        let variables = HashMap::from([
+            ("file".to_string(), "src/lib.rs".to_string()),
+            ("goal".to_string(), "speed".to_string()),
+        ]);
+        assert_eq!(
+            expand_template(
+                "Optimize {{file}} for {{ goal }}; keep {{file}} tidy",
+                &variables,
+                false
+            )
+            .unwrap(),
+            "Optimize src/lib.rs for speed; keep src/lib.rs tidy"
+        );
+        assert_eq!(
+            expand_template("no placeholders {{ unclosed", &variables, false).unwrap(),
+            "no placeholders {{ unclosed"
+        );
+    }
+
+    #[test]
+    fn expand_template_rejects_missing_variables() {
+        let variables = HashMap::from([("file".to_string(), "a.rs".to_string())]);
+        let err = expand_template(
+            "Fix {{file}} using {{tool}} and {{tool}} {{lang}}",
+            &variables,
+            false,
+        )
+        .unwrap_err();
+        assert!(err
+            .message
+            .contains("template has no value for: tool, lang"));
+    }
+
+    #[test]
+    fn expand_template_keeps_unresolved_when_allowed() {
+        let variables = HashMap::from([("file".to_string(), "a.rs".to_string())]);
+        assert_eq!(
+            expand_template("Fix {{file}} with {{ tool }}", &variables, true).unwrap(),
+            "Fix a.rs with {{ tool }}"
+        );
+    }
+
+    #[test]
+    fn decode_prompt_b64_rejects_invalid_input() {
+        let err = decode_prompt_b64("not base64!").unwrap_err();
+        assert!(err.message.contains("not valid base64"));
+
+        // "/w==" decodes to the single byte 0xFF, which is not UTF-8
+        let err = decode_prompt_b64("/w==").unwrap_err();
+        assert!(err.message.contains("valid UTF-8"));
+    }
+
+    #[test]
+    fn record_turn_offset_tracks_sessions_without_rewinding() {
+        let server = CodexServer::new();
+        assert_eq!(server.turn_offset("s1"), TurnOffset::default());
+
+        let mut result = codex::CodexResult {
+            success: true,
+            session_id: "s1".to_string(),
+            turn_offset: TurnOffset {
+                agent_messages: 2,
+                events: 5,
+            },
+            attempts: 1,
+            ..Default::default()
+        };
+        server.record_turn_offset(&result);
+        assert_eq!(server.turn_offset("s1").events, 5);
+
+        // A run that failed before replaying the full history must not rewind the offset
+        result.turn_offset = TurnOffset::default();
+        server.record_turn_offset(&result);
+        assert_eq!(server.turn_offset("s1").agent_messages, 2);
+        assert_eq!(server.turn_offset("other"), TurnOffset::default());
+    }
+
+    #[test]
+    fn build_instructions_summarizes_security() {
+        let security = SecurityConfig {
+            allow_danger_full_access: false,
+            allow_yolo: true,
+            allow_skip_git_check: false,
+            block_dirty_tree: true,
+            allow_post_process: false,
+            allowed_models: None,
+            downgrade_disallowed_models: false,
+            allow_long_timeout: false,
+            strict_security: false,
+            allow_provider_headers: false,
+            allow_verify_cmd: false,
+            auto_skip_git_check: false,
+        };
+
+        let instructions = build_instructions(&security);
+        assert!(instructions.contains("- yolo: allowed"));
+        assert!(instructions.contains("- danger-full-access sandbox: not allowed"));
+        assert!(instructions.contains("dirty git trees: blocked"));
+    }
+
+    #[test]
+    fn prepare_options_suggests_parent_when_cd_is_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("main.rs");
+        std::fs::write(&file, "fn main() {}").unwrap();
+
+        let args: CodexArgs = serde_json::from_value(serde_json::json!({
+            "PROMPT": "test",
+            "cd": file,
+        }))
+        .unwrap();
+        let err = CodexServer::new().prepare_options(args).unwrap_err();
+
+        let parent = dir.path().canonicalize().unwrap();
+        assert!(err
+            .message
+            .contains("cd is a file; did you mean its parent"));
+        assert!(err.message.contains(&parent.display().to_string()));
+    }
+
+    #[test]
+    fn expand_tilde_replaces_leading_home_only() {
+        let home = Path::new("/home/alice");
+        assert_eq!(
+            expand_tilde(Path::new("~/pic.png"), Some(home)),
+            PathBuf::from("/home/alice/pic.png")
+        );
+        assert_eq!(
+            expand_tilde(Path::new("~/projects/app"), Some(home)),
+            PathBuf::from("/home/alice/projects/app")
+        );
+        assert_eq!(
+            expand_tilde(Path::new("~"), Some(home)),
+            PathBuf::from("/home/alice")
+        );
+        assert_eq!(
+            expand_tilde(Path::new("~bob/pic.png"), Some(home)),
+            PathBuf::from("~bob/pic.png")
+        );
+        assert_eq!(
+            expand_tilde(Path::new("images/~/pic.png"), Some(home)),
+            PathBuf::from("images/~/pic.png")
+        );
+        assert_eq!(
+            expand_tilde(Path::new("~/pic.png"), None),
+            PathBuf::from("~/pic.png")
+        );
+    }
+
+    #[test]
+    fn prepare_options_expands_tilde_in_cd() {
+        let Some(home) = dirs::home_dir().and_then(|h| h.canonicalize().ok()) else {
+            return;
+        };
+        let args: CodexArgs = serde_json::from_value(serde_json::json!({
+            "PROMPT": "test",
+            "cd": "~",
+        }))
+        .unwrap();
+
+        let (opts, _) = CodexServer::new().prepare_options(args).unwrap();
+        assert_eq!(opts.working_dir, home);
+    }
+
+    #[test]
+    fn request_guard_duration_exceeds_codex_timeout() {
+        assert_eq!(
+            request_guard_duration(Some(60)).as_secs(),
+            60 + REQUEST_GUARD_GRACE_SECS
+        );
+        assert_eq!(
+            request_guard_duration(None).as_secs(),
+            DEFAULT_TIMEOUT_SECS + REQUEST_GUARD_GRACE_SECS
+        );
+    }
+
+    #[tokio::test]
+    async fn guard_request_passes_through_results_in_time() {
+        let outcome = guard_request(std::time::Duration::from_secs(5), async { 42 }).await;
+        assert_eq!(outcome, Ok(42));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn guard_request_fires_when_child_closes_output_but_never_exits() {
+        let mut child = tokio::process::Command::new("sh")
+            .args(["-c", "exec >&- 2>&-; exec sleep 30"])
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("sh must be available");
+
+        let started = std::time::Instant::now();
+        let outcome = guard_request(std::time::Duration::from_millis(300), child.wait()).await;
+
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        let err = outcome.expect_err("guard should fire on a hung child");
+        assert!(err.contains("server-side guard"));
+    }
+
+    #[test]
+    fn always_warnings_emits_empty_field_on_clean_run() {
+        let result = codex::CodexResult {
+            success: true,
+            session_id: "session".to_string(),
+            agent_messages: "done".to_string(),
+            attempts: 1,
+            ..Default::default()
+        };
+
+        let warnings = finalize_warnings(merge_warnings(Vec::new(), None), true);
+        let output = build_codex_output(&result, false, false, warnings);
+        let json = serde_json::to_value(&output).unwrap();
+        assert_eq!(json["warnings"], "");
+
+        let warnings = finalize_warnings(merge_warnings(Vec::new(), None), false);
+        let output = build_codex_output(&result, false, false, warnings);
+        let json = serde_json::to_value(&output).unwrap();
+        assert!(json.get("warnings").is_none());
+        assert_eq!(json["schema_version"], OUTPUT_SCHEMA_VERSION);
+        assert_eq!(OUTPUT_SCHEMA_VERSION, 1);
+    }
+
+    #[test]
+    fn fields_restrict_output_keys() {
+        let result = codex::CodexResult {
+            success: true,
+            session_id: "session".to_string(),
+            agent_messages: "done".to_string(),
+            warnings: Some("careful".to_string()),
+            attempts: 1,
+            final_answer: Some("done".to_string()),
+            exit_code: Some(0),
+            ttft_ms: Some(5),
+            ..Default::default()
+        };
+        let output = build_codex_output(&result, false, false, result.warnings.clone());
+
+        let full = select_output_fields(&output, &[]).unwrap();
+        for key in full.as_object().unwrap().keys() {
+            assert!(
+                CODEX_OUTPUT_FIELDS.contains(&key.as_str()),
+                "{} is not selectable",
+                key
+            );
+        }
+
+        // Warnings survive any selection, so a filtered response never hides them
+        let selected = select_output_fields(&output, &["SESSION_ID".to_string()]).unwrap();
+        let keys: Vec<&String> = selected.as_object().unwrap().keys().collect();
+        assert_eq!(keys.len(), 4, "unexpected keys: {:?}", keys);
+        assert_eq!(selected["success"], true);
+        assert_eq!(selected["schema_version"], OUTPUT_SCHEMA_VERSION);
+        assert_eq!(selected["SESSION_ID"], "session");
+        assert_eq!(selected["warnings"], "careful");
+
+        let mut result = result;
+        result.success = false;
+        result.error = Some("boom".to_string());
+        let mut output = build_codex_output(&result, false, false, None);
+        output.security_downgrades = Some(SecurityDowngrades {
+            yolo_disabled: true,
+            ..Default::default()
+        });
+        let selected = select_output_fields(&output, &["SESSION_ID".to_string()]).unwrap();
+        assert_eq!(selected["error"], "boom");
+        assert_eq!(selected["security_downgrades"]["yolo_disabled"], true);
+
+        assert!(check_output_fields(&["agent_messages".to_string()]).is_ok());
+        let err = check_output_fields(&["agent_message".to_string()]).unwrap_err();
+        assert!(err.message.contains("agent_message"));
+    }
+
+    #[test]
+    fn output_fingerprint_is_stable_for_equal_answers() {
+        let message = |pairs: &[(&str, &str)]| -> HashMap<String, Value> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), Value::from(*value)))
+                .collect()
+        };
+
+        assert_eq!(
+            output_fingerprint("same answer", None),
+            output_fingerprint("same answer", None)
+        );
+        assert_eq!(output_fingerprint("same answer", None).len(), 64);
+        assert_eq!(
+            output_fingerprint("line one\r\nline two\n", None),
+            output_fingerprint("line one\nline two", None)
+        );
+        assert_ne!(
+            output_fingerprint("one answer", None),
+            output_fingerprint("another answer", None)
+        );
+
+        let first = vec![message(&[("type", "agent_message"), ("text", "hi")])];
+        let reordered = vec![message(&[("text", "hi"), ("type", "agent_message")])];
+        let different = vec![message(&[("type", "reasoning"), ("text", "hi")])];
+        assert_eq!(
+            output_fingerprint("hi", Some(first.as_slice())),
+            output_fingerprint("hi", Some(reordered.as_slice()))
+        );
+        assert_ne!(
+            output_fingerprint("hi", Some(first.as_slice())),
+            output_fingerprint("hi", Some(different.as_slice()))
+        );
+        assert_ne!(
+            output_fingerprint("hi", Some(first.as_slice())),
+            output_fingerprint("hi", None)
+        );
+    }
 }
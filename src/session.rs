@@ -0,0 +1,312 @@
+use serde_json::Value;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Environment variable codex uses for its home directory (default: ~/.codex)
+pub const CODEX_HOME_ENV: &str = "CODEX_HOME";
+
+/// Pure function to resolve codex's home directory. Empty values are treated as unset and fall
+/// back to `~/.codex`. A CODEX_HOME naming a directory that does not exist resolves to None with
+/// a warning, so features reading codex's files are skipped instead of failing on every call.
+fn resolve_codex_home(
+    env_val: Option<OsString>,
+    home: Option<PathBuf>,
+) -> (Option<PathBuf>, Option<String>) {
+    match env_val {
+        Some(val) if !val.is_empty() => {
+            let dir = PathBuf::from(val);
+            if dir.is_dir() {
+                (Some(dir), None)
+            } else {
+                let warning = format!(
+                    "{}={} is not an existing directory; features that read Codex's sessions are unavailable",
+                    CODEX_HOME_ENV,
+                    dir.display()
+                );
+                (None, Some(warning))
+            }
+        }
+        _ => (home.map(|home| home.join(".codex")), None),
+    }
+}
+
+/// Codex's home directory (`$CODEX_HOME`, else `~/.codex`) and a warning when CODEX_HOME is
+/// unusable. The single place CODEX_HOME is read
+pub fn codex_home() -> (Option<PathBuf>, Option<String>) {
+    resolve_codex_home(std::env::var_os(CODEX_HOME_ENV), dirs::home_dir())
+}
+
+/// Directory holding codex session transcripts: `$CODEX_HOME/sessions`, else `~/.codex/sessions`
+pub fn sessions_dir() -> Option<PathBuf> {
+    codex_home().0.map(|home| home.join("sessions"))
+}
+
+/// Find the transcript for `session_id` under `dir`. Codex names transcripts
+/// `rollout-<timestamp>-<session id>.jsonl` in date subdirectories, so the tree is searched for a
+/// `.jsonl` file whose name ends with `-<session id>`.
+pub fn find_session_file(dir: &Path, session_id: &str) -> Option<PathBuf> {
+    let suffix = format!("-{}.jsonl", session_id);
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&current) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.ends_with(&suffix))
+            {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+/// The last agent message in a transcript. Accepts both the session log format
+/// (`{"type":"event_msg","payload":{"type":"agent_message","message":...}}`) and the
+/// `codex exec --json` event format (`{"type":"item.completed","item":{"type":"agent_message",...}}`).
+pub fn final_agent_message(transcript: &str) -> Option<String> {
+    transcript
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line.trim()).ok())
+        .filter_map(|event| {
+            if let Some(payload) = event.get("payload") {
+                if payload.get("type").and_then(Value::as_str) == Some("agent_message") {
+                    return payload
+                        .get("message")
+                        .and_then(Value::as_str)
+                        .map(str::to_string);
+                }
+            }
+            let item = event.get("item")?;
+            if item.get("type").and_then(Value::as_str) == Some("agent_message") {
+                return item.get("text").and_then(Value::as_str).map(str::to_string);
+            }
+            None
+        })
+        .last()
+}
+
+/// Read the transcript of a session stored under `dir`
+pub fn read_transcript(dir: &Path, session_id: &str) -> Result<String, String> {
+    let path = find_session_file(dir, session_id).ok_or_else(|| {
+        format!(
+            "session '{}' was not found in {}",
+            session_id,
+            dir.display()
+        )
+    })?;
+    std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
+
+/// Read the final agent message of a session stored under `dir`
+pub fn read_final_message(dir: &Path, session_id: &str) -> Result<String, String> {
+    let transcript = read_transcript(dir, session_id)?;
+    final_agent_message(&transcript)
+        .ok_or_else(|| format!("session '{}' has no agent message", session_id))
+}
+
+/// Maximum size of a Markdown export of a session
+pub const MAX_EXPORT_BYTES: usize = 1024 * 1024;
+
+/// One step of a session, in transcript order
+#[derive(Debug, PartialEq, Eq)]
+enum TranscriptEntry {
+    User(String),
+    Agent(String),
+    Reasoning(String),
+    Command {
+        command: String,
+        exit_code: Option<i64>,
+    },
+}
+
+/// A command as codex records it: one string, or the program and its arguments
+fn command_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(command) => Some(command.clone()),
+        Value::Array(parts) => Some(
+            parts
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        _ => None,
+    }
+}
+
+/// Read one transcript line in either format final_agent_message accepts. Session logs repeat
+/// each step as a `response_item`; only the `event_msg` copy is used so nothing appears twice
+fn transcript_entry(event: &Value) -> Option<TranscriptEntry> {
+    let text =
+        |object: &Value, key: &str| object.get(key).and_then(Value::as_str).map(str::to_string);
+    match event.get("type").and_then(Value::as_str)? {
+        "event_msg" => {
+            let payload = event.get("payload")?;
+            match payload.get("type").and_then(Value::as_str)? {
+                "user_message" => text(payload, "message").map(TranscriptEntry::User),
+                "agent_message" => text(payload, "message").map(TranscriptEntry::Agent),
+                "agent_reasoning" => text(payload, "text").map(TranscriptEntry::Reasoning),
+                "exec_command_end" => Some(TranscriptEntry::Command {
+                    command: command_text(payload.get("command")?)?,
+                    exit_code: payload.get("exit_code").and_then(Value::as_i64),
+                }),
+                _ => None,
+            }
+        }
+        "item.completed" => {
+            let item = event.get("item")?;
+            match item.get("type").and_then(Value::as_str)? {
+                "agent_message" => text(item, "text").map(TranscriptEntry::Agent),
+                "reasoning" => text(item, "text").map(TranscriptEntry::Reasoning),
+                "command_execution" => Some(TranscriptEntry::Command {
+                    command: command_text(item.get("command")?)?,
+                    exit_code: item.get("exit_code").and_then(Value::as_i64),
+                }),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Render a transcript as Markdown: a section per turn with the user's request, the commands
+/// Codex ran, its answers, and (when include_reasoning is set) its reasoning. Output beyond
+/// `max_bytes` is cut off with a marker; the flag reports whether that happened
+pub fn transcript_to_markdown(
+    session_id: &str,
+    transcript: &str,
+    include_reasoning: bool,
+    max_bytes: usize,
+) -> (String, bool) {
+    use std::fmt::Write;
+
+    let mut markdown = format!("# Codex session {}\n", session_id);
+    let mut turns = 0;
+    let entries = transcript
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line.trim()).ok())
+        .filter_map(|event| transcript_entry(&event));
+    for entry in entries {
+        // Exec event logs have no user messages, so their steps open the first turn themselves
+        if turns == 0 || matches!(entry, TranscriptEntry::User(_)) {
+            turns += 1;
+            let _ = write!(markdown, "\n## Turn {}\n", turns);
+        }
+        match entry {
+            TranscriptEntry::User(text) => {
+                let _ = write!(markdown, "\n### User\n\n{}\n", text.trim_end());
+            }
+            TranscriptEntry::Agent(text) => {
+                let _ = write!(markdown, "\n### Codex\n\n{}\n", text.trim_end());
+            }
+            TranscriptEntry::Reasoning(text) if include_reasoning => {
+                let _ = write!(markdown, "\n### Reasoning\n\n{}\n", text.trim_end());
+            }
+            TranscriptEntry::Reasoning(_) => {}
+            TranscriptEntry::Command { command, exit_code } => {
+                let _ = write!(markdown, "\n### Command\n\n```sh\n{}\n```\n", command);
+                if let Some(code) = exit_code {
+                    let _ = writeln!(markdown, "\nExit code: {}", code);
+                }
+            }
+        }
+    }
+
+    if markdown.len() <= max_bytes {
+        return (markdown, false);
+    }
+    let mut end = max_bytes;
+    while !markdown.is_char_boundary(end) {
+        end -= 1;
+    }
+    markdown.truncate(end);
+    markdown.push_str("\n\n[... session export truncated due to size limit ...]\n");
+    (markdown, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_codex_home_prefers_codex_home() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            resolve_codex_home(Some(dir.path().into()), None),
+            (Some(dir.path().to_path_buf()), None)
+        );
+        assert_eq!(
+            resolve_codex_home(Some(OsString::new()), Some(PathBuf::from("/home/u"))),
+            (Some(PathBuf::from("/home/u/.codex")), None)
+        );
+        assert_eq!(resolve_codex_home(None, None), (None, None));
+    }
+
+    #[test]
+    fn resolve_codex_home_degrades_when_directory_is_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("nowhere");
+        let (home, warning) =
+            resolve_codex_home(Some(missing.clone().into()), Some(PathBuf::from("/home/u")));
+        assert_eq!(home, None);
+        let warning = warning.unwrap();
+        assert!(warning.contains("CODEX_HOME"));
+        assert!(warning.contains(&missing.display().to_string()));
+    }
+
+    #[test]
+    fn final_agent_message_reads_both_formats() {
+        let transcript = r#"{"type":"event_msg","payload":{"type":"agent_message","message":"first"}}
+{"type":"item.completed","item":{"type":"agent_message","text":"second"}}
+{"type":"event_msg","payload":{"type":"token_count"}}"#;
+        assert_eq!(final_agent_message(transcript).as_deref(), Some("second"));
+        assert_eq!(final_agent_message("not json"), None);
+    }
+
+    #[test]
+    fn transcript_to_markdown_renders_turns() {
+        let transcript = r#"{"type":"event_msg","payload":{"type":"user_message","message":"List files"}}
+{"type":"response_item","payload":{"type":"message","role":"user","content":[]}}
+{"type":"event_msg","payload":{"type":"agent_reasoning","text":"Use ls"}}
+{"type":"event_msg","payload":{"type":"exec_command_end","command":["ls","-a"],"exit_code":0}}
+{"type":"event_msg","payload":{"type":"agent_message","message":"Two files."}}
+{"type":"event_msg","payload":{"type":"user_message","message":"Thanks"}}"#;
+
+        let (markdown, truncated) = transcript_to_markdown("abc", transcript, false, 4096);
+        assert!(!truncated);
+        assert_eq!(
+            markdown,
+            "# Codex session abc\n\n## Turn 1\n\n### User\n\nList files\n\n### Command\n\n```sh\nls -a\n```\n\nExit code: 0\n\n### Codex\n\nTwo files.\n\n## Turn 2\n\n### User\n\nThanks\n"
+        );
+
+        let (markdown, _) = transcript_to_markdown("abc", transcript, true, 4096);
+        assert!(markdown.contains("### Reasoning\n\nUse ls\n"));
+
+        let (markdown, truncated) = transcript_to_markdown("abc", transcript, false, 30);
+        assert!(truncated);
+        assert!(markdown.ends_with("[... session export truncated due to size limit ...]\n"));
+    }
+
+    #[test]
+    fn find_session_file_searches_date_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let day = dir.path().join("2025/10/01");
+        std::fs::create_dir_all(&day).unwrap();
+        let file = day.join("rollout-2025-10-01T10-00-00-abc-123.jsonl");
+        std::fs::write(&file, "").unwrap();
+
+        assert_eq!(find_session_file(dir.path(), "abc-123"), Some(file));
+        assert_eq!(find_session_file(dir.path(), "missing"), None);
+    }
+}
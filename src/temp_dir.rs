@@ -0,0 +1,98 @@
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Environment variable selecting the base directory for per-call temporary files
+pub const TEMP_DIR_ENV: &str = "CODEX_TEMP_DIR";
+
+/// Pure function to resolve the temp base directory from an environment variable value.
+/// Empty values are treated as unset and fall back to the system temp directory.
+fn resolve_temp_base(env_val: Option<OsString>) -> PathBuf {
+    match env_val {
+        Some(val) if !val.is_empty() => PathBuf::from(val),
+        _ => std::env::temp_dir(),
+    }
+}
+
+/// Base directory for per-call temp dirs: CODEX_TEMP_DIR if set, else the system temp directory
+pub fn temp_base_dir() -> PathBuf {
+    resolve_temp_base(std::env::var_os(TEMP_DIR_ENV))
+}
+
+/// A temporary directory scoped to a single tool call.
+/// The directory and everything in it is removed when the guard is dropped, including on
+/// early returns and errors.
+#[derive(Debug)]
+pub struct CallTempDir {
+    path: PathBuf,
+}
+
+impl CallTempDir {
+    /// Create a per-call directory under the configured base directory
+    pub fn new() -> Result<Self> {
+        Self::new_in(&temp_base_dir())
+    }
+
+    /// Create a per-call directory under `base`, creating `base` itself if needed
+    pub fn new_in(base: &Path) -> Result<Self> {
+        let path = base.join(format!("codex-mcp-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create temp directory {}", path.display()))?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for CallTempDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                eprintln!(
+                    "Warning: Failed to remove temp directory {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_temp_base_uses_configured_dir() {
+        assert_eq!(
+            resolve_temp_base(Some(OsString::from("/custom/tmp"))),
+            PathBuf::from("/custom/tmp")
+        );
+    }
+
+    #[test]
+    fn resolve_temp_base_falls_back_to_system_temp() {
+        assert_eq!(resolve_temp_base(None), std::env::temp_dir());
+        assert_eq!(
+            resolve_temp_base(Some(OsString::new())),
+            std::env::temp_dir()
+        );
+    }
+
+    #[test]
+    fn call_temp_dir_is_created_under_base_and_removed_on_drop() {
+        let base = tempfile::tempdir().unwrap();
+
+        let call_dir = CallTempDir::new_in(base.path()).unwrap();
+        let path = call_dir.path().to_path_buf();
+        assert!(path.starts_with(base.path()));
+        std::fs::write(path.join("artifact.bin"), b"data").unwrap();
+        assert!(path.join("artifact.bin").exists());
+
+        drop(call_dir);
+        assert!(!path.exists());
+        assert!(base.path().exists());
+    }
+}
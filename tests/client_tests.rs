@@ -0,0 +1,53 @@
+use codex_mcp_rs::{CodexClient, SandboxPolicy};
+
+mod common;
+
+#[tokio::test]
+async fn test_client_runs_and_resumes_tasks_against_fake_codex() {
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"client-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"client done"}}"#,
+    )
+    .await;
+
+    let client = CodexClient::new()
+        .with_sandbox(SandboxPolicy::WorkspaceWrite)
+        .with_model("gpt-5-codex")
+        .with_timeout_secs(30)
+        .with_skip_git_repo_check(true);
+
+    let result = client
+        .run_task("summarize", temp_dir.path())
+        .await
+        .expect("run_task should return Ok");
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.session_id, "client-session");
+    assert_eq!(result.agent_messages, "client done");
+
+    let resumed = client
+        .resume_task(&result.session_id, "and now the tests", temp_dir.path())
+        .await
+        .expect("resume_task should return Ok");
+    assert!(resumed.success, "unexpected error: {:?}", resumed.error);
+    assert_eq!(resumed.agent_messages, "client done");
+}
+
+#[test]
+fn test_client_options_carry_client_settings() {
+    let opts = CodexClient::new()
+        .with_sandbox(SandboxPolicy::WorkspaceWrite)
+        .with_profile("fast")
+        .with_max_retries(2)
+        .options("prompt", "/tmp")
+        .with_session_id("session-1")
+        .build();
+
+    assert_eq!(opts.sandbox, SandboxPolicy::WorkspaceWrite);
+    assert_eq!(opts.profile.as_deref(), Some("fast"));
+    assert_eq!(opts.max_retries, 2);
+    assert_eq!(opts.session_id.as_deref(), Some("session-1"));
+    assert!(!opts.skip_git_repo_check);
+}
@@ -1,6 +1,11 @@
 // Common test utilities and helpers
+#![allow(dead_code)]
 
-use std::path::PathBuf;
+use codex_mcp_rs::codex::{Options, SandboxPolicy};
+use std::path::{Path, PathBuf};
+
+/// Serializes tests that point CODEX_BIN at a fake binary, since the variable is process-global
+pub static CODEX_BIN_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
 
 /// Get a temporary directory for testing
 pub fn get_temp_dir() -> PathBuf {
@@ -8,18 +13,31 @@ pub fn get_temp_dir() -> PathBuf {
 }
 
 /// Create a test options with default values
-pub fn create_test_options(prompt: &str, working_dir: &str) -> codex_mcp_rs::codex::Options {
-    codex_mcp_rs::codex::Options {
-        prompt: prompt.to_string(),
-        working_dir: working_dir.to_string(),
-        sandbox: "read-only".to_string(),
-        session_id: None,
-        skip_git_repo_check: true,
-        return_all_messages: false,
-        image_paths: vec![],
-        model: None,
-        yolo: false,
-        profile: None,
+pub fn create_test_options(prompt: &str, working_dir: &Path) -> Options {
+    Options::builder(prompt, working_dir)
+        .with_skip_git_repo_check(true)
+        .build()
+}
+
+/// Points CODEX_BIN at the bundled fake_codex binary running `scenario` (see src/bin/fake_codex.rs)
+/// while holding CODEX_BIN_LOCK. The environment is restored when the guard is dropped.
+pub struct FakeCodex {
+    _lock: tokio::sync::MutexGuard<'static, ()>,
+}
+
+impl FakeCodex {
+    pub async fn install(scenario: &str) -> Self {
+        let lock = CODEX_BIN_LOCK.lock().await;
+        std::env::set_var("CODEX_BIN", env!("CARGO_BIN_EXE_fake_codex"));
+        std::env::set_var("FAKE_CODEX_SCENARIO", scenario);
+        Self { _lock: lock }
+    }
+}
+
+impl Drop for FakeCodex {
+    fn drop(&mut self) {
+        std::env::remove_var("CODEX_BIN");
+        std::env::remove_var("FAKE_CODEX_SCENARIO");
     }
 }
 
@@ -29,7 +47,7 @@ pub fn generate_mock_session_id() -> String {
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
-        .as_secs();
+        .as_nanos();
     format!("test-session-{}", timestamp)
 }
 
@@ -46,10 +64,10 @@ mod tests {
 
     #[test]
     fn test_create_test_options() {
-        let opts = create_test_options("test prompt", "/tmp");
+        let opts = create_test_options("test prompt", Path::new("/tmp"));
         assert_eq!(opts.prompt, "test prompt");
-        assert_eq!(opts.working_dir, "/tmp");
-        assert_eq!(opts.sandbox, "read-only");
+        assert_eq!(opts.working_dir, PathBuf::from("/tmp"));
+        assert_eq!(opts.sandbox, SandboxPolicy::ReadOnly);
     }
 
     #[test]
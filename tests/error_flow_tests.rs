@@ -4,6 +4,8 @@ use serde_json::Value;
 use std::collections::HashMap;
 use std::path::PathBuf;
 
+mod common;
+
 #[test]
 fn test_agent_messages_size_limit() {
     // Create a mock result that would exceed the agent messages limit
@@ -12,11 +14,8 @@ fn test_agent_messages_size_limit() {
         success: true,
         session_id: "test-session".to_string(),
         agent_messages: large_message,
-        agent_messages_truncated: false,
-        all_messages: Vec::new(),
-        all_messages_truncated: false,
-        error: None,
-        warnings: None,
+        attempts: 1,
+        ..Default::default()
     };
 
     // The agent_messages should be truncatable in practice
@@ -31,10 +30,8 @@ fn test_agent_messages_truncation_flag() {
         session_id: "test-session".to_string(),
         agent_messages: "[... Agent messages truncated due to size limit ...]".to_string(),
         agent_messages_truncated: true,
-        all_messages: Vec::new(),
-        all_messages_truncated: false,
-        error: None,
-        warnings: None,
+        attempts: 1,
+        ..Default::default()
     };
 
     assert!(result.agent_messages_truncated);
@@ -48,11 +45,8 @@ fn test_all_messages_limit() {
         success: true,
         session_id: "test-session".to_string(),
         agent_messages: "test messages".to_string(),
-        agent_messages_truncated: false,
-        all_messages: Vec::new(),
-        all_messages_truncated: false,
-        error: None,
-        warnings: None,
+        attempts: 1,
+        ..Default::default()
     };
 
     // Simulate adding messages up to limit
@@ -75,14 +69,12 @@ fn test_all_messages_limit() {
 #[test]
 fn test_error_and_warning_handling() {
     let result = CodexResult {
-        success: false,
         session_id: "".to_string(),
         agent_messages: "".to_string(),
-        agent_messages_truncated: false,
-        all_messages: Vec::new(),
-        all_messages_truncated: false,
         error: Some("Test error message".to_string()),
         warnings: Some("Test warning message".to_string()),
+        attempts: 1,
+        ..Default::default()
     };
 
     assert!(!result.success);
@@ -99,16 +91,9 @@ fn test_path_handling_with_non_utf8() {
     let opts = Options {
         prompt: "test".to_string(),
         working_dir: non_utf8_path.clone(),
-        sandbox: SandboxPolicy::ReadOnly,
-        session_id: None,
         skip_git_repo_check: true,
-        return_all_messages: false,
-        return_all_messages_limit: None,
         image_paths: vec![non_utf8_path.clone()],
-        model: None,
-        yolo: false,
-        profile: None,
-        timeout_secs: None,
+        ..Default::default()
     };
 
     // Should be able to create options without panicking
@@ -149,7 +134,7 @@ fn test_server_security_restrictions() {
     let args = codex_mcp_rs::server::CodexArgs {
         prompt: "test".to_string(),
         cd: PathBuf::from("/tmp"),
-        sandbox: SandboxPolicy::DangerFullAccess,
+        sandbox: Some(SandboxPolicy::DangerFullAccess),
         session_id: None,
         skip_git_repo_check: true,
         return_all_messages: false,
@@ -159,6 +144,50 @@ fn test_server_security_restrictions() {
         yolo: true,
         profile: None,
         timeout_secs: None,
+        idle_timeout_secs: None,
+        return_boundary_events: false,
+        compress_output: false,
+        color: None,
+        response_schema: None,
+        agents_as_system_flag: false,
+        annotate_timestamps: false,
+        max_retries: None,
+        agent_message_join: Default::default(),
+        always_warnings: false,
+        force_new_session: false,
+        prompt_b64: None,
+        return_delta: false,
+        post_process_cmd: None,
+        capture_resource_usage: false,
+        output_as_resource: false,
+        full_auto: false,
+        capability_token: None,
+        return_raw_stdout: false,
+        return_on_first_message: false,
+        normalize_ids: false,
+        return_todos: false,
+        use_login_shell: false,
+        plain_text_output: false,
+        after_message_index: None,
+        all_messages_exclude_types: Vec::new(),
+        all_messages_include_types: Vec::new(),
+        capture_git_head: false,
+        provider_headers: HashMap::new(),
+        agent_truncate_from: Default::default(),
+        stdout_encoding: None,
+        agents_absolute_max: None,
+        template: None,
+        variables: HashMap::new(),
+        allow_unresolved: false,
+        oss: false,
+        verify_cmd: None,
+        verify_affects_success: false,
+        fields: Vec::new(),
+        include_fingerprint: false,
+        stop_sequences: Vec::new(),
+        return_output_images: false,
+        context_files: Vec::new(),
+        use_git_root: false,
     };
 
     // Simulate security config that disallows dangerous features
@@ -166,12 +195,23 @@ fn test_server_security_restrictions() {
         allow_danger_full_access: false,
         allow_yolo: false,
         allow_skip_git_check: false,
+        block_dirty_tree: false,
+        allow_post_process: false,
+        allowed_models: None,
+        downgrade_disallowed_models: false,
+        allow_long_timeout: false,
+        strict_security: false,
+        allow_provider_headers: false,
+        allow_verify_cmd: false,
+        auto_skip_git_check: false,
     };
 
-    let (restricted_args, warnings) = server.apply_security_restrictions(args, &security);
+    let (restricted_args, warnings) = server
+        .apply_security_restrictions(args, &security)
+        .expect("lenient mode downgrades instead of failing");
 
     // Should be downgraded to safe defaults
-    assert_eq!(restricted_args.sandbox, SandboxPolicy::ReadOnly);
+    assert_eq!(restricted_args.sandbox, Some(SandboxPolicy::ReadOnly));
     assert!(!restricted_args.yolo);
     assert!(!restricted_args.skip_git_repo_check);
     // Should have warnings about the downgrades
@@ -182,52 +222,21 @@ fn test_server_security_restrictions() {
 async fn test_timeout_error_shape() {
     // Test that timeout produces proper error structure without validation noise
     use codex_mcp_rs::codex;
-    use std::env;
     use tempfile::tempdir;
 
     // Create a temporary directory for the test
     let temp_dir = tempdir().expect("Failed to create temp dir");
     let temp_path = temp_dir.path().to_path_buf();
 
-    // Create a simple shell script that sleeps
-    #[cfg(not(target_os = "windows"))]
-    {
-        use std::fs;
-        use std::os::unix::fs::PermissionsExt;
-
-        let script_path = temp_path.join("sleep_script.sh");
-        fs::write(&script_path, "#!/bin/sh\nsleep 10\n").expect("Failed to write script");
-        let mut perms = fs::metadata(&script_path)
-            .expect("Failed to get metadata")
-            .permissions();
-        perms.set_mode(0o755);
-        fs::set_permissions(&script_path, perms).expect("Failed to set permissions");
-
-        env::set_var("CODEX_BIN", script_path.to_str().unwrap());
-    }
-
-    #[cfg(target_os = "windows")]
-    {
-        use std::fs;
-        let script_path = temp_path.join("sleep_script.bat");
-        fs::write(&script_path, "@echo off\ntimeout /t 10 /nobreak\n")
-            .expect("Failed to write script");
-        env::set_var("CODEX_BIN", script_path.to_str().unwrap());
-    }
+    // A fake codex that sleeps well past the timeout
+    let _fake = common::FakeCodex::install("sleep 10000").await;
 
     let opts = Options {
         prompt: "test".to_string(),
         working_dir: temp_path.clone(),
-        sandbox: SandboxPolicy::ReadOnly,
-        session_id: None,
         skip_git_repo_check: true,
-        return_all_messages: false,
-        return_all_messages_limit: None,
-        image_paths: vec![],
-        model: None,
-        yolo: false,
-        profile: None,
         timeout_secs: Some(1), // 1 second timeout
+        ..Default::default()
     };
 
     let result = codex::run(opts).await.expect("run should return Ok");
@@ -258,7 +267,1887 @@ async fn test_timeout_error_shape() {
         result.warnings.is_none(),
         "timeout should not generate validation warnings"
     );
+}
+
+// On Windows codex runs under cmd.exe, and killing the shell leaves the fake binary holding the pipes
+#[cfg(unix)]
+#[tokio::test]
+async fn test_idle_timeout_kills_silent_child() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    // Print one event, then go silent well past the idle window
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"idle-session"}
+sleep 30000"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.timeout_secs = Some(20);
+    opts.idle_timeout_secs = Some(1);
+
+    let started = std::time::Instant::now();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    assert!(
+        started.elapsed() < std::time::Duration::from_secs(10),
+        "idle timeout should fire long before the total timeout"
+    );
+    let error_msg = result.error.expect("idle timeout should set an error");
+    assert!(
+        error_msg.contains("no output for 1 seconds"),
+        "unexpected error: {}",
+        error_msg
+    );
+    assert_eq!(result.session_id, "idle-session");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_idle_timeout_records_a_single_error() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install("sleep 30000").await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.timeout_secs = Some(20);
+    opts.idle_timeout_secs = Some(1);
+
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    // The kill we sent must not add a signal exit (or an OOM guess) next to the idle timeout
+    assert_eq!(result.errors.len(), 1, "errors: {:?}", result.errors);
+    assert!(result.errors[0].contains("idle timeout"));
+}
+
+#[tokio::test]
+async fn test_boundary_events_capture_first_and_last() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"boundary-session"}
+emit {"type":"turn.started"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}
+emit {"type":"turn.completed"}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.return_boundary_events = true;
+
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    // Boundary capture must not depend on return_all_messages
+    assert!(result.all_messages.is_empty());
+
+    let first = result.first_event.expect("first event should be captured");
+    let last = result.last_event.expect("last event should be captured");
+    assert_eq!(first["type"], "thread.started");
+    assert_eq!(first["thread_id"], "boundary-session");
+    assert_eq!(last["type"], "turn.completed");
+}
+
+#[tokio::test]
+async fn test_plan_steps_parse_from_fake_output() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"plan-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"Plan:\n1. Inspect the parser\n2. Add a failing test\n3. Fix the bug"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("fix the parser", temp_dir.path());
+    opts.prompt = format!("{}{}", codex::PLAN_INSTRUCTION, opts.prompt);
+
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(
+        codex::parse_plan_steps(&result.agent_messages),
+        vec!["Inspect the parser", "Add a failing test", "Fix the bug"]
+    );
+}
+
+#[tokio::test]
+async fn test_annotate_timestamps_are_present_and_monotonic() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"ts-session"}
+sleep 50
+emit {"type":"item.completed","item":{"type":"agent_message","text":"one"}}
+sleep 50
+emit {"type":"turn.completed"}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.return_all_messages = true;
+    opts.annotate_timestamps = true;
+
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.all_messages.len(), 3);
+    let stamps: Vec<u64> = result
+        .all_messages
+        .iter()
+        .map(|m| {
+            m["_received_ms"]
+                .as_u64()
+                .expect("_received_ms should be set")
+        })
+        .collect();
+    assert!(stamps.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(stamps[2] >= stamps[0] + 50);
+}
+
+#[tokio::test]
+async fn test_retries_stop_within_total_budget() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    // Always fails with an error that looks transient
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"error","message":"rate limit exceeded (429)"}
+exit 1"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.timeout_secs = Some(2);
+    opts.max_retries = 5;
+
+    let started = std::time::Instant::now();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(
+        started.elapsed() < std::time::Duration::from_secs(3),
+        "retries must not outlive the total timeout"
+    );
+    assert!(!result.success);
+    let error_msg = result.error.expect("exhausted retries should set an error");
+    assert!(
+        error_msg.contains("Retry budget exhausted"),
+        "unexpected error: {}",
+        error_msg
+    );
+    assert!(error_msg.contains("rate limit exceeded"));
+}
+
+#[tokio::test]
+async fn test_agent_message_join_none_concatenates_chunks() {
+    use codex_mcp_rs::codex::{self, AgentMessageJoin};
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"chunk-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"```rust\nfn ma"}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"in() {}\n"}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"```"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.agent_message_join = AgentMessageJoin::None;
+
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.agent_messages, "```rust\nfn main() {}\n```");
+}
+
+#[tokio::test]
+async fn test_final_answer_is_extracted_from_agent_messages() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    {
+        let _fake = common::FakeCodex::install(
+            r#"emit {"type":"thread.started","thread_id":"final-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"Looking at the code"}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"The bug is fixed."}}"#,
+        )
+        .await;
+        let opts = common::create_test_options("test", temp_dir.path());
+        let result = codex::run(opts).await.expect("run should return Ok");
+        assert_eq!(result.final_answer.as_deref(), Some("The bug is fixed."));
+        assert_eq!(
+            result.agent_messages,
+            "Looking at the code\nThe bug is fixed."
+        );
+    }
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"final-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"Answer: 42","final":true}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"Let me know if that helps"}}"#,
+    )
+    .await;
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+    assert_eq!(result.final_answer.as_deref(), Some("Answer: 42"));
+}
+
+#[tokio::test]
+async fn test_agent_messages_sink_receives_messages_in_order() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let sink = temp_dir.path().join("progress.log");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"sink-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"Reading files"}}
+emit {"type":"item.completed","item":{"type":"command_execution","command":"ls"}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"Running tests"}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"All done"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.agent_messages_sink = Some(sink.clone());
+    let result = codex::run(opts.clone())
+        .await
+        .expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(
+        std::fs::read_to_string(&sink).unwrap(),
+        "Reading files\nRunning tests\nAll done\n"
+    );
+    assert_eq!(
+        result.agent_messages,
+        "Reading files\nRunning tests\nAll done"
+    );
+
+    opts.agent_messages_sink_only = true;
+    let result = codex::run(opts).await.expect("run should return Ok");
+    assert!(result.agent_messages.is_empty());
+    assert!(std::fs::read_to_string(&sink)
+        .unwrap()
+        .ends_with("All done\nReading files\nRunning tests\nAll done\n"));
+}
+
+#[tokio::test]
+async fn test_ttft_measures_delay_before_first_agent_message() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    {
+        let _fake = common::FakeCodex::install(
+            r#"emit {"type":"thread.started","thread_id":"ttft-session"}
+sleep 300
+emit {"type":"item.completed","item":{"type":"agent_message","text":"first"}}
+sleep 1000
+emit {"type":"item.completed","item":{"type":"agent_message","text":"second"}}"#,
+        )
+        .await;
+        let opts = common::create_test_options("test", temp_dir.path());
+        let result = codex::run(opts).await.expect("run should return Ok");
+
+        let ttft = result.ttft_ms.expect("an agent message arrived");
+        assert!(ttft >= 300, "ttft {}ms is shorter than the delay", ttft);
+        assert!(ttft < 1300, "ttft {}ms includes the second message", ttft);
+    }
+
+    let _fake =
+        common::FakeCodex::install(r#"emit {"type":"thread.started","thread_id":"ttft-session"}"#)
+            .await;
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+    assert_eq!(result.ttft_ms, None);
+}
+
+#[tokio::test]
+async fn test_cancelled_run_returns_session_id() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+    use tokio_util::sync::CancellationToken;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"cancel-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"Starting"}}
+sleep 10000"#,
+    )
+    .await;
+
+    let token = CancellationToken::new();
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.cancel = Some(token.clone());
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        token.cancel();
+    });
+
+    let started = std::time::Instant::now();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert!(!result.success);
+    assert!(result.interrupted);
+    assert_eq!(result.session_id, "cancel-session");
+    assert_eq!(result.agent_messages, "Starting");
+    assert!(result
+        .error
+        .unwrap()
+        .contains("resume session cancel-session"));
+}
+
+/// EventSink that records each hook call, for asserting their order
+#[derive(Debug, Default)]
+struct RecordingSink {
+    calls: std::sync::Mutex<Vec<String>>,
+}
+
+impl codex_mcp_rs::events::EventSink for RecordingSink {
+    fn on_run_start(&self, _opts: &Options) {
+        self.calls.lock().unwrap().push("start".to_string());
+    }
+
+    fn on_event(&self, event: &Value) {
+        let event_type = event["type"].as_str().unwrap_or_default();
+        self.calls
+            .lock()
+            .unwrap()
+            .push(format!("event {}", event_type));
+    }
+
+    fn on_run_end(&self, summary: &codex_mcp_rs::events::RunSummary) {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(format!("end {} {}", summary.success, summary.session_id));
+    }
+}
+
+#[tokio::test]
+async fn test_event_sink_hooks_fire_in_order() {
+    use codex_mcp_rs::codex;
+    use codex_mcp_rs::server::{CodexArgs, CodexServer};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"sink-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}
+emit {"type":"turn.completed"}"#,
+    )
+    .await;
+
+    let sink = Arc::new(RecordingSink::default());
+    let server = CodexServer::new().with_event_sink(sink.clone());
+    let (opts, _) = server
+        .prepare_options(CodexArgs {
+            prompt: "test".to_string(),
+            cd: temp_dir.path().to_path_buf(),
+            ..Default::default()
+        })
+        .expect("arguments are valid");
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(
+        *sink.calls.lock().unwrap(),
+        [
+            "start",
+            "event thread.started",
+            "event item.completed",
+            "event turn.completed",
+            "end true sink-session",
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_verify_cmd_reports_and_can_fail_the_run() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"verify-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.verify_cmd = Some(vec!["true".to_string()]);
+    let result = codex::run(opts.clone())
+        .await
+        .expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    let verification = result.verification.expect("verify_cmd should be reported");
+    assert!(verification.passed);
+    assert_eq!(verification.exit_code, Some(0));
+
+    // A failing check only warns by default
+    opts.verify_cmd = Some(vec!["false".to_string()]);
+    let result = codex::run(opts.clone())
+        .await
+        .expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert!(
+        !result
+            .verification
+            .expect("verify_cmd should be reported")
+            .passed
+    );
+    assert!(result
+        .warnings
+        .expect("failed verification should warn")
+        .contains("verify_cmd 'false' failed"));
+
+    opts.verify_affects_success = true;
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    assert_eq!(result.session_id, "verify-session");
+    assert_eq!(result.agent_messages, "done");
+    assert_eq!(
+        result
+            .verification
+            .expect("verify_cmd should be reported")
+            .exit_code,
+        Some(1)
+    );
+    assert!(result
+        .error
+        .expect("failed verification should be an error")
+        .contains("verify_cmd 'false' failed"));
+}
+
+#[tokio::test]
+async fn test_large_stdout_and_stderr_are_drained_together() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    // About 1.5MB on each pipe, far beyond the OS pipe buffers and over the 1MB stderr cap
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"flood-session"}
+flood 1500 1024"#,
+    )
+    .await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = tokio::time::timeout(std::time::Duration::from_secs(30), codex::run(opts))
+        .await
+        .expect("draining both pipes should not deadlock")
+        .expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.session_id, "flood-session");
+    assert!(!result.agent_messages_truncated);
+    assert_eq!(
+        result.agent_messages.matches(&"o".repeat(1024)).count(),
+        1500
+    );
+    let warnings = result.warnings.expect("stderr should be reported");
+    assert!(warnings.contains(&"e".repeat(1024)));
+    assert!(warnings.contains("[... stderr truncated due to size limit ...]"));
+}
+
+#[tokio::test]
+async fn test_agent_message_strip_prefix_removes_tag() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"prefix-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"assistant> first"}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"untagged"}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"assistant> last"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts.clone())
+        .await
+        .expect("run should return Ok");
+    assert_eq!(
+        result.agent_messages,
+        "assistant> first\nuntagged\nassistant> last"
+    );
+
+    opts.agent_message_strip_prefix = Some("assistant> ".to_string());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.agent_messages, "first\nuntagged\nlast");
+    assert_eq!(result.final_answer.as_deref(), Some("last"));
+}
+
+#[tokio::test]
+async fn test_stop_sequence_terminates_run_early() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"stop-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"reading config"}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"found BEGIN PRIVATE KEY in .env"}}
+sleep 10000
+emit {"type":"item.completed","item":{"type":"agent_message","text":"never seen"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.stop_sequences = vec!["BEGIN PRIVATE KEY".to_string()];
+    let started = std::time::Instant::now();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(
+        started.elapsed() < std::time::Duration::from_secs(5),
+        "run should stop at the stop sequence"
+    );
+    assert!(result.interrupted);
+    assert_eq!(result.session_id, "stop-session");
+    assert!(!result.agent_messages.contains("never seen"));
+    assert!(result
+        .warnings
+        .expect("the stop should be explained")
+        .contains("stop sequence 'BEGIN PRIVATE KEY'"));
+}
+
+#[tokio::test]
+async fn test_changing_thread_id_is_warned_about() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"first-thread"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"working"}}
+emit {"type":"thread.started","thread_id":"second-thread"}
+emit {"type":"turn.completed","thread_id":"second-thread"}"#,
+    )
+    .await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.session_id, "second-thread");
+    assert!(result
+        .warnings
+        .expect("the thread_id change should warn")
+        .contains("several thread_ids in one run (first-thread, second-thread)"));
+}
+
+#[tokio::test]
+async fn test_output_images_are_collected_and_validated() {
+    use codex_mcp_rs::codex::{self, OutputImage};
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    std::fs::write(temp_dir.path().join("diagram.png"), b"\x89PNG\r\n").unwrap();
+    std::fs::write(temp_dir.path().join("notes.txt"), "not an image").unwrap();
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"image-session"}
+emit {"type":"item.completed","item":{"type":"image","path":"diagram.png"}}
+emit {"type":"item.completed","item":{"type":"file_output","path":"notes.txt"}}
+emit {"type":"item.completed","item":{"type":"image","path":"missing.png"}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"drew it"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts.clone())
+        .await
+        .expect("run should return Ok");
+    assert!(result.output_images.is_empty());
+
+    opts.return_output_images = true;
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(
+        result.output_images,
+        vec![OutputImage {
+            path: temp_dir.path().join("diagram.png"),
+            mime: "image/png".to_string(),
+        }]
+    );
+    let warnings = result.warnings.expect("the missing image should warn");
+    assert!(warnings.contains("missing.png cannot be read"));
+    assert!(!warnings.contains("notes.txt"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_verify_cmd_shares_the_run_timeout() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"slow-verify"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.timeout_secs = Some(2);
+    opts.verify_cmd = Some(vec!["sleep".to_string(), "30".to_string()]);
+    let started = std::time::Instant::now();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    assert_eq!(result.session_id, "slow-verify");
+    assert_eq!(result.agent_messages, "done");
+    let verification = result.verification.expect("verify_cmd should be reported");
+    assert!(!verification.passed);
+    assert_eq!(verification.exit_code, None);
+    assert!(verification.output.contains("timeout expired"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unreadable_agents_md_aborts_when_fatal() {
+    use codex_mcp_rs::codex;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let agents_md = temp_dir.path().join("AGENTS.md");
+    std::fs::write(&agents_md, "Be terse.").unwrap();
+    std::fs::set_permissions(&agents_md, std::fs::Permissions::from_mode(0o000)).unwrap();
+    if std::fs::read(&agents_md).is_ok() {
+        // Permissions are not enforced (e.g. running as root), so the file cannot be made unreadable
+        return;
+    }
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"agents-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts.clone())
+        .await
+        .expect("run should return Ok");
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert!(result
+        .warnings
+        .expect("the unreadable AGENTS.md should warn")
+        .contains("continuing without it"));
+
+    opts.agents_read_error_fatal = true;
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    assert!(result.session_id.is_empty(), "codex should not have run");
+    let error = result.error.expect("the abort should be explained");
+    assert!(error.contains("AGENTS.md"), "unexpected error: {}", error);
+    assert!(error.contains("agents_read_error_fatal"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_killed_by_signal_is_reported() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"oom-session"}
+kill"#,
+    )
+    .await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    let error_msg = result
+        .error
+        .expect("signal termination should set an error");
+    assert!(
+        error_msg.contains("terminated by signal 9"),
+        "unexpected error: {}",
+        error_msg
+    );
+}
+
+#[tokio::test]
+async fn test_force_new_session_drops_resume() {
+    use codex_mcp_rs::codex;
+    use codex_mcp_rs::server::{CodexArgs, CodexServer};
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit-args
+emit {"type":"thread.started","thread_id":"fresh-session"}"#,
+    )
+    .await;
+
+    let args: CodexArgs = serde_json::from_value(serde_json::json!({
+        "PROMPT": "test",
+        "cd": temp_dir.path(),
+        "SESSION_ID": "old-session",
+        "return_all_messages": true,
+        "force_new_session": true,
+    }))
+    .expect("args should deserialize");
+
+    let (opts, warnings) = CodexServer::new()
+        .prepare_options(args)
+        .expect("options should be valid");
+    assert!(opts.session_id.is_none());
+    assert!(warnings.iter().any(|w| w.contains("force_new_session")));
+
+    let result = codex::run(opts).await.expect("run should return Ok");
+    assert!(result.success, "unexpected error: {:?}", result.error);
+
+    let child_args = result
+        .all_messages
+        .iter()
+        .find(|event| event.get("type").and_then(Value::as_str) == Some("fake_codex.args"))
+        .and_then(|event| event.get("args"))
+        .and_then(Value::as_array)
+        .expect("fake codex should report its arguments");
+    assert!(!child_args.iter().any(|arg| arg == "resume"));
+    assert!(!child_args.iter().any(|arg| arg == "old-session"));
+}
+
+#[tokio::test]
+async fn test_rate_limit_retry_after_is_parsed() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"limited-session"}
+emit {"type":"turn.failed","error":{"message":"Rate limit exceeded","retry_after":42}}
+exit 1"#,
+    )
+    .await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    assert_eq!(result.retry_after_secs, Some(42));
+}
+
+#[tokio::test]
+async fn test_nonzero_exit_keeps_partial_messages() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"partial-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"Half of the work is done"}}
+exit 1"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.return_all_messages = true;
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    assert_eq!(result.exit_code, Some(1));
+    assert_eq!(result.agent_messages, "Half of the work is done");
+    assert_eq!(result.all_messages.len(), 2);
+    assert!(result.error.unwrap().contains("exit code"));
+}
+
+#[tokio::test]
+async fn test_delta_offset_returns_only_current_turn() {
+    use codex_mcp_rs::codex::{self, TurnOffset};
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    // A resumed session that replays the first turn before producing the second
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"delta-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"first answer"}}
+emit {"type":"turn.completed"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"second answer"}}
+emit {"type":"turn.completed"}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.session_id = Some("delta-session".to_string());
+    opts.return_all_messages = true;
+    opts.delta_offset = TurnOffset {
+        agent_messages: 1,
+        events: 3,
+    };
+
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.session_id, "delta-session");
+    assert_eq!(result.agent_messages, "second answer");
+    assert_eq!(result.all_messages.len(), 2);
+    assert_eq!(
+        result.turn_offset,
+        TurnOffset {
+            agent_messages: 2,
+            events: 5,
+        }
+    );
+}
+
+/// A completed agent_message whose text contains the invalid UTF-8 byte 0xFF, hex-encoded for
+/// the fake binary's emit-hex directive
+fn invalid_utf8_agent_message_hex() -> String {
+    let mut line =
+        br#"{"type":"item.completed","item":{"type":"agent_message","text":"bad "#.to_vec();
+    line.push(0xFF);
+    line.extend_from_slice(br#" byte"}}"#);
+    line.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[tokio::test]
+async fn test_invalid_utf8_is_replaced_in_lossy_mode() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let scenario = format!(
+        "emit {{\"type\":\"thread.started\",\"thread_id\":\"utf8-session\"}}\nemit-hex {}",
+        invalid_utf8_agent_message_hex()
+    );
+    let _fake = common::FakeCodex::install(&scenario).await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.agent_messages, "bad \u{FFFD} byte");
+}
+
+#[tokio::test]
+async fn test_invalid_utf8_in_complete_line_is_reported() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let scenario = format!(
+        "emit {{\"type\":\"thread.started\",\"thread_id\":\"utf8-session\"}}\nemit-hex {}",
+        invalid_utf8_agent_message_hex()
+    );
+    let _fake = common::FakeCodex::install(&scenario).await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    let warnings = result.warnings.expect("replaced bytes should be reported");
+    // The 0xFF byte follows the 68-byte JSON prefix
+    assert!(
+        warnings.contains("invalid UTF-8 after byte 68 of a complete line"),
+        "unexpected warnings: {}",
+        warnings
+    );
+}
+
+#[tokio::test]
+async fn test_invalid_utf8_is_a_parse_error_in_strict_mode() {
+    use codex_mcp_rs::codex::{self, Utf8Mode};
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let scenario = format!(
+        "emit {{\"type\":\"thread.started\",\"thread_id\":\"utf8-session\"}}\nemit-hex {}",
+        invalid_utf8_agent_message_hex()
+    );
+    let _fake = common::FakeCodex::install(&scenario).await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.utf8_mode = Utf8Mode::Strict;
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    assert!(result.agent_messages.is_empty());
+    let error_msg = result.error.expect("invalid UTF-8 should set an error");
+    assert!(
+        error_msg.contains("Invalid UTF-8"),
+        "unexpected error: {}",
+        error_msg
+    );
+    assert!(result
+        .warnings
+        .expect("strict mode should record a warning")
+        .contains("invalid UTF-8"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_post_process_cmd_rewrites_agent_messages() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"post-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"hello world"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.post_process_cmd = Some(vec!["tr".to_string(), "a-z".to_string(), "A-Z".to_string()]);
+    let result = codex::run(opts.clone())
+        .await
+        .expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.agent_messages, "HELLO WORLD");
+
+    // A failing post-processor keeps the original answer and explains why
+    opts.post_process_cmd = Some(vec!["false".to_string()]);
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.agent_messages, "hello world");
+    assert!(result
+        .warnings
+        .expect("failed post-processing should warn")
+        .contains("post_process_cmd 'false'"));
+}
+
+#[tokio::test]
+async fn test_warmup_reports_readiness() {
+    use codex_mcp_rs::server::run_warmup;
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"warmup-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"ready"}}"#,
+    )
+    .await;
+
+    let output = run_warmup().await;
+    assert!(output.ready, "unexpected details: {}", output.details);
+    assert!(output.details.contains("codex answered"));
+}
+
+#[tokio::test]
+async fn test_warmup_reports_failure_details() {
+    use codex_mcp_rs::server::run_warmup;
+
+    let _fake = common::FakeCodex::install(
+        r#"stderr Not logged in
+exit 1"#,
+    )
+    .await;
+
+    let output = run_warmup().await;
+    assert!(!output.ready);
+    assert!(
+        output.details.contains("Not logged in"),
+        "unexpected details: {}",
+        output.details
+    );
+}
+
+#[tokio::test]
+async fn test_check_flag_passes_with_working_codex() {
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"check-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"ready"}}"#,
+    )
+    .await;
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_codex-mcp-rs"))
+        .arg("--check")
+        .output()
+        .await
+        .expect("run codex-mcp-rs --check");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "unexpected stderr: {}", stderr);
+    assert!(stderr.contains("codex binary found"));
+    assert!(stderr.contains("Check PASSED"));
+    assert!(output.stdout.is_empty());
+}
+
+#[tokio::test]
+async fn test_check_flag_fails_when_codex_is_missing() {
+    let _fake = common::FakeCodex::install("").await;
+    std::env::set_var("CODEX_BIN", "/nonexistent/codex-for-check");
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_codex-mcp-rs"))
+        .arg("--check")
+        .output()
+        .await
+        .expect("run codex-mcp-rs --check");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("[fail] codex binary"),
+        "unexpected stderr: {}",
+        stderr
+    );
+    assert!(stderr.contains("Check FAILED"));
+}
+
+#[tokio::test]
+async fn test_startup_probe_fails_when_codex_is_too_slow() {
+    let _fake = common::FakeCodex::install(
+        r#"sleep 5000
+emit {"type":"item.completed","item":{"type":"agent_message","text":"ready"}}"#,
+    )
+    .await;
+
+    let started = std::time::Instant::now();
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_codex-mcp-rs"))
+        .arg("--probe")
+        .env("CODEX_STARTUP_PROBE_SECS", "1")
+        .stdin(std::process::Stdio::null())
+        .output()
+        .await
+        .expect("run codex-mcp-rs --probe");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(started.elapsed() < std::time::Duration::from_secs(4));
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "unexpected stderr: {}",
+        stderr
+    );
+    assert!(
+        stderr.contains("startup probe failed"),
+        "unexpected stderr: {}",
+        stderr
+    );
+    assert!(output.stdout.is_empty());
+}
+
+#[tokio::test]
+async fn test_success_event_types_recognize_completion() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"done-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}
+emit {"type":"task_complete"}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.success_event_types = vec!["turn.completed".to_string(), "task_complete".to_string()];
+    let result = codex::run(opts.clone())
+        .await
+        .expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert!(result.warnings.is_none(), "{:?}", result.warnings);
+
+    // Without a recognized completion event the run still succeeds, with a warning
+    opts.success_event_types = vec!["turn.completed".to_string()];
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert!(result
+        .warnings
+        .expect("missing completion event should warn")
+        .contains("no completion event was observed"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_resource_usage_is_captured() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"busy-session"}
+spin 300
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.capture_resource_usage = true;
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    let usage = result
+        .resource_usage
+        .expect("resource usage should be captured");
+    assert!(usage.user_cpu_ms > 0, "unexpected usage: {:?}", usage);
+    assert!(usage.max_rss_kb > 0, "unexpected usage: {:?}", usage);
+}
+
+#[tokio::test]
+async fn test_errors_lists_parse_error_and_exit_status_separately() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"broken-session"}
+emit this is not json
+exit 3"#,
+    )
+    .await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    assert!(result.errors[0].starts_with("JSON parse error"));
+    // The child may exit on its own, which is listed, or be stopped after the parse error,
+    // depending on timing. A signal exit caused by our own kill is not listed
+    assert!(
+        result.errors[1..].iter().all(|e| e.contains("exit code")),
+        "unexpected errors: {:?}",
+        result.errors
+    );
+    let joined = result.error.expect("error should be set");
+    assert!(joined.contains("JSON parse error"));
+}
+
+#[tokio::test]
+async fn test_return_raw_stdout_matches_codex_output() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let started = r#"{"type":"thread.started","thread_id":"raw-session"}"#;
+    let message = r#"{"type":"item.completed","item":{"type":"agent_message","text":"hi"}}"#;
+    let _fake = common::FakeCodex::install(&format!(
+        "emit {}\nstderr not part of stdout\nemit {}",
+        started, message
+    ))
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.return_raw_stdout = true;
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(
+        result.raw_stdout.as_deref(),
+        Some(format!("{}\n{}\n", started, message).as_str())
+    );
+    assert!(!result.raw_stdout_truncated);
+    assert_eq!(result.agent_messages, "hi");
+}
+
+#[tokio::test]
+async fn test_return_on_first_message_stops_after_first_agent_message() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"first-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"starting"}}
+sleep 2000
+emit {"type":"item.completed","item":{"type":"agent_message","text":"finished"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.return_on_first_message = true;
+    let started = std::time::Instant::now();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert!(result.interrupted);
+    assert_eq!(result.agent_messages, "starting");
+    assert_eq!(result.session_id, "first-session");
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_effective_message_limit_reports_clamped_cap() {
+    use codex_mcp_rs::codex::{self, MAX_MESSAGE_LIMIT};
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"limit-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.return_all_messages = true;
+    opts.return_all_messages_limit = Some(MAX_MESSAGE_LIMIT * 2);
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.effective_message_limit, Some(MAX_MESSAGE_LIMIT));
+    assert_eq!(result.all_messages.len(), 2);
+}
+
+#[tokio::test]
+async fn test_replay_file_is_parsed_like_codex_output() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    // No fake binary: a replay must not spawn codex at all
+    let _lock = common::CODEX_BIN_LOCK.lock().await;
+    std::env::set_var("CODEX_BIN", "/nonexistent/codex-for-replay");
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.replay_file = Some(
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/replay_session.jsonl"),
+    );
+    opts.return_all_messages = true;
+    let result = codex::run(opts).await;
+    std::env::remove_var("CODEX_BIN");
+    let result = result.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.session_id, "0199a213-81c0-7800-8aa1-bbab2a035a53");
+    assert_eq!(
+        result.agent_messages,
+        "The project has a Cargo.toml and a src directory."
+    );
+    assert_eq!(result.all_messages.len(), 7);
+}
+
+#[tokio::test]
+async fn test_normalize_ids_makes_message_ids_strings() {
+    use codex_mcp_rs::codex;
+    use serde_json::Value;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"ids-session"}
+emit {"type":"item.started","item":{"id":7,"type":"command_execution"}}
+emit {"type":"item.completed","item":{"id":"item_8","type":"agent_message","text":"done"}}
+emit {"type":"turn.completed","id":9,"call_id":10.5}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.return_all_messages = true;
+    opts.normalize_ids = true;
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    let messages = &result.all_messages;
+    assert_eq!(messages[1]["item"]["id"], Value::from("7"));
+    assert_eq!(messages[2]["item"]["id"], Value::from("item_8"));
+    assert_eq!(messages[3]["id"], Value::from("9"));
+    assert_eq!(messages[3]["call_id"], Value::from("10.5"));
+    assert_eq!(messages[0]["thread_id"], Value::from("ids-session"));
+}
+
+#[tokio::test]
+async fn test_return_todos_keeps_latest_plan_state() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"todo-session"}
+emit {"type":"item.started","item":{"type":"todo","id":"t1","text":"Read the code","status":"in_progress"}}
+emit {"type":"item.started","item":{"type":"todo","id":"t2","text":"Write a test","status":"pending"}}
+emit {"type":"item.updated","item":{"type":"plan_update","id":"t1","text":"Read the code","status":"completed"}}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}"#,
+    )
+    .await;
+
+    let mut opts = common::create_test_options("test", temp_dir.path());
+    opts.return_todos = true;
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    let todos: Vec<(&str, &str)> = result
+        .todos
+        .iter()
+        .map(|todo| (todo.text.as_str(), todo.status.as_str()))
+        .collect();
+    assert_eq!(
+        todos,
+        vec![("Read the code", "completed"), ("Write a test", "pending")]
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_login_shell_passes_arguments_through_unchanged() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r#"emit-args
+emit {"type":"thread.started","thread_id":"shell-session"}"#,
+    )
+    .await;
+
+    let prompt = "it's \"quoted\" $HOME `id` ; echo *";
+    let mut opts = common::create_test_options(prompt, temp_dir.path());
+    opts.return_all_messages = true;
+    opts.use_login_shell = true;
+    let result = codex::run(opts).await.expect("run should return Ok");
+    assert!(result.success, "unexpected error: {:?}", result.error);
+
+    let child_args = result
+        .all_messages
+        .iter()
+        .find(|event| event.get("type").and_then(Value::as_str) == Some("fake_codex.args"))
+        .and_then(|event| event.get("args"))
+        .and_then(Value::as_array)
+        .expect("fake codex should report its arguments");
+    assert_eq!(child_args.first(), Some(&Value::from("exec")));
+    assert_eq!(child_args.last(), Some(&Value::from(prompt)));
+}
+
+const AGENTS_MD_SCENARIO: &str = r#"emit {"type":"thread.started","thread_id":"agents-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}"#;
+
+#[tokio::test]
+async fn test_agents_md_applied_when_present() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    std::fs::write(temp_dir.path().join("AGENTS.md"), "Be terse.").unwrap();
+    let _fake = common::FakeCodex::install(AGENTS_MD_SCENARIO).await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.agents_md_applied());
+    assert_eq!(
+        result.agents_md_path,
+        Some(temp_dir.path().join("AGENTS.md"))
+    );
+}
+
+#[tokio::test]
+async fn test_agents_md_not_applied_when_oversized() {
+    use codex_mcp_rs::codex::{self, MAX_AGENTS_MD_SIZE};
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    std::fs::write(
+        temp_dir.path().join("AGENTS.md"),
+        "x".repeat(MAX_AGENTS_MD_SIZE as usize + 1),
+    )
+    .unwrap();
+    let _fake = common::FakeCodex::install(AGENTS_MD_SCENARIO).await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.agents_md_applied());
+    assert!(result
+        .warnings
+        .expect("skipped AGENTS.md should warn")
+        .contains("byte limit"));
+}
+
+#[tokio::test]
+async fn test_agents_md_not_applied_when_absent() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(AGENTS_MD_SCENARIO).await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert!(!result.agents_md_applied());
+    assert!(result.agents_md_path.is_none());
+}
+
+#[tokio::test]
+async fn test_batch_runs_each_directory_and_reports_failures_per_entry() {
+    use codex_mcp_rs::server::{BatchArgs, CodexServer};
+    use tempfile::tempdir;
+
+    let first = tempdir().expect("Failed to create temp dir");
+    let second = tempdir().expect("Failed to create temp dir");
+    let missing = first.path().join("does-not-exist");
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"batch-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"batch done"}}"#,
+    )
+    .await;
+
+    let server = CodexServer::new();
+    let output = server
+        .run_batch(BatchArgs {
+            prompt: "summarize".to_string(),
+            cds: vec![
+                first.path().to_path_buf(),
+                second.path().to_path_buf(),
+                missing.clone(),
+            ],
+            sandbox: None,
+            model: None,
+            profile: None,
+            timeout_secs: None,
+            skip_git_repo_check: true,
+        })
+        .await;
+
+    assert_eq!(output.results.len(), 3);
+    for (entry, dir) in output.results.iter().zip([first.path(), second.path()]) {
+        assert_eq!(entry.cd, dir.to_string_lossy());
+        assert!(entry.success(), "unexpected error: {:?}", entry.error);
+        assert_eq!(entry.agent_messages(), Some("batch done"));
+    }
+    let failed = &output.results[2];
+    assert_eq!(failed.cd, missing.to_string_lossy());
+    assert!(!failed.success());
+    assert!(failed.error.is_some());
+}
+
+#[tokio::test]
+async fn test_spawn_jitter_delays_spawn_without_changing_result() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"jitter-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"jittered"}}"#,
+    )
+    .await;
+
+    let opts = codex::Options::builder("test", temp_dir.path())
+        .with_skip_git_repo_check(true)
+        .with_spawn_jitter_ms(20)
+        .build();
+    let started = std::time::Instant::now();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.session_id, "jitter-session");
+    assert_eq!(result.agent_messages, "jittered");
+    assert!(result.spawn_delay_ms <= 20);
+    assert!(started.elapsed() >= std::time::Duration::from_millis(result.spawn_delay_ms));
+}
+
+#[tokio::test]
+async fn test_approval_request_fails_fast_with_clear_error() {
+    use codex_mcp_rs::codex::{self, APPROVAL_UNAVAILABLE_ERROR};
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"approval-session"}
+emit {"type":"exec_approval_request","call_id":"call-1","command":["touch","file"]}
+sleep 30000"#,
+    )
+    .await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let started = std::time::Instant::now();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(
+        started.elapsed() < std::time::Duration::from_secs(10),
+        "approval request should not wait for the timeout"
+    );
+    assert!(!result.success);
+    assert!(result
+        .error
+        .as_deref()
+        .is_some_and(|e| e.starts_with(APPROVAL_UNAVAILABLE_ERROR)));
+    assert_eq!(result.session_id, "approval-session");
+}
+
+#[tokio::test]
+async fn test_plain_text_output_flattens_markdown_and_keeps_original() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r###"emit {"type":"thread.started","thread_id":"plain-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"## Result\n```sh\ncargo test\n```\n- all **green**"}}"###,
+    )
+    .await;
+
+    let opts = codex::Options::builder("test", temp_dir.path())
+        .with_skip_git_repo_check(true)
+        .with_plain_text_output(true)
+        .build();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(
+        result.agent_messages,
+        "## Result\n```sh\ncargo test\n```\n- all **green**"
+    );
+    assert_eq!(
+        result.agent_messages_plain.as_deref(),
+        Some("Result\ncargo test\nall green")
+    );
+}
+
+#[tokio::test]
+async fn test_writable_runs_in_one_directory_serialize() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"locked-session"}
+sleep 500
+emit {"type":"item.completed","item":{"type":"agent_message","text":"written"}}"#,
+    )
+    .await;
+
+    let writable = || {
+        codex::Options::builder("edit", temp_dir.path())
+            .with_sandbox(SandboxPolicy::WorkspaceWrite)
+            .with_skip_git_repo_check(true)
+            .build()
+    };
+    let started = std::time::Instant::now();
+    let (first, second) = tokio::join!(codex::run(writable()), codex::run(writable()));
+    let elapsed = started.elapsed();
+
+    for result in [first, second] {
+        let result = result.expect("run should return Ok");
+        assert!(result.success, "unexpected error: {:?}", result.error);
+        assert_eq!(result.agent_messages, "written");
+    }
+    assert!(
+        elapsed >= std::time::Duration::from_millis(1000),
+        "runs overlapped: both finished in {:?}",
+        elapsed
+    );
+}
+
+#[tokio::test]
+async fn test_after_message_index_skips_messages_already_fetched() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"cursor-session"}
+emit {"type":"turn.started"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"new answer"}}
+emit {"type":"turn.completed"}"#,
+    )
+    .await;
+
+    // A poll resuming the session after the client already received the first two messages
+    let opts = codex::Options::builder("continue", temp_dir.path())
+        .with_skip_git_repo_check(true)
+        .with_session_id("cursor-session")
+        .with_return_all_messages(true)
+        .with_after_message_index(2)
+        .build();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.message_count, Some(4));
+    let types: Vec<_> = result
+        .all_messages
+        .iter()
+        .map(|message| message["type"].as_str().unwrap_or_default())
+        .collect();
+    assert_eq!(types, ["item.completed", "turn.completed"]);
+    assert_eq!(result.agent_messages, "new answer");
+}
+
+#[tokio::test]
+async fn test_deeply_nested_json_line_is_a_clean_parse_error() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let scenario = format!(
+        "emit {{\"type\":\"thread.started\",\"thread_id\":\"nested-session\"}}\nemit {}{}",
+        "[".repeat(10_000),
+        "]".repeat(10_000)
+    );
+    let _fake = common::FakeCodex::install(&scenario).await;
+
+    let opts = common::create_test_options("test", temp_dir.path());
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    let error = result.error.expect("nesting should be reported");
+    assert!(
+        error.contains("JSON parse error: line nests deeper than 64 levels"),
+        "unexpected error: {}",
+        error
+    );
+    assert!(error.len() < 2000, "error should not quote the whole line");
+}
+
+const MIXED_EVENTS_SCENARIO: &str = r#"emit {"type":"thread.started","thread_id":"filter-session"}
+emit {"type":"heartbeat"}
+emit {"type":"item.completed","item":{"type":"command_execution","command":"ls"}}
+emit {"type":"ping"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"filtered"}}
+emit {"type":"heartbeat"}
+emit {"type":"turn.completed"}"#;
+
+fn message_types(result: &CodexResult) -> Vec<&str> {
+    result
+        .all_messages
+        .iter()
+        .map(|message| message["type"].as_str().unwrap_or_default())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_all_messages_exclude_types_drops_noisy_events() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(MIXED_EVENTS_SCENARIO).await;
+
+    let opts = codex::Options::builder("test", temp_dir.path())
+        .with_skip_git_repo_check(true)
+        .with_return_all_messages(true)
+        .with_all_messages_exclude_types(vec!["heartbeat".to_string(), "ping".to_string()])
+        .build();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(
+        message_types(&result),
+        [
+            "thread.started",
+            "item.completed",
+            "item.completed",
+            "turn.completed"
+        ]
+    );
+    assert_eq!(result.agent_messages, "filtered");
+}
+
+#[tokio::test]
+async fn test_old_codex_version_is_warned_and_cached() {
+    use codex_mcp_rs::server::CodexServer;
+
+    let _fake = common::FakeCodex::install("emit codex-cli 0.9.3").await;
+    let mut server = CodexServer::new();
+    let warning = server
+        .check_codex_version()
+        .await
+        .expect("old versions only warn by default")
+        .expect("an old version should be reported");
+
+    assert!(warning.contains("codex 0.9.3 is older than"));
+    assert!(server.config_warnings().contains(&warning));
+    assert_eq!(
+        server.codex_version().map(|v| v.to_string()).as_deref(),
+        Some("0.9.3")
+    );
+}
+
+#[tokio::test]
+async fn test_current_codex_version_is_not_warned() {
+    use codex_mcp_rs::server::CodexServer;
+
+    let _fake = common::FakeCodex::install("emit codex-cli 99.0.0").await;
+    let mut server = CodexServer::new();
+    assert_eq!(server.check_codex_version().await, Ok(None));
+    assert!(server.codex_version().is_some());
+}
+
+#[tokio::test]
+async fn test_all_messages_include_types_keeps_only_listed_types() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(MIXED_EVENTS_SCENARIO).await;
+
+    let opts = codex::Options::builder("test", temp_dir.path())
+        .with_skip_git_repo_check(true)
+        .with_return_all_messages(true)
+        .with_all_messages_include_types(vec![
+            "agent_message".to_string(),
+            "command_execution".to_string(),
+        ])
+        .build();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    let item_types: Vec<_> = result
+        .all_messages
+        .iter()
+        .map(|message| message["item"]["type"].as_str().unwrap_or_default())
+        .collect();
+    assert_eq!(item_types, ["command_execution", "agent_message"]);
+}
+
+#[tokio::test]
+async fn test_all_messages_include_then_exclude_types() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let _fake = common::FakeCodex::install(MIXED_EVENTS_SCENARIO).await;
+
+    let opts = codex::Options::builder("test", temp_dir.path())
+        .with_skip_git_repo_check(true)
+        .with_return_all_messages(true)
+        .with_all_messages_include_types(vec![
+            "item.completed".to_string(),
+            "turn.completed".to_string(),
+        ])
+        .with_all_messages_exclude_types(vec!["command_execution".to_string()])
+        .build();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(message_types(&result), ["item.completed", "turn.completed"]);
+    assert_eq!(
+        result.all_messages[0]["item"]["type"].as_str(),
+        Some("agent_message")
+    );
+}
+
+#[tokio::test]
+async fn test_attempts_counts_retries_until_success() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let counter = temp_dir.path().join("attempts");
+    let _fake = common::FakeCodex::install(&format!(
+        r#"fail-first 2 {}
+emit {{"type":"thread.started","thread_id":"retry-session"}}
+emit {{"type":"item.completed","item":{{"type":"agent_message","text":"third time lucky"}}}}"#,
+        counter.display()
+    ))
+    .await;
+
+    let opts = codex::Options::builder("test", temp_dir.path())
+        .with_skip_git_repo_check(true)
+        .with_max_retries(3)
+        .build();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.attempts, 3);
+    assert_eq!(result.agent_messages, "third time lucky");
+}
+
+#[tokio::test]
+async fn test_attempts_is_accurate_when_the_last_attempt_fails() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let counter = temp_dir.path().join("attempts");
+    let _fake = common::FakeCodex::install(&format!("fail-first 10 {}", counter.display())).await;
+
+    let opts = codex::Options::builder("test", temp_dir.path())
+        .with_skip_git_repo_check(true)
+        .with_max_retries(1)
+        .build();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(!result.success);
+    assert_eq!(result.attempts, 2);
+}
+
+#[tokio::test]
+async fn test_capture_git_head_records_commit_before_run() {
+    use codex_mcp_rs::codex;
+    use tempfile::tempdir;
+
+    let repo = tempdir().expect("Failed to create temp dir");
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(repo.path())
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .expect("git must be installed to run this test");
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    };
+    git(&["init", "-q"]);
+    git(&["commit", "-q", "--allow-empty", "-m", "initial"]);
+    let head = git(&["rev-parse", "HEAD"]);
+
+    let _fake = common::FakeCodex::install(
+        r#"emit {"type":"thread.started","thread_id":"git-session"}
+emit {"type":"item.completed","item":{"type":"agent_message","text":"done"}}"#,
+    )
+    .await;
+    let opts = codex::Options::builder("test", repo.path())
+        .with_capture_git_head(true)
+        .build();
+    let result = codex::run(opts).await.expect("run should return Ok");
+
+    assert!(result.success, "unexpected error: {:?}", result.error);
+    assert_eq!(result.git_before.as_deref(), Some(head.as_str()));
+    assert_eq!(result.git_after, result.git_before);
 
-    // Clean up env var
-    env::remove_var("CODEX_BIN");
+    let outside = tempdir().expect("Failed to create temp dir");
+    assert!(codex_mcp_rs::git::head_commit(outside.path()).is_none());
 }
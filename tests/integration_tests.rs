@@ -1,4 +1,4 @@
-use codex_mcp_rs::codex::{Options, SandboxPolicy};
+use codex_mcp_rs::codex::{ColorMode, Options, SandboxPolicy};
 use std::path::PathBuf;
 
 #[test]
@@ -7,16 +7,8 @@ fn test_options_validation() {
     let opts = Options {
         prompt: "Test prompt".to_string(),
         working_dir: PathBuf::from("/tmp"),
-        sandbox: SandboxPolicy::ReadOnly,
-        session_id: None,
         skip_git_repo_check: true,
-        return_all_messages: false,
-        return_all_messages_limit: None,
-        image_paths: vec![],
-        model: None,
-        yolo: false,
-        profile: None,
-        timeout_secs: None,
+        ..Default::default()
     };
 
     assert!(!opts.prompt.is_empty());
@@ -36,15 +28,8 @@ fn test_sandbox_policies() {
             prompt: "test".to_string(),
             working_dir: PathBuf::from("/tmp"),
             sandbox: policy.clone(),
-            session_id: None,
             skip_git_repo_check: true,
-            return_all_messages: false,
-            return_all_messages_limit: None,
-            image_paths: vec![],
-            model: None,
-            yolo: false,
-            profile: None,
-            timeout_secs: None,
+            ..Default::default()
         };
 
         assert_eq!(opts.sandbox, policy);
@@ -66,19 +51,12 @@ fn test_image_paths() {
     let opts = Options {
         prompt: "Analyze this image".to_string(),
         working_dir: PathBuf::from("/tmp"),
-        sandbox: SandboxPolicy::ReadOnly,
-        session_id: None,
         skip_git_repo_check: true,
-        return_all_messages: false,
-        return_all_messages_limit: None,
         image_paths: vec![
             PathBuf::from("/path/to/image1.png"),
             PathBuf::from("/path/to/image2.jpg"),
         ],
-        model: None,
-        yolo: false,
-        profile: None,
-        timeout_secs: None,
+        ..Default::default()
     };
 
     assert_eq!(opts.image_paths.len(), 2);
@@ -93,16 +71,9 @@ fn test_session_id_format() {
     let opts = Options {
         prompt: "Continue task".to_string(),
         working_dir: PathBuf::from("/tmp"),
-        sandbox: SandboxPolicy::ReadOnly,
         session_id: Some(session_id.to_string()),
         skip_git_repo_check: true,
-        return_all_messages: false,
-        return_all_messages_limit: None,
-        image_paths: vec![],
-        model: None,
-        yolo: false,
-        profile: None,
-        timeout_secs: None,
+        ..Default::default()
     };
 
     assert!(opts.session_id.is_some());
@@ -121,16 +92,9 @@ fn test_model_options() {
         let opts = Options {
             prompt: "test".to_string(),
             working_dir: PathBuf::from("/tmp"),
-            sandbox: SandboxPolicy::ReadOnly,
-            session_id: None,
             skip_git_repo_check: true,
-            return_all_messages: false,
-            return_all_messages_limit: None,
-            image_paths: vec![],
             model: Some(model.to_string()),
-            yolo: false,
-            profile: None,
-            timeout_secs: None,
+            ..Default::default()
         };
 
         assert_eq!(opts.model, Some(model.to_string()));
@@ -142,31 +106,17 @@ fn test_return_all_messages_flag() {
     let opts_detailed = Options {
         prompt: "test".to_string(),
         working_dir: PathBuf::from("/tmp"),
-        sandbox: SandboxPolicy::ReadOnly,
-        session_id: None,
         skip_git_repo_check: true,
         return_all_messages: true,
         return_all_messages_limit: Some(5000),
-        image_paths: vec![],
-        model: None,
-        yolo: false,
-        profile: None,
-        timeout_secs: None,
+        ..Default::default()
     };
 
     let opts_simple = Options {
         prompt: "test".to_string(),
         working_dir: PathBuf::from("/tmp"),
-        sandbox: SandboxPolicy::ReadOnly,
-        session_id: None,
         skip_git_repo_check: true,
-        return_all_messages: false,
-        return_all_messages_limit: None,
-        image_paths: vec![],
-        model: None,
-        yolo: false,
-        profile: None,
-        timeout_secs: None,
+        ..Default::default()
     };
 
     assert!(opts_detailed.return_all_messages);
@@ -190,16 +140,8 @@ fn test_working_directory_paths() {
         let opts = Options {
             prompt: "test".to_string(),
             working_dir: PathBuf::from(path),
-            sandbox: SandboxPolicy::ReadOnly,
-            session_id: None,
             skip_git_repo_check: true,
-            return_all_messages: false,
-            return_all_messages_limit: None,
-            image_paths: vec![],
-            model: None,
-            yolo: false,
-            profile: None,
-            timeout_secs: None,
+            ..Default::default()
         };
 
         assert_eq!(opts.working_dir, PathBuf::from(path));
@@ -214,16 +156,9 @@ fn test_profile_configuration() {
         let opts = Options {
             prompt: "test".to_string(),
             working_dir: PathBuf::from("/tmp"),
-            sandbox: SandboxPolicy::ReadOnly,
-            session_id: None,
             skip_git_repo_check: true,
-            return_all_messages: false,
-            return_all_messages_limit: None,
-            image_paths: vec![],
-            model: None,
-            yolo: false,
             profile: Some(profile.to_string()),
-            timeout_secs: None,
+            ..Default::default()
         };
 
         assert_eq!(opts.profile, Some(profile.to_string()));
@@ -235,34 +170,79 @@ fn test_yolo_mode() {
     let opts_safe = Options {
         prompt: "test".to_string(),
         working_dir: PathBuf::from("/tmp"),
-        sandbox: SandboxPolicy::ReadOnly,
-        session_id: None,
         skip_git_repo_check: true,
-        return_all_messages: false,
-        return_all_messages_limit: None,
-        image_paths: vec![],
-        model: None,
-        yolo: false,
-        profile: None,
-        timeout_secs: None,
+        ..Default::default()
     };
 
     let opts_yolo = Options {
         prompt: "test".to_string(),
         working_dir: PathBuf::from("/tmp"),
         sandbox: SandboxPolicy::DangerFullAccess,
-        session_id: None,
         skip_git_repo_check: true,
-        return_all_messages: false,
-        return_all_messages_limit: None,
-        image_paths: vec![],
-        model: None,
         yolo: true,
-        profile: None,
-        timeout_secs: None,
+        ..Default::default()
     };
 
     assert!(!opts_safe.yolo);
     assert!(opts_yolo.yolo);
     assert_eq!(opts_yolo.sandbox, SandboxPolicy::DangerFullAccess);
 }
+
+#[test]
+fn test_options_builder_minimal() {
+    let opts = Options::builder("Minimal prompt", "/tmp").build();
+
+    assert_eq!(opts.prompt, "Minimal prompt");
+    assert_eq!(opts.working_dir, PathBuf::from("/tmp"));
+    assert_eq!(opts.sandbox, SandboxPolicy::ReadOnly);
+    assert!(opts.session_id.is_none());
+    assert!(!opts.skip_git_repo_check);
+    assert!(!opts.return_all_messages);
+    assert!(opts.image_paths.is_empty());
+    assert!(!opts.yolo);
+    assert!(opts.timeout_secs.is_none());
+    assert_eq!(opts.max_retries, 0);
+}
+
+#[test]
+fn test_options_builder_fully_specified() {
+    let opts = Options::builder("Full prompt", PathBuf::from("/work"))
+        .with_sandbox(SandboxPolicy::WorkspaceWrite)
+        .with_session_id("session-1")
+        .with_skip_git_repo_check(true)
+        .with_return_all_messages(true)
+        .with_return_all_messages_limit(100)
+        .with_image_path("/img/a.png")
+        .with_image_path("/img/b.png")
+        .with_model("gpt-5")
+        .with_yolo(true)
+        .with_profile("dev")
+        .with_timeout_secs(120)
+        .with_idle_timeout_secs(30)
+        .with_return_boundary_events(true)
+        .with_color(ColorMode::Never)
+        .with_agents_as_system_flag(true)
+        .with_annotate_timestamps(true)
+        .with_max_retries(2)
+        .build();
+
+    assert_eq!(opts.sandbox, SandboxPolicy::WorkspaceWrite);
+    assert_eq!(opts.session_id.as_deref(), Some("session-1"));
+    assert!(opts.skip_git_repo_check);
+    assert!(opts.return_all_messages);
+    assert_eq!(opts.return_all_messages_limit, Some(100));
+    assert_eq!(
+        opts.image_paths,
+        vec![PathBuf::from("/img/a.png"), PathBuf::from("/img/b.png")]
+    );
+    assert_eq!(opts.model.as_deref(), Some("gpt-5"));
+    assert!(opts.yolo);
+    assert_eq!(opts.profile.as_deref(), Some("dev"));
+    assert_eq!(opts.timeout_secs, Some(120));
+    assert_eq!(opts.idle_timeout_secs, Some(30));
+    assert!(opts.return_boundary_events);
+    assert_eq!(opts.color, Some(ColorMode::Never));
+    assert!(opts.agents_as_system_flag);
+    assert!(opts.annotate_timestamps);
+    assert_eq!(opts.max_retries, 2);
+}
@@ -1,6 +1,36 @@
 use codex_mcp_rs::server::CodexServer;
 use rmcp::{model::*, ServerHandler};
 
+/// Serializes tests that change process environment variables, since the test harness runs
+/// tests on parallel threads that share one environment
+static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Run `f` with the given environment variables set (or removed, for None), restoring their
+/// previous values afterwards
+fn with_env<T>(vars: &[(&str, Option<&str>)], f: impl FnOnce() -> T) -> T {
+    let _guard = ENV_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let saved: Vec<_> = vars
+        .iter()
+        .map(|(name, _)| (*name, std::env::var_os(name)))
+        .collect();
+    for (name, value) in vars {
+        match value {
+            Some(value) => std::env::set_var(name, value),
+            None => std::env::remove_var(name),
+        }
+    }
+    let result = f();
+    for (name, value) in saved {
+        match value {
+            Some(value) => std::env::set_var(name, value),
+            None => std::env::remove_var(name),
+        }
+    }
+    result
+}
+
 #[test]
 fn test_server_creation() {
     let server = CodexServer::new();
@@ -29,6 +59,79 @@ fn test_server_info() {
     assert!(info.instructions.unwrap().contains("codex tool"));
 }
 
+#[test]
+fn test_server_info_lists_sandbox_modes() {
+    use codex_mcp_rs::codex::SandboxPolicy;
+
+    let instructions = CodexServer::new().get_info().instructions.unwrap();
+    for policy in SandboxPolicy::ALL {
+        assert!(instructions.contains(policy.as_str()));
+        assert!(instructions.contains(policy.describe()));
+    }
+}
+
+#[test]
+fn test_server_info_reports_yolo_setting() {
+    let instructions = |value| {
+        with_env(&[("CODEX_ALLOW_YOLO", Some(value))], || {
+            CodexServer::new().get_info().instructions.unwrap()
+        })
+    };
+    let allowed = instructions("true");
+    let denied = instructions("false");
+
+    assert!(allowed.contains("- yolo: allowed"));
+    assert!(denied.contains("- yolo: not allowed"));
+}
+
+#[test]
+fn test_effective_config_reflects_env_overrides() {
+    let config = with_env(
+        &[
+            ("CODEX_MAX_CONCURRENCY", Some("3")),
+            ("CODEX_MAX_IMAGES", Some("4")),
+            ("CODEX_CAPABILITY_SECRET", Some("do-not-leak")),
+        ],
+        || serde_json::to_value(CodexServer::new().effective_config()).unwrap(),
+    );
+
+    assert_eq!(config["max_concurrency"], 3);
+    assert_eq!(config["max_images"], 4);
+    assert_eq!(config["capability_tokens_enabled"], true);
+    for key in [
+        "default_timeout_secs",
+        "max_timeout_secs",
+        "max_prompt_bytes",
+        "max_message_limit",
+    ] {
+        assert!(config[key].is_u64(), "missing {}", key);
+    }
+    assert!(config["security"]["allow_yolo"].is_boolean());
+    assert!(!config.to_string().contains("do-not-leak"));
+}
+
+#[test]
+fn test_missing_codex_home_is_a_single_config_warning() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("nowhere");
+    let (sessions_dir, config) = with_env(&[("CODEX_HOME", missing.to_str())], || {
+        let server = CodexServer::new();
+        (
+            codex_mcp_rs::session::sessions_dir(),
+            serde_json::to_value(server.effective_config()).unwrap(),
+        )
+    });
+
+    assert_eq!(sessions_dir, None);
+    let warnings: Vec<_> = config["warnings"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|w| w.as_str().unwrap().contains("CODEX_HOME"))
+        .collect();
+    assert_eq!(warnings.len(), 1);
+}
+
 #[test]
 fn test_default_implementation() {
     let server1 = CodexServer::new();